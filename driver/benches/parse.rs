@@ -0,0 +1,122 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Benchmarks parsing a large exposition. To compare the pest grammar with the
+//! hand-written scanner, save a baseline with one and compare the other to it:
+//!
+//! ```shell
+//! cargo bench -p driver --bench parse -- --save-baseline pest
+//! cargo bench -p driver --bench parse --features fast-parse -- --baseline pest
+//! ```
+
+use std::fmt::Write;
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use driver::parse;
+
+/// The size of the generated exposition, in bytes.
+const EXPOSITION_BYTES: usize = 10 * 1024 * 1024;
+
+/// Generates an exposition resembling that of a large node_exporter or
+/// application, with a mix of counters, gauges, histograms and summaries that
+/// have several labels each, until it is at least `EXPOSITION_BYTES` long.
+fn exposition() -> String {
+    let mut out = String::with_capacity(EXPOSITION_BYTES + 64 * 1024);
+    let mut group = 0;
+    while out.len() < EXPOSITION_BYTES {
+        let name = format!("app_http_requests_{}", group);
+        writeln!(out, "# HELP {}_total Requests handled,\\nby route.", name).unwrap();
+        writeln!(out, "# TYPE {}_total counter", name).unwrap();
+        for route in 0..20 {
+            for code in ["200", "404", "500"] {
+                writeln!(
+                    out,
+                    "{}_total{{method=\"GET\",route=\"/api/v1/items/{}\",code=\"{}\"}} {}",
+                    name,
+                    route,
+                    code,
+                    route * 1000 + group
+                )
+                .unwrap();
+            }
+        }
+
+        let name = format!("node_memory_{}_bytes", group);
+        writeln!(out, "# HELP {} Memory information field.", name).unwrap();
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        for numa in 0..4 {
+            writeln!(
+                out,
+                "{}{{node=\"{}\"}} {}e+09",
+                name,
+                numa,
+                1.5 + numa as f64
+            )
+            .unwrap();
+        }
+
+        let name = format!("app_request_duration_seconds_{}", group);
+        writeln!(out, "# HELP {} Time spent handling requests.", name).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for route in 0..5 {
+            let labels = format!("route=\"/api/v1/items/{}\"", route);
+            let bounds = [
+                "0.005", "0.01", "0.025", "0.05", "0.1", "0.25", "0.5", "1", "+Inf",
+            ];
+            for (i, le) in bounds.iter().enumerate() {
+                writeln!(
+                    out,
+                    "{}_bucket{{{},le=\"{}\"}} {}",
+                    name,
+                    labels,
+                    le,
+                    i * 10
+                )
+                .unwrap();
+            }
+            writeln!(out, "{}_sum{{{}}} 12.345", name, labels).unwrap();
+            writeln!(out, "{}_count{{{}}} 80", name, labels).unwrap();
+        }
+
+        let name = format!("go_gc_duration_seconds_{}", group);
+        writeln!(out, "# HELP {} A summary of GC pause durations.", name).unwrap();
+        writeln!(out, "# TYPE {} summary", name).unwrap();
+        for quantile in ["0", "0.25", "0.5", "0.75", "1"] {
+            writeln!(out, "{}{{quantile=\"{}\"}} 3.1e-05", name, quantile).unwrap();
+        }
+        writeln!(out, "{}_sum 0.0123", name).unwrap();
+        writeln!(out, "{}_count 42", name).unwrap();
+        group += 1;
+    }
+    out
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let input = exposition();
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.sample_size(10);
+    group.bench_function("parse", |b| {
+        b.iter(|| parse::parse(Some("localhost:9100"), Some("node"), black_box(&input)).unwrap())
+    });
+    group.bench_function("parse_lenient", |b| {
+        b.iter(|| parse::parse_lenient(Some("localhost:9100"), Some("node"), black_box(&input)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
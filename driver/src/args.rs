@@ -0,0 +1,452 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The command-line flags and configuration settings shared by every binary,
+//! which flattens them into its own.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum};
+use serde::Deserialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::fetch;
+use crate::relabel::{RelabelConfig, Relabeler};
+use crate::scraper::{parse_duration, parse_timestamp, ConfigDuration};
+
+/// The flags for scraping targets, serving the HTTP endpoints and processing
+/// samples, which every binary has. A binary adds them to its own with
+/// `#[command(flatten)]`, and implements `Args` by returning them.
+#[derive(Clone, clap::Args)]
+pub struct DriverArgs {
+    /// A TOML file to read settings from.
+    /// Settings given on the command line take precedence over those in the file.
+    #[arg(long)]
+    pub config: Option<String>,
+
+    /// The IP address to listen on for connections.
+    /// Only needed when running as a server.
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: String,
+
+    /// The port number to use.
+    /// Only needed when running as a server.
+    #[arg(short, long, default_value_t = 8080)]
+    pub port: u16,
+
+    /// Don't listen for connections, which disables the /metrics and lifecycle
+    /// endpoints. Useful for running several instances on one host.
+    #[arg(long)]
+    pub no_listen: bool,
+
+    /// Serve the HTTP endpoints over TLS, with the certificate chain in this PEM file.
+    /// Requires --tls-key.
+    #[arg(long, value_name = "PATH")]
+    pub tls_cert: Option<String>,
+
+    /// The PEM file holding the private key for --tls-cert.
+    #[arg(long, value_name = "PATH")]
+    pub tls_key: Option<String>,
+
+    /// Require basic authentication with these credentials for every HTTP endpoint
+    /// except the index page. Put them in the configuration file to keep them out of
+    /// the process list.
+    #[arg(long, value_name = "USER:PASSWORD")]
+    pub web_auth: Option<String>,
+
+    /// The instance label to add to the samples of each target, as a template
+    /// such as "{host}:{port}". If not provided, the target's address is used.
+    #[arg(long)]
+    pub instance: Option<String>,
+
+    /// The job label to add to the samples of each target, as a template
+    /// such as "{__meta_service}". If not provided, no job label is added.
+    #[arg(long)]
+    pub job: Option<String>,
+
+    /// How often metrics will be scraped, e.g. "500ms", "30s" or "5m".
+    /// A bare number is a number of seconds.
+    #[arg(short, long, value_name = "DURATION", default_value = "5s", value_parser = parse_duration)]
+    pub interval: Duration,
+
+    /// How long to wait for a scrape to complete.
+    /// Defaults to the scrape interval.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub scrape_timeout: Option<Duration>,
+
+    /// How many times to retry a scrape that fails with a connection or server error.
+    /// Retries back off exponentially, but never beyond the next scrape interval.
+    #[arg(long, default_value_t = 0)]
+    pub max_retries: u32,
+
+    /// The largest response body to accept from a target, in bytes.
+    /// Scrapes with larger bodies fail, to avoid running out of memory.
+    #[arg(long, default_value_t = fetch::DEFAULT_MAX_BODY_BYTES)]
+    pub max_body_bytes: usize,
+
+    /// The most samples to accept from one scrape, after filtering and relabeling.
+    /// Scrapes with more samples are dropped entirely. 0 means no limit.
+    #[arg(long, default_value_t = 0)]
+    pub sample_limit: usize,
+
+    /// How many scrapes to hold in memory before dropping samples.
+    #[arg(short, long, default_value_t = 5)]
+    pub buffer: usize,
+
+    /// When the buffer is full, wait for the writer to catch up instead of dropping
+    /// the scrape. Waits for at most one scrape interval.
+    #[arg(long)]
+    pub block_on_full: bool,
+
+    /// Lengthen the scrape interval while the writer is falling behind, and restore it
+    /// once the writer catches up, instead of dropping scrapes.
+    #[arg(long)]
+    pub adaptive_interval: bool,
+
+    /// How long to wait on shutdown for buffered scrapes to be written.
+    /// Scrapes not written by then are lost. If 0, waits for as long as it takes.
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+    pub shutdown_timeout: Duration,
+
+    /// How often to flush buffered samples to the output while running.
+    /// If 0, samples are written when the buffers fill up, and on shutdown.
+    #[arg(long, value_name = "DURATION", default_value = "0", value_parser = parse_duration)]
+    pub flush_interval: Duration,
+
+    /// What to do when a scrape is missed because the process fell behind schedule:
+    /// scrape late and shift the later scrapes, skip it, or catch up with scrapes
+    /// in quick succession.
+    #[arg(long, value_enum, default_value_t = MissedTicks::Delay)]
+    pub missed_ticks: MissedTicks,
+
+    /// Skip metric families that can't be parsed, instead of dropping the whole scrape.
+    /// A label given twice in one sample keeps its last value, instead of dropping the whole scrape.
+    #[arg(long)]
+    pub lenient: bool,
+
+    /// Warn when a counter decreases between scrapes of a target, unless the
+    /// target was restarted, as seen from its process_start_time_seconds.
+    #[arg(long)]
+    pub check_counters: bool,
+
+    /// Drop a label from a target's metric family once it has more than this many distinct
+    /// values within --label-value-window. Samples that only differed in it are dropped.
+    /// 0 means labels are never dropped this way.
+    #[arg(long, default_value_t = 0)]
+    pub label_value_limit: usize,
+
+    /// The window over which --label-value-limit counts the values of each label.
+    #[arg(long, value_name = "DURATION", default_value = "1h", value_parser = parse_duration)]
+    pub label_value_window: Duration,
+
+    /// Send conditional requests using the ETag or Last-Modified time of each target's
+    /// last scrape, and skip scrapes that the target reports as unchanged.
+    #[arg(long)]
+    pub skip_unchanged: bool,
+
+    /// Write the raw body of each scrape to a file in this directory before
+    /// parsing it, to help debug targets whose metrics are exported wrongly.
+    #[arg(long, value_name = "PATH")]
+    pub dump_dir: Option<String>,
+
+    /// The most files to keep in the dump directory.
+    /// The oldest are deleted to make room for new ones.
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub dump_max_files: usize,
+
+    /// Only export metric families whose names match this regex.
+    /// May be repeated to include several patterns.
+    #[arg(long, value_name = "REGEX")]
+    pub include_metric: Vec<String>,
+
+    /// Don't export metric families whose names match this regex, even if they
+    /// are included. May be repeated.
+    #[arg(long, value_name = "REGEX")]
+    pub exclude_metric: Vec<String>,
+
+    /// Scrape each target once and exit, instead of polling them.
+    #[arg(long)]
+    pub once: bool,
+
+    /// Scrape and parse each target once, logging a summary instead of writing
+    /// anything. Exits with an error if any scrape can't be parsed.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Send traces of each scrape to this OTLP/HTTP endpoint,
+    /// e.g. "http://localhost:4318/v1/traces".
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+
+    /// Enable the /-/reload and /-/quit endpoints, which reload the configuration
+    /// and shut down the process when sent a POST request.
+    #[arg(long)]
+    pub enable_lifecycle: bool,
+
+    /// Skip verification of the target's TLS certificate.
+    /// Only use this for trusted endpoints with self-signed certificates.
+    #[arg(long)]
+    pub insecure_skip_verify: bool,
+
+    /// Credentials to send when scraping the target.
+    /// Either a bearer token, or "user:password" for basic authentication.
+    #[arg(long)]
+    pub authorization: Option<String>,
+
+    /// An HTTP proxy to connect to targets through, e.g. "http://proxy:3128".
+    /// HTTPS targets are tunneled through it with CONNECT. Defaults to the
+    /// HTTP_PROXY or HTTPS_PROXY environment variable. Hosts listed in NO_PROXY
+    /// are always connected to directly.
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// The path to scrape on targets whose URL has no path,
+    /// e.g. "/actuator/prometheus".
+    #[arg(long, default_value = "/metrics")]
+    pub metrics_path: String,
+
+    /// A JSON file listing more targets to scrape, in the format of Prometheus'
+    /// file-based service discovery. The file is read again whenever it changes.
+    #[arg(long, value_name = "PATH")]
+    pub targets_file: Option<String>,
+
+    /// The time of samples read from files or stdin that don't have their own,
+    /// as an RFC 3339 date and time or milliseconds since the epoch. Defaults to
+    /// the time stdin is read, or the modification time of each file.
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub timestamp: Option<u64>,
+
+    /// Additional Prometheus client endpoints to scrape.
+    /// May be repeated to scrape several targets from one process.
+    #[arg(long = "target", value_name = "TARGET")]
+    pub extra_targets: Vec<String>,
+
+    /// The URL of a Prometheus client endpoint to scrape.
+    /// If "-", then read from stdin. If a file or directory, then read it once.
+    pub target: Option<String>,
+
+    /// Targets read from the configuration file.
+    #[arg(skip)]
+    pub config_targets: Vec<String>,
+
+    /// Relabeling rules read from the configuration file.
+    #[arg(skip)]
+    pub relabeler: Relabeler,
+
+    /// The names of the settings given on the command line.
+    #[arg(skip)]
+    pub command_line: HashSet<String>,
+}
+
+impl DriverArgs {
+    /// Records which settings were given on the command line, so that the
+    /// configuration file doesn't override them.
+    pub fn set_command_line(&mut self, matches: &ArgMatches) {
+        self.command_line = matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect();
+    }
+
+    /// Applies the settings from a configuration file,
+    /// except for those that were given on the command line.
+    pub fn apply_config(&mut self, config: DriverConfig) -> Result<(), String> {
+        macro_rules! apply {
+            ($field:ident) => {
+                if let Some(value) = config.$field {
+                    if !self.command_line.contains(stringify!($field)) {
+                        self.$field = value.into();
+                    }
+                }
+            };
+            ($field:ident => $id:ident, $convert:expr) => {
+                if let Some(value) = config.$field {
+                    if !self.command_line.contains(stringify!($id)) {
+                        self.$id = $convert(value)?;
+                    }
+                }
+            };
+        }
+        apply!(host);
+        apply!(port);
+        apply!(no_listen);
+        apply!(tls_cert);
+        apply!(tls_key);
+        apply!(web_auth);
+        apply!(instance);
+        apply!(job);
+        apply!(interval => interval, |d: ConfigDuration| d.to_duration());
+        apply!(scrape_timeout => scrape_timeout, |d: ConfigDuration| {
+            d.to_duration().map(Some)
+        });
+        apply!(max_retries);
+        apply!(max_body_bytes);
+        apply!(sample_limit);
+        apply!(buffer);
+        apply!(block_on_full);
+        apply!(adaptive_interval);
+        apply!(shutdown_timeout => shutdown_timeout, |d: ConfigDuration| {
+            d.to_duration()
+        });
+        apply!(flush_interval => flush_interval, |d: ConfigDuration| {
+            d.to_duration()
+        });
+        apply!(missed_ticks => missed_ticks, |m: String| {
+            MissedTicks::from_str(&m, true).map_err(|_| format!("invalid missed_ticks {:?}", m))
+        });
+        apply!(lenient);
+        apply!(check_counters);
+        apply!(label_value_limit);
+        apply!(label_value_window => label_value_window, |d: ConfigDuration| {
+            d.to_duration()
+        });
+        apply!(skip_unchanged);
+        apply!(dump_dir);
+        apply!(dump_max_files);
+        apply!(include_metric);
+        apply!(exclude_metric);
+        apply!(once);
+        apply!(otlp_endpoint);
+        apply!(enable_lifecycle);
+        apply!(insecure_skip_verify);
+        apply!(authorization);
+        apply!(proxy);
+        apply!(metrics_path);
+        apply!(targets_file);
+        apply!(timestamp => timestamp, |t: String| parse_timestamp(&t).map(Some));
+        apply!(targets => config_targets, Ok::<_, String>);
+        apply!(relabel => relabeler, |r: Vec<RelabelConfig>| Relabeler::new(&r));
+        Ok(())
+    }
+}
+
+/// The settings of `DriverArgs` in a configuration file. Each setting is
+/// optional and is named after the corresponding command-line flag, with
+/// underscores in place of dashes. A binary adds them to its own settings
+/// with `#[serde(flatten)]`.
+#[derive(Default, Deserialize)]
+pub struct DriverConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub no_listen: Option<bool>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub web_auth: Option<String>,
+    pub instance: Option<String>,
+    pub job: Option<String>,
+    pub interval: Option<ConfigDuration>,
+    pub scrape_timeout: Option<ConfigDuration>,
+    pub max_retries: Option<u32>,
+    pub max_body_bytes: Option<usize>,
+    pub sample_limit: Option<usize>,
+    pub buffer: Option<usize>,
+    pub block_on_full: Option<bool>,
+    pub adaptive_interval: Option<bool>,
+    pub shutdown_timeout: Option<ConfigDuration>,
+    pub flush_interval: Option<ConfigDuration>,
+    pub missed_ticks: Option<String>,
+    pub lenient: Option<bool>,
+    pub check_counters: Option<bool>,
+    pub label_value_limit: Option<usize>,
+    pub label_value_window: Option<ConfigDuration>,
+    pub skip_unchanged: Option<bool>,
+    pub dump_dir: Option<String>,
+    pub dump_max_files: Option<usize>,
+    pub include_metric: Option<Vec<String>>,
+    pub exclude_metric: Option<Vec<String>>,
+    pub once: Option<bool>,
+    pub otlp_endpoint: Option<String>,
+    pub enable_lifecycle: Option<bool>,
+    pub insecure_skip_verify: Option<bool>,
+    pub authorization: Option<String>,
+    pub proxy: Option<String>,
+    pub metrics_path: Option<String>,
+    pub targets_file: Option<String>,
+    pub timestamp: Option<String>,
+    /// The targets to scrape, used only if none are given on the command line.
+    pub targets: Option<Vec<String>>,
+    /// Rules for rewriting or dropping samples, applied in order.
+    pub relabel: Option<Vec<RelabelConfig>>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum MissedTicks {
+    Delay,
+    Skip,
+    Burst,
+}
+
+impl MissedTicks {
+    pub fn behavior(self) -> MissedTickBehavior {
+        match self {
+            MissedTicks::Delay => MissedTickBehavior::Delay,
+            MissedTicks::Skip => MissedTickBehavior::Skip,
+            MissedTicks::Burst => MissedTickBehavior::Burst,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches, Parser};
+
+    #[derive(Parser)]
+    struct Binary {
+        #[command(flatten)]
+        driver: DriverArgs,
+    }
+
+    fn parse(command_line: &[&str]) -> DriverArgs {
+        let matches = Binary::command().get_matches_from(command_line);
+        let mut args = Binary::from_arg_matches(&matches).unwrap().driver;
+        args.set_command_line(&matches);
+        args
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let mut args = parse(&["binary", "--interval", "10s", "http://localhost:9100"]);
+        args.apply_config(DriverConfig {
+            interval: Some(ConfigDuration::Seconds(30)),
+            buffer: Some(7),
+            missed_ticks: Some("skip".to_string()),
+            targets: Some(vec!["http://localhost:9101".to_string()]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(args.interval, Duration::from_secs(10));
+        assert_eq!(args.buffer, 7);
+        assert!(matches!(args.missed_ticks, MissedTicks::Skip));
+        assert_eq!(args.target.as_deref(), Some("http://localhost:9100"));
+        assert_eq!(args.config_targets, vec!["http://localhost:9101"]);
+    }
+
+    #[test]
+    fn invalid_setting_is_an_error() {
+        let mut args = parse(&["binary"]);
+        let result = args.apply_config(DriverConfig {
+            missed_ticks: Some("sometimes".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(
+            result.err().as_deref(),
+            Some("invalid missed_ticks \"sometimes\"")
+        );
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Discovering the targets to scrape from a file, like Prometheus' `file_sd_configs`.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+/// How often the targets file is checked for changes.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A group of targets that share the same labels. A targets file holds a list
+/// of them, e.g. `[{"targets": ["localhost:9100"], "labels": {"env": "dev"}}]`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetGroup {
+    /// The addresses of the targets, as "host:port", or their full URLs.
+    pub targets: Vec<String>,
+    /// Labels added to every sample scraped from the targets.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A file listing target groups, which is read again when it changes.
+pub struct TargetsFile {
+    path: PathBuf,
+    /// The modification time and size of the file when it was last read.
+    version: Option<(SystemTime, u64)>,
+}
+
+impl TargetsFile {
+    pub fn new(path: &str) -> Self {
+        TargetsFile {
+            path: PathBuf::from(path),
+            version: None,
+        }
+    }
+
+    /// Reads the target groups from the file.
+    pub fn read(&mut self) -> Result<Vec<TargetGroup>, String> {
+        self.version = self.current_version();
+        let text = std::fs::read_to_string(&self.path)
+            .map_err(|err| format!("unable to read {}: {}", self.path.display(), err))?;
+        serde_json::from_str(&text)
+            .map_err(|err| format!("unable to parse {}: {}", self.path.display(), err))
+    }
+
+    /// Reads the target groups from the file if it has changed since it was
+    /// last read, or returns None if it hasn't.
+    pub fn read_if_changed(&mut self) -> Option<Result<Vec<TargetGroup>, String>> {
+        let version = self.current_version();
+        if version.is_some() && version == self.version {
+            return None;
+        }
+        Some(self.read())
+    }
+
+    fn current_version(&self) -> Option<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(&self.path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Saving the raw body of each scrape to a file, to debug targets whose
+//! metrics can't be parsed or exported.
+
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::parse::Exposition;
+
+/// The extensions of the files written for text and protobuf expositions.
+const TEXT_EXTENSION: &str = "prom";
+const PROTOBUF_EXTENSION: &str = "pb";
+
+/// The number of digits of the timestamp at the start of each file name.
+const TIMESTAMP_DIGITS: usize = 13;
+
+/// Writes scrapes to files in a directory, named after the time of the scrape,
+/// a sequence number and the instance it came from, such as
+/// "1714564800000-000042-localhost_9100.prom". Only the newest `max_files` are
+/// kept. Other files in the directory are left alone.
+pub struct Dumper {
+    dir: PathBuf,
+    max_files: usize,
+    /// The files written by dumpers in the directory, oldest first.
+    files: VecDeque<PathBuf>,
+    /// Tells apart the files of scrapes with the same timestamp and instance.
+    sequence: u32,
+}
+
+impl Dumper {
+    /// Creates the directory if needed. Files left in it by an earlier run
+    /// count towards `max_files`.
+    pub fn new(dir: &str, max_files: usize) -> Result<Self, String> {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir)
+            .map_err(|err| format!("unable to create {}: {}", dir.display(), err))?;
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .map_err(|err| format!("unable to read {}: {}", dir.display(), err))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| is_dump_file(path))
+            .collect();
+        // The names start with the timestamp, so they sort oldest first.
+        files.sort();
+        let mut dumper = Dumper {
+            dir,
+            max_files,
+            files: files.into(),
+            sequence: 0,
+        };
+        dumper.prune(max_files);
+        Ok(dumper)
+    }
+
+    /// Writes the body of a scrape, deleting the oldest files if needed to
+    /// stay within the limit.
+    pub fn dump(&mut self, timestamp_millis: u64, instance: Option<&str>, exposition: &Exposition) {
+        if self.max_files == 0 {
+            return;
+        }
+        // Instances are usually "host:port", which isn't a valid file name everywhere.
+        let instance: String = instance
+            .unwrap_or("unknown")
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let extension = match exposition {
+            Exposition::Text(_) => TEXT_EXTENSION,
+            Exposition::Protobuf(_) => PROTOBUF_EXTENSION,
+        };
+        // A file is never overwritten, even if it was left by an earlier run,
+        // as it would then be counted twice.
+        let (path, mut file) = loop {
+            let path = self.dir.join(format!(
+                "{:0width$}-{:06}-{}.{}",
+                timestamp_millis,
+                self.sequence,
+                instance,
+                extension,
+                width = TIMESTAMP_DIGITS
+            ));
+            self.sequence = (self.sequence + 1) % 1_000_000;
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => {
+                    error!("unable to create {}: {}", path.display(), err);
+                    return;
+                }
+            }
+        };
+        // The file is kept track of even if it couldn't be written, so that it
+        // will be deleted in turn.
+        match file.write_all(exposition.as_bytes()) {
+            Ok(()) => debug!("wrote scrape to {}", path.display()),
+            Err(err) => error!("unable to write {}: {}", path.display(), err),
+        }
+        self.prune(self.max_files - 1);
+        self.files.push_back(path);
+    }
+
+    /// Deletes the oldest files until at most `keep` are left.
+    fn prune(&mut self, keep: usize) {
+        while self.files.len() > keep {
+            let Some(path) = self.files.pop_front() else {
+                break;
+            };
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!("unable to delete {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Whether `path` is named like the files written by a `Dumper`: a timestamp,
+/// then anything made of the characters allowed in instance names, with the
+/// extension of a text or protobuf exposition.
+fn is_dump_file(path: &Path) -> bool {
+    if !path
+        .extension()
+        .is_some_and(|ext| ext == TEXT_EXTENSION || ext == PROTOBUF_EXTENSION)
+    {
+        return false;
+    }
+    let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        return false;
+    };
+    let Some((timestamp, rest)) = stem.split_at_checked(TIMESTAMP_DIGITS) else {
+        return false;
+    };
+    timestamp.bytes().all(|c| c.is_ascii_digit())
+        && rest.len() > 1
+        && rest.starts_with('-')
+        && rest
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'.' | b'-' | b'_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dump-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn list(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn same_millisecond() {
+        let dir = test_dir("same_millisecond");
+        let mut dumper = Dumper::new(dir.to_str().unwrap(), 10).unwrap();
+        let exposition = Exposition::Text("up 1\n".to_string());
+        dumper.dump(1000, Some("localhost:9100"), &exposition);
+        dumper.dump(1000, Some("localhost:9100"), &exposition);
+        assert_eq!(
+            list(&dir),
+            vec![
+                "0000000001000-000000-localhost_9100.prom",
+                "0000000001000-000001-localhost_9100.prom",
+            ]
+        );
+        assert_eq!(dumper.files.len(), 2);
+
+        // A new dumper starts its sequence again, but doesn't overwrite them.
+        let mut dumper = Dumper::new(dir.to_str().unwrap(), 10).unwrap();
+        dumper.dump(1000, Some("localhost:9100"), &exposition);
+        assert_eq!(list(&dir).len(), 3);
+        assert_eq!(dumper.files.len(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_only_own_files() {
+        let dir = test_dir("prunes_only_own_files");
+        for name in ["notes.prom", "0000000000500-x.txt", "12345-short.prom"] {
+            std::fs::write(dir.join(name), "keep").unwrap();
+        }
+        std::fs::write(dir.join("0000000000900-old.prom"), "old").unwrap();
+        let mut dumper = Dumper::new(dir.to_str().unwrap(), 2).unwrap();
+        assert_eq!(dumper.files.len(), 1);
+        let exposition = Exposition::Protobuf(vec![0]);
+        dumper.dump(1000, None, &exposition);
+        dumper.dump(2000, None, &exposition);
+        assert_eq!(
+            list(&dir),
+            vec![
+                "0000000000500-x.txt",
+                "0000000001000-000000-unknown.pb",
+                "0000000002000-000001-unknown.pb",
+                "12345-short.prom",
+                "notes.prom",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
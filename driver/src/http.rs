@@ -13,23 +13,188 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 
+use base64::prelude::*;
 use bytes::Bytes;
 use http_body_util::Full;
+use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
 use hyper::server::conn::http1;
 use hyper::service::Service;
-use hyper::Request;
-use hyper::StatusCode;
+pub use hyper::StatusCode;
 use hyper::{body::Incoming, Response};
+use hyper::{Method, Request};
 use hyper_util::rt::TokioIo;
 use prometheus::{Encoder, TextEncoder};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
 use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task;
+use tokio_rustls::TlsAcceptor;
 
 const INDEX_HTML: &str = include_str!("./index.html");
 
-pub struct Svc {}
+/// Channels used by the lifecycle endpoints to signal the polling loop.
+pub struct Lifecycle {
+    /// Notified when a reload of the configuration is requested.
+    pub reload: watch::Sender<()>,
+    /// Notified when a shutdown is requested.
+    pub quit: watch::Sender<()>,
+}
+
+/// Endpoints that a binary serves under `/api/`, such as for reading back what
+/// it has written. Requests are handled on a thread where blocking is allowed.
+pub trait Api: Send + Sync {
+    /// Handles a GET of `/api/{endpoint}`, given the decoded query parameters.
+    /// Returns `None` if there is no such endpoint.
+    fn get(&self, endpoint: &str, params: &HashMap<String, String>) -> Option<ApiResponse>;
+}
+
+/// A JSON response from an `Api` endpoint.
+pub struct ApiResponse {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl ApiResponse {
+    /// A successful response holding `value`.
+    pub fn ok(value: &serde_json::Value) -> ApiResponse {
+        ApiResponse {
+            status: StatusCode::OK,
+            body: value.to_string(),
+        }
+    }
+
+    /// A failed response, whose body is `{"error": message}`.
+    pub fn error(status: StatusCode, message: impl Into<String>) -> ApiResponse {
+        ApiResponse {
+            status,
+            body: serde_json::json!({ "error": message.into() }).to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Svc {
+    /// `None` if the lifecycle endpoints are disabled.
+    lifecycle: Option<Arc<Lifecycle>>,
+    /// `None` if the binary has no `/api/` endpoints.
+    api: Option<Arc<dyn Api>>,
+    /// The `Authorization` header that every request except for the index
+    /// must have, or `None` if no authentication is required.
+    authorization: Option<Arc<str>>,
+}
+
+impl Svc {
+    /// `web_auth` is "user:password", the credentials that requests must give
+    /// with basic authentication.
+    pub fn new(
+        lifecycle: Option<Arc<Lifecycle>>,
+        api: Option<Arc<dyn Api>>,
+        web_auth: Option<&str>,
+    ) -> Result<Svc, String> {
+        let authorization = match web_auth {
+            Some(credentials) if !credentials.contains(':') => {
+                return Err("web authentication must be given as \"user:password\"".to_string());
+            }
+            Some(credentials) => {
+                Some(format!("Basic {}", BASE64_STANDARD.encode(credentials)).into())
+            }
+            None => None,
+        };
+        Ok(Svc {
+            lifecycle,
+            api,
+            authorization,
+        })
+    }
+
+    /// Whether a request has the credentials required, if any.
+    fn authorized(&self, req: &Request<Incoming>) -> bool {
+        let Some(expected) = &self.authorization else {
+            return true;
+        };
+        let Some(given) = req.headers().get(AUTHORIZATION) else {
+            return false;
+        };
+        // Compare every byte, so the time taken doesn't reveal how much matched.
+        let (given, expected) = (given.as_bytes(), expected.as_bytes());
+        given.len() == expected.len()
+            && given
+                .iter()
+                .zip(expected)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Handles a request to one of the lifecycle endpoints, which must be a POST
+    /// or PUT. The handler notifies the given channel and responds with `message`.
+    fn lifecycle(
+        &self,
+        req: &Request<Incoming>,
+        channel: fn(&Lifecycle) -> &watch::Sender<()>,
+        message: &'static str,
+    ) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+        let Some(lifecycle) = &self.lifecycle else {
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body("Lifecycle endpoints are not enabled.\n".into());
+        };
+        if !matches!(*req.method(), Method::POST | Method::PUT) {
+            return Response::builder()
+                .header("Allow", "POST, PUT")
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Full::default());
+        }
+        channel(lifecycle).send_replace(());
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(message.into())
+    }
+
+    /// Handles a request to an `/api/` endpoint, which must be a GET.
+    async fn api(
+        api: Option<Arc<dyn Api>>,
+        req: Request<Incoming>,
+    ) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+        let endpoint = req.uri().path()["/api/".len()..].to_string();
+        let response = match api {
+            Some(_) if req.method() != Method::GET => {
+                return Response::builder()
+                    .header("Allow", "GET")
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Full::default());
+            }
+            Some(api) => {
+                let params: HashMap<String, String> =
+                    form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                        .into_owned()
+                        .collect();
+                task::spawn_blocking(move || api.get(&endpoint, &params))
+                    .await
+                    .unwrap_or_else(|err| {
+                        error!("API request panicked: {}", err);
+                        Some(ApiResponse::error(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "internal error",
+                        ))
+                    })
+            }
+            None => None,
+        };
+        let response = response
+            .unwrap_or_else(|| ApiResponse::error(StatusCode::NOT_FOUND, "no such endpoint"));
+        Response::builder()
+            .header("Content-Type", "application/json")
+            .status(response.status)
+            .body(response.body.into())
+    }
+}
 
 impl Service<Request<Incoming>> for Svc {
     type Response = Response<Full<Bytes>>;
@@ -37,6 +202,16 @@ impl Service<Request<Incoming>> for Svc {
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
+        if req.uri().path() != "/" && !self.authorized(&req) {
+            let res = Response::builder()
+                .header(WWW_AUTHENTICATE, "Basic realm=\"prom-convert\"")
+                .status(StatusCode::UNAUTHORIZED)
+                .body(Full::default());
+            return Box::pin(async { res });
+        }
+        if req.uri().path().starts_with("/api/") {
+            return Box::pin(Svc::api(self.api.clone(), req));
+        }
         let res = match req.uri().path() {
             "/" => Response::builder()
                 .header("Content-Type", "text/html; charset=utf-8")
@@ -54,12 +229,8 @@ impl Service<Request<Incoming>> for Svc {
             }
             "/-/healthy" => Response::builder().status(StatusCode::OK).body("OK".into()),
             "/-/ready" => Response::builder().status(StatusCode::OK).body("OK".into()),
-            "/-/reload" => Response::builder()
-                .status(StatusCode::NOT_IMPLEMENTED)
-                .body(Full::default()),
-            "/-/quit" => Response::builder()
-                .status(StatusCode::NOT_IMPLEMENTED)
-                .body(Full::default()),
+            "/-/reload" => self.lifecycle(&req, |l| &l.reload, "Reloading configuration.\n"),
+            "/-/quit" => self.lifecycle(&req, |l| &l.quit, "Shutting down.\n"),
             _ => Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(Full::default()),
@@ -68,10 +239,46 @@ impl Service<Request<Incoming>> for Svc {
     }
 }
 
-pub fn serve(tcp_stream: TcpStream) {
-    tokio::spawn(
-        http1::Builder::new()
-            .keep_alive(false)
-            .serve_connection(TokioIo::new(tcp_stream), Svc {}),
-    );
+/// Creates the TLS acceptor used to serve HTTPS, from the PEM files holding the
+/// certificate chain and its private key.
+pub fn tls_acceptor(cert: &str, key: &str) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("unable to read TLS certificate {}: {}", cert, err))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|err| format!("unable to read TLS key {}: {}", key, err))?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("invalid TLS certificate or key: {}", err))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serves the requests of a connection, after a TLS handshake if `tls` is given.
+/// Completes once the response has been sent and the connection closed.
+pub async fn serve(tcp_stream: TcpStream, tls: Option<TlsAcceptor>, svc: Svc) {
+    let mut builder = http1::Builder::new();
+    builder.keep_alive(false);
+    let result = match tls {
+        Some(tls) => match tls.accept(tcp_stream).await {
+            Ok(tls_stream) => {
+                builder
+                    .serve_connection(TokioIo::new(tls_stream), svc)
+                    .await
+            }
+            Err(err) => {
+                debug!("TLS handshake failed: {}", err);
+                return;
+            }
+        },
+        None => {
+            builder
+                .serve_connection(TokioIo::new(tcp_stream), svc)
+                .await
+        }
+    };
+    if let Err(err) = result {
+        debug!("error serving connection: {}", err);
+    }
 }
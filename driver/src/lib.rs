@@ -16,197 +16,109 @@
 #[macro_use]
 extern crate log;
 
-use std::io::Read;
-use std::process::ExitCode;
-use std::time::Instant;
-use std::time::{Duration, SystemTime};
-
-use hyper::Uri;
-use tokio::net::TcpListener;
-use tokio::runtime;
-use tokio::signal;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::task;
-use tokio::time::MissedTickBehavior;
-
+#[cfg(feature = "scraper")]
+mod args;
+#[cfg(feature = "scraper")]
+mod discovery;
+#[cfg(feature = "scraper")]
+mod dump;
+#[cfg(feature = "scraper")]
 pub mod fetch;
+#[cfg(feature = "scraper")]
 pub mod http;
+#[cfg(feature = "scraper")]
+mod metrics;
 pub mod parse;
-
-pub trait Exporter {
-    fn export(&mut self, timestamp_millis: u64, family: &parse::MetricFamily) -> bool;
-    fn close(&mut self);
+#[cfg(feature = "scraper")]
+pub mod push;
+pub mod relabel;
+#[cfg(feature = "scraper")]
+mod scraper;
+#[cfg(feature = "scraper")]
+mod telemetry;
+#[cfg(feature = "scraper")]
+mod template;
+#[cfg(feature = "scraper")]
+mod validate;
+
+#[cfg(feature = "scraper")]
+pub use args::{DriverArgs, DriverConfig};
+#[cfg(feature = "scraper")]
+pub use scraper::{
+    collect_once, parse_duration, parse_timestamp, run, Args, ConfigDuration, Reload,
+};
+#[cfg(feature = "scraper")]
+pub use tokio::time::MissedTickBehavior;
+
+/// Logs a sample whose value an exporter couldn't parse, and counts it in
+/// `prom_convert_unparseable_samples_total`.
+#[cfg(feature = "scraper")]
+pub fn unparseable_sample(sample: &parse::Sample) {
+    warn!(
+        "unable to parse value {:?} of sample {}",
+        sample.value, sample.var
+    );
+    metrics::UNPARSEABLE_SAMPLES.inc();
 }
 
-pub trait Args {
-    /// The (host, port) address to listen on for connections.
-    fn addr(&self) -> (&str, u16);
-
-    fn instance(&self) -> Option<&str>;
-    fn job(&self) -> Option<&str>;
-
-    /// How often metrics will be scraped.
-    fn interval(&self) -> Duration;
-
-    /// How many scrapes to hold in memory before dropping samples.
-    fn buffer(&self) -> usize;
-
-    /// The URL of a Prometheus client endpoint to scrape.
-    /// If "-", then read from stdin.
-    fn target(&self) -> &str;
+/// Logs a metric family that an exporter couldn't write, and counts it in
+/// `prom_convert_export_failures_total`. Exporters that write in the
+/// background call this for failures they find after `export` has returned.
+#[cfg(feature = "scraper")]
+pub fn export_failed(exporter: &str) {
+    error!("unable to export metric family to {}", exporter);
+    metrics::EXPORT_FAILURES
+        .with_label_values(&[exporter])
+        .inc();
 }
 
-async fn collect(url: Uri, tx: Sender<(u64, String)>) {
-    debug!("collecting sample");
-    match fetch::fetch(url).await {
-        Ok((timestamp_millis, exposition)) => {
-            debug!("collected sample {}", timestamp_millis);
-            if let Err(err) = tx.try_send((timestamp_millis, exposition)) {
-                error!("unable to send sample {}: {}", timestamp_millis, err);
-            }
-        }
-        Err(err) => error!("unable to collect sample: {}", err),
-    }
-}
+pub trait Exporter {
+    /// A short name for the destination, such as "sqlite", used in log
+    /// messages and as the `exporter` label of the collector's own metrics.
+    fn name(&self) -> &'static str;
 
-async fn polling_loop(args: &impl Args, url: Uri, tx: Sender<(u64, String)>) {
-    let addr = args.addr();
-    let listener = match TcpListener::bind(addr).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            error!("error binding to {}:{}: {}", addr.0, addr.1, err);
-            return;
-        }
-    };
-    info!("listening on {}:{}", addr.0, addr.1);
+    /// Writes out the samples of a metric family scraped at `timestamp_millis`.
+    /// Returns false if the family could not be exported.
+    fn export(&mut self, timestamp_millis: u64, family: &parse::MetricFamily) -> bool;
 
-    let mut sample_interval = tokio::time::interval(args.interval());
-    sample_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    /// Writes out any buffered samples without finalizing the output, so that
+    /// they are durable while the collector keeps running. Called periodically
+    /// if a flush interval is set. Exporters that don't buffer needn't implement it.
+    fn flush(&mut self) {}
 
-    loop {
-        tokio::select! {
-            _ = signal::ctrl_c() => {
-                info!("Interrupt signal received.");
-                break
-            }
-            _ = sample_interval.tick() => {
-              debug!("scheduling sample");
-              tokio::spawn(collect(url.clone(), tx.clone()));
-            }
-            Ok((tcp_stream, _)) = listener.accept() => {
-              http::serve(tcp_stream);
-            }
-        }
-    }
-}
+    /// Called once every metric family of a scrape has been exported, so that
+    /// exporters that batch the samples of a scrape can send the batch without
+    /// waiting for the next one. Exporters that don't needn't implement it.
+    fn end_scrape(&mut self) {}
 
-fn read_from_stdin(tx: Sender<(u64, String)>) -> ExitCode {
-    let mut input = String::new();
-    if let Err(err) = std::io::stdin().read_to_string(&mut input) {
-        error!("error reading from stdin: {}", err);
-        return ExitCode::FAILURE;
-    }
-    let timestamp = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-    if let Err(err) = tx.try_send((timestamp, input)) {
-        error!("unable to send sample: {}", err);
-        return ExitCode::FAILURE;
-    }
-    ExitCode::SUCCESS
+    /// Flushes any buffered samples and finalizes the output.
+    /// Called once, after the last scrape has been exported, including on shutdown.
+    fn close(&mut self);
 }
 
-async fn writer_loop(
-    mut rx: Receiver<(u64, String)>,
-    instance: Option<String>,
-    job: Option<String>,
-    mut exporter: Box<dyn Exporter + Send>,
-) {
-    debug!("writer started");
-    loop {
-        match rx.recv().await {
-            Some((timestamp_millis, exposition)) => {
-                debug!("processing sample {}", timestamp_millis);
-                let start_marker = Instant::now();
-                if let Some(families) =
-                    parse::parse(instance.as_deref(), job.as_deref(), &exposition)
-                {
-                    let parse_time = start_marker.elapsed();
-                    info!("parse time: {:?}", parse_time);
-                    for family in families {
-                        if !exporter.export(timestamp_millis, &family) {
-                            error!("unable to export metric family");
-                        }
-                        // Yield to the scheduler to allow other tasks to run
-                        task::yield_now().await;
-                    }
-                    let write_time = start_marker.elapsed();
-                    info!("write time: {:?}", write_time - parse_time);
-                }
-                debug!("processing done");
-            }
-            None => {
-                debug!("no more samples to process");
-                break;
-            }
-        }
-    }
-    exporter.close();
+/// An exporter that writes nothing, used to check that targets can be scraped
+/// and parsed. When closed, it logs how much would have been exported.
+#[derive(Default)]
+pub struct DryRunExporter {
+    families: usize,
+    samples: usize,
 }
 
-async fn run_async(args: &impl Args, exporter: Box<dyn Exporter + Send>) -> ExitCode {
-    let uri = match args.target() {
-        "-" => None,
-        uri => match uri.parse::<Uri>() {
-            Ok(uri) => Some(uri),
-            Err(err) => {
-                error!("invalid URI {}: {}", uri, err);
-                return ExitCode::FAILURE;
-            }
-        },
-    };
-
-    let instance = if let Some(instance) = args.instance() {
-        Some(instance)
-    } else {
-        uri.as_ref()
-            .and_then(|url| url.authority())
-            .map(|f| f.as_str())
+impl Exporter for DryRunExporter {
+    fn name(&self) -> &'static str {
+        "dry_run"
     }
-    .map(|s| s.to_string());
-    let job = args.job().map(|f| f.to_string());
 
-    let (tx, rx) = channel::<(u64, String)>(args.buffer());
-    let writer_task = tokio::spawn(writer_loop(rx, instance, job, exporter));
-
-    let exit_code = match uri {
-        None => read_from_stdin(tx),
-        Some(uri) => {
-            debug!("starting polling loop");
-            polling_loop(args, uri, tx).await;
-            ExitCode::SUCCESS
-        }
-    };
-    debug!("waiting for writer task to complete");
-    if let Err(err) = writer_task.await {
-        error!("error waiting for writer task to complete: {}", err);
+    fn export(&mut self, _timestamp_millis: u64, family: &parse::MetricFamily) -> bool {
+        self.families += 1;
+        self.samples += family.samples.len();
+        true
     }
-    debug!("done");
-    exit_code
-}
 
-pub fn run(args: &impl Args, exporter: Box<dyn Exporter + Send>) -> ExitCode {
-    match runtime::Builder::new_current_thread()
-        .enable_time()
-        .enable_io()
-        .build()
-        .map(|rt| rt.block_on(run_async(args, exporter)))
-    {
-        Ok(exit_code) => exit_code,
-        Err(err) => {
-            error!("error running application thead: {}", err);
-            ExitCode::FAILURE
-        }
+    fn close(&mut self) {
+        info!(
+            "dry run: {} metric families with {} samples would have been exported",
+            self.families, self.samples
+        );
     }
 }
@@ -0,0 +1,183 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Metrics about the collector itself, served by the `/metrics` endpoint.
+
+use std::sync::LazyLock;
+
+use prometheus::{
+    exponential_buckets, register_histogram, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Histogram, IntCounter, IntCounterVec, IntGauge,
+};
+
+pub static SCRAPE_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "prom_convert_scrape_duration_seconds",
+        "Time taken to fetch the exposition from a target, including retries."
+    )
+    .unwrap()
+});
+
+pub static SCRAPE_BODY_SIZE: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "prom_convert_scrape_body_size_bytes",
+        "Size of the response bodies scraped from targets, after decompression.",
+        // From 1 KiB up to 256 MiB.
+        exponential_buckets(1024.0, 4.0, 10).unwrap()
+    )
+    .unwrap()
+});
+
+pub static PARSE_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "prom_convert_parse_duration_seconds",
+        "Time taken to parse a scraped exposition."
+    )
+    .unwrap()
+});
+
+pub static WRITE_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "prom_convert_write_duration_seconds",
+        "Time taken to export the metric families of a scrape."
+    )
+    .unwrap()
+});
+
+pub static DROPPED_SCRAPES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_dropped_scrapes_total",
+        "Number of scrapes dropped because the writer fell behind."
+    )
+    .unwrap()
+});
+
+pub static SCRAPE_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_scrape_failures_total",
+        "Number of scrapes that failed, after any retries."
+    )
+    .unwrap()
+});
+
+pub static PARSE_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_parse_failures_total",
+        "Number of scraped expositions that could not be parsed."
+    )
+    .unwrap()
+});
+
+pub static SCRAPES_OVER_LIMIT: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_scrapes_over_limit_total",
+        "Number of scrapes dropped because they had more samples than the sample limit."
+    )
+    .unwrap()
+});
+
+pub static EXPORT_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "prom_convert_export_failures_total",
+        "Number of metric families that an exporter could not write.",
+        &["exporter"]
+    )
+    .unwrap()
+});
+
+pub static UNPARSEABLE_SAMPLES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_unparseable_samples_total",
+        "Number of samples that an exporter could not parse the value of."
+    )
+    .unwrap()
+});
+
+pub static PARSED_FAMILIES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_parsed_families_total",
+        "Number of metric families parsed from scraped expositions."
+    )
+    .unwrap()
+});
+
+pub static PARSED_SAMPLES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_parsed_samples_total",
+        "Number of samples parsed from scraped expositions."
+    )
+    .unwrap()
+});
+
+pub static PARSED_BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_parsed_bytes_total",
+        "Size of the scraped expositions that were parsed, in bytes."
+    )
+    .unwrap()
+});
+
+pub static COUNTER_ANOMALIES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_counter_anomalies_total",
+        "Number of times a scraped counter decreased without its target being restarted."
+    )
+    .unwrap()
+});
+
+pub static DROPPED_LABELS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "prom_convert_dropped_labels_total",
+        "Number of labels dropped from a target's metric family for having too many values."
+    )
+    .unwrap()
+});
+
+pub static QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "prom_convert_queue_depth",
+        "Number of scrapes waiting to be parsed and written."
+    )
+    .unwrap()
+});
+
+pub static QUEUE_CAPACITY: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "prom_convert_queue_capacity",
+        "Number of scrapes that can wait to be written before new ones are dropped."
+    )
+    .unwrap()
+});
+
+/// Registers all metrics, so they are served even before they are first observed.
+pub fn register() {
+    LazyLock::force(&SCRAPE_DURATION);
+    LazyLock::force(&SCRAPE_BODY_SIZE);
+    LazyLock::force(&PARSE_DURATION);
+    LazyLock::force(&WRITE_DURATION);
+    LazyLock::force(&DROPPED_SCRAPES);
+    LazyLock::force(&SCRAPE_FAILURES);
+    LazyLock::force(&PARSE_FAILURES);
+    LazyLock::force(&SCRAPES_OVER_LIMIT);
+    LazyLock::force(&EXPORT_FAILURES);
+    LazyLock::force(&UNPARSEABLE_SAMPLES);
+    LazyLock::force(&PARSED_FAMILIES);
+    LazyLock::force(&PARSED_SAMPLES);
+    LazyLock::force(&PARSED_BYTES);
+    LazyLock::force(&COUNTER_ANOMALIES);
+    LazyLock::force(&DROPPED_LABELS);
+    LazyLock::force(&QUEUE_DEPTH);
+    LazyLock::force(&QUEUE_CAPACITY);
+}
@@ -13,8 +13,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+use pest::error::{ErrorVariant, InputLocation, LineColLocation};
 use pest::iterators::Pair;
-use pest::Parser;
+use pest::{Parser, Position};
+
+mod protobuf;
+mod scan;
 
 #[derive(pest_derive::Parser)]
 #[grammar = "./prometheus.pest"]
@@ -31,23 +39,60 @@ pub enum SampleType {
     Untyped,
 }
 
-pub type LabelSet<'a> = Vec<(&'a str, &'a str)>;
+/// Label names paired with their (unescaped) values.
+pub type LabelSet<'a> = Vec<(&'a str, Cow<'a, str>)>;
+
+/// An OpenMetrics exemplar attached to a sample, e.g. `# {trace_id="abc"} 0.07 1623.5`.
+#[derive(Clone, Debug)]
+pub struct Exemplar<'a> {
+    pub labels: LabelSet<'a>,
+    pub value: f64,
+    /// Timestamp in milliseconds since the epoch, if one was given.
+    pub timestamp: Option<i64>,
+}
 
 #[derive(Clone, Debug)]
 pub struct Sample<'a> {
     pub var: &'a str,
     pub labels: LabelSet<'a>,
     pub value: &'a str,
-    // TODO: Support exemplars?
-    // timestamp: Option<&'a str>,
-    // exemplar: Option<Exemplar>,
+    /// Explicit timestamp in milliseconds since the epoch, if one was given.
+    pub timestamp: Option<i64>,
+    pub exemplar: Option<Exemplar<'a>>,
+}
+
+/// A single histogram series, decoded from its `_bucket`, `_sum` and `_count` samples.
+#[derive(Clone, Debug, Default)]
+pub struct HistogramSeries<'a> {
+    /// The labels identifying the series, excluding `le`.
+    pub labels: LabelSet<'a>,
+    pub timestamp: Option<i64>,
+    /// Cumulative `(upper_bound, count)` pairs, sorted by upper bound.
+    pub buckets: Vec<(f64, f64)>,
+    pub sum: Option<f64>,
+    pub count: Option<f64>,
+}
+
+/// A single summary series, decoded from its quantile, `_sum` and `_count` samples.
+#[derive(Clone, Debug, Default)]
+pub struct SummarySeries<'a> {
+    /// The labels identifying the series, excluding `quantile`.
+    pub labels: LabelSet<'a>,
+    pub timestamp: Option<i64>,
+    /// `(quantile, value)` pairs, sorted by quantile.
+    pub quantiles: Vec<(f64, f64)>,
+    pub sum: Option<f64>,
+    pub count: Option<f64>,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct MetricFamily<'a> {
     pub var: Option<&'a str>, // TODO: this shouldn't be optional?
-    pub help: Option<&'a str>,
+    /// The (unescaped) text of the `# HELP` line.
+    pub help: Option<Cow<'a, str>>,
     pub r#type: SampleType,
+    /// The unit given by an OpenMetrics `# UNIT` line, such as "seconds".
+    pub unit: Option<&'a str>,
     pub samples: Vec<Sample<'a>>,
 }
 
@@ -56,63 +101,76 @@ impl<'a> MetricFamily<'a> {
         instance: Option<&'a str>,
         job: Option<&'a str>,
         pair: Pair<'a, Rule>,
-    ) -> Option<MetricFamily<'a>> {
+        openmetrics: bool,
+        lenient: bool,
+    ) -> Result<MetricFamily<'a>, Invalid<'a>> {
         debug_assert_eq!(pair.as_rule(), Rule::metricfamily);
+        let start = pair.as_span().start_pos();
         let mut metric_family = MetricFamily::default();
         for child in pair.into_inner() {
-            match child.as_rule() {
-                Rule::metricdescriptor => {
-                    if !metric_family.samples.is_empty() {
-                        error!("Metric Descriptor after samples");
-                        return None;
-                    }
-                    if !metric_family.parse_metric_descriptor(child) {
-                        return None;
-                    }
+            let position = child.as_span().start_pos();
+            let result = match child.as_rule() {
+                Rule::metricdescriptor if !metric_family.samples.is_empty() => {
+                    Err("metric descriptor after samples".to_string())
                 }
-                Rule::metric => match Self::parse_sample(instance, job, child) {
-                    Some(sample) => metric_family.samples.push(sample),
-                    None => return None,
-                },
+                Rule::metricdescriptor => metric_family.parse_metric_descriptor(child),
+                Rule::metric => Self::parse_sample(instance, job, child, openmetrics, lenient)
+                    .map(|sample| metric_family.samples.push(sample)),
                 _ => unreachable!(),
-            }
+            };
+            result.map_err(|message| (position, message))?;
         }
-        Some(metric_family)
+        if openmetrics {
+            metric_family
+                .apply_openmetrics_rules()
+                .map_err(|message| (start, message))?;
+        }
+        Ok(metric_family)
     }
 
-    fn parse_metric_descriptor(&mut self, pair: Pair<'a, Rule>) -> bool {
+    fn parse_metric_descriptor(&mut self, pair: Pair<'a, Rule>) -> Result<(), String> {
         assert_eq!(pair.as_rule(), Rule::metricdescriptor);
         let mut descriptor = pair.into_inner();
-        let descriptor_type = descriptor.next().unwrap();
+        let keyword = descriptor.next().unwrap().as_str();
         let metric_name = descriptor.next().unwrap().as_str();
+        let value = descriptor.next().unwrap().as_str();
+        self.apply_descriptor(keyword, metric_name, value)
+    }
+
+    /// Applies a `# HELP`, `# TYPE` or `# UNIT` descriptor, given its keyword, the
+    /// name of the metric it describes and its (still escaped) value. Returns an
+    /// error if it describes a different metric than the family's earlier descriptors.
+    fn apply_descriptor(
+        &mut self,
+        keyword: &str,
+        metric_name: &'a str,
+        value: &'a str,
+    ) -> Result<(), String> {
         match self.var {
             None => {
                 self.var = Some(metric_name);
             }
             Some(var) => {
                 if metric_name != var {
-                    error!(
+                    return Err(format!(
                         "metric name mismatch: {} {}, expected {}",
-                        descriptor_type.as_str(),
-                        metric_name,
-                        var
-                    );
-                    return false;
+                        keyword, metric_name, var
+                    ));
                 }
             }
         }
-        match descriptor_type.as_rule() {
-            Rule::kw_help => {
+        match keyword {
+            "HELP" => {
                 if self.help.is_some() {
                     warn!("help for {} already set, overwriting", metric_name);
                 }
-                self.help = Some(descriptor.next().unwrap().as_str());
+                self.help = Some(unescape(value));
             }
-            Rule::kw_type => {
+            "TYPE" => {
                 if self.r#type != SampleType::Untyped {
                     warn!("type for {} already set, overwriting", metric_name);
                 }
-                self.r#type = match descriptor.next().unwrap().as_str() {
+                self.r#type = match value {
                     "counter" => SampleType::Counter,
                     "gauge" => SampleType::Gauge,
                     "histogram" => SampleType::Histogram,
@@ -121,90 +179,1085 @@ impl<'a> MetricFamily<'a> {
                     _ => unreachable!(),
                 }
             }
+            "UNIT" => {
+                if self.unit.is_some() {
+                    warn!("unit for {} already set, overwriting", metric_name);
+                }
+                self.unit = Some(value);
+            }
             _ => unreachable!(),
         }
-        true
+        Ok(())
     }
 
     fn parse_sample(
         instance: Option<&'a str>,
         job: Option<&'a str>,
         pair: Pair<'a, Rule>,
-    ) -> Option<Sample<'a>> {
+        openmetrics: bool,
+        lenient: bool,
+    ) -> Result<Sample<'a>, String> {
         assert_eq!(pair.as_rule(), Rule::metric);
 
         let mut descriptor = pair.into_inner();
         let metric_name = descriptor.next().unwrap().as_str();
         let labels = if descriptor.peek().unwrap().as_rule() == Rule::labels {
-            parse_labels(instance, job, descriptor.next().unwrap())
+            parse_labels(
+                metric_name,
+                instance,
+                job,
+                descriptor.next().unwrap(),
+                lenient,
+            )?
         } else {
-            Vec::new()
+            target_labels(instance, job)
         };
         let value = descriptor.next().unwrap().as_str();
-        Some(Sample {
+        let timestamp = match descriptor.peek() {
+            Some(pair) if pair.as_rule() == Rule::timestamp => Some(parse_timestamp(
+                metric_name,
+                descriptor.next().unwrap().as_str(),
+                openmetrics,
+            )?),
+            _ => None,
+        };
+        let exemplar = match descriptor.next() {
+            Some(pair) => Some(parse_exemplar(metric_name, pair, lenient)?),
+            None => None,
+        };
+        Ok(Sample {
             var: metric_name,
             labels,
             value,
+            timestamp,
+            exemplar,
         })
     }
+
+    /// Applies the OpenMetrics naming rules to the family, which for now only
+    /// concern counters. Returns an error if its samples don't follow them.
+    fn apply_openmetrics_rules(&mut self) -> Result<(), String> {
+        if self.r#type != SampleType::Counter || self.strip_total_suffix() {
+            return Ok(());
+        }
+        Err(format!(
+            "invalid sample names for counter {}",
+            self.var.unwrap_or_default()
+        ))
+    }
+
+    /// Applies the OpenMetrics naming rules for counters, where the samples of a
+    /// counter `foo` are named `foo_total`. The suffix is removed so the samples
+    /// are named after the family, as in the Prometheus text format, and any
+    /// `foo_created` samples are dropped. Returns false if a sample has some
+    /// other name.
+    fn strip_total_suffix(&mut self) -> bool {
+        let Some(var) = self.var else {
+            return false;
+        };
+        let mut valid = true;
+        self.samples.retain_mut(|sample| {
+            match sample.var.strip_prefix(var) {
+                Some("_total") | Some("") => sample.var = var,
+                Some("_created") => {
+                    debug!("dropping {} sample", sample.var);
+                    return false;
+                }
+                _ => valid = false,
+            }
+            true
+        });
+        valid
+    }
+
+    /// Decodes a histogram family into one `HistogramSeries` per distinct label set.
+    /// Returns `None` if the family is not a histogram or a sample can't be decoded.
+    pub fn as_histogram(&self) -> Option<Vec<HistogramSeries<'a>>> {
+        if self.r#type != SampleType::Histogram {
+            return None;
+        }
+        let var = self.var?;
+        let mut series: Vec<HistogramSeries<'a>> = Vec::new();
+        let mut index: HashMap<LabelSet<'a>, usize> = HashMap::new();
+        for sample in self.samples.iter() {
+            let suffix = sample.var.strip_prefix(var).unwrap_or_default();
+            if !matches!(suffix, "_bucket" | "_sum" | "_count") {
+                warn!("unexpected sample {} in histogram {}", sample.var, var);
+                continue;
+            }
+            let value = match parse_value(sample.value) {
+                Some(value) => value,
+                None => {
+                    error!(
+                        "unable to parse value {:?} for {}",
+                        sample.value, sample.var
+                    );
+                    return None;
+                }
+            };
+            let (labels, le) = split_label(&sample.labels, "le");
+            let i = *index.entry(labels.clone()).or_insert_with(|| {
+                series.push(HistogramSeries {
+                    labels,
+                    timestamp: sample.timestamp,
+                    ..Default::default()
+                });
+                series.len() - 1
+            });
+            let entry = &mut series[i];
+            match suffix {
+                "_bucket" => {
+                    let le = match le.map(|le| (le, parse_value(le))) {
+                        Some((_, Some(le))) => le,
+                        Some((le, None)) => {
+                            error!("unable to parse bucket bound {:?} for {}", le, sample.var);
+                            return None;
+                        }
+                        None => {
+                            error!("missing le label for {}", sample.var);
+                            return None;
+                        }
+                    };
+                    entry.buckets.push((le, value));
+                }
+                "_sum" => entry.sum = Some(value),
+                "_count" => entry.count = Some(value),
+                _ => unreachable!(),
+            }
+        }
+        for entry in series.iter_mut() {
+            entry.buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+        }
+        Some(series)
+    }
+
+    /// Decodes a summary family into one `SummarySeries` per distinct label set.
+    /// Returns `None` if the family is not a summary or a sample can't be decoded.
+    pub fn as_summary(&self) -> Option<Vec<SummarySeries<'a>>> {
+        if self.r#type != SampleType::Summary {
+            return None;
+        }
+        let var = self.var?;
+        let mut series: Vec<SummarySeries<'a>> = Vec::new();
+        let mut index: HashMap<LabelSet<'a>, usize> = HashMap::new();
+        for sample in self.samples.iter() {
+            let suffix = sample.var.strip_prefix(var).unwrap_or_default();
+            if !matches!(suffix, "" | "_sum" | "_count") {
+                warn!("unexpected sample {} in summary {}", sample.var, var);
+                continue;
+            }
+            let value = match parse_value(sample.value) {
+                Some(value) => value,
+                None => {
+                    error!(
+                        "unable to parse value {:?} for {}",
+                        sample.value, sample.var
+                    );
+                    return None;
+                }
+            };
+            let (labels, quantile) = split_label(&sample.labels, "quantile");
+            let i = *index.entry(labels.clone()).or_insert_with(|| {
+                series.push(SummarySeries {
+                    labels,
+                    timestamp: sample.timestamp,
+                    ..Default::default()
+                });
+                series.len() - 1
+            });
+            let entry = &mut series[i];
+            match suffix {
+                "" => {
+                    let quantile = match quantile.map(|q| (q, parse_value(q))) {
+                        Some((_, Some(quantile))) => quantile,
+                        Some((q, None)) => {
+                            error!("unable to parse quantile {:?} for {}", q, sample.var);
+                            return None;
+                        }
+                        None => {
+                            error!("missing quantile label for {}", sample.var);
+                            return None;
+                        }
+                    };
+                    entry.quantiles.push((quantile, value));
+                }
+                "_sum" => entry.sum = Some(value),
+                "_count" => entry.count = Some(value),
+                _ => unreachable!(),
+            }
+        }
+        for entry in series.iter_mut() {
+            entry.quantiles.sort_by(|a, b| a.0.total_cmp(&b.0));
+        }
+        Some(series)
+    }
 }
 
-fn parse_labels<'a>(
-    instance: Option<&'a str>,
-    job: Option<&'a str>,
+/// Label names paired with their values, owned rather than borrowed from the exposition.
+pub type OwnedLabelSet = Vec<(String, String)>;
+
+fn owned_labels(labels: &LabelSet) -> OwnedLabelSet {
+    labels
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+fn borrowed_labels(labels: &OwnedLabelSet) -> LabelSet<'_> {
+    labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), Cow::Borrowed(value.as_str())))
+        .collect()
+}
+
+/// An `Exemplar` that owns its data.
+#[derive(Clone, Debug)]
+pub struct OwnedExemplar {
+    pub labels: OwnedLabelSet,
+    pub value: f64,
+    pub timestamp: Option<i64>,
+}
+
+impl From<&Exemplar<'_>> for OwnedExemplar {
+    fn from(exemplar: &Exemplar) -> Self {
+        OwnedExemplar {
+            labels: owned_labels(&exemplar.labels),
+            value: exemplar.value,
+            timestamp: exemplar.timestamp,
+        }
+    }
+}
+
+/// A `Sample` that owns its data.
+#[derive(Clone, Debug)]
+pub struct OwnedSample {
+    pub var: String,
+    pub labels: OwnedLabelSet,
+    pub value: String,
+    /// Explicit timestamp in milliseconds since the epoch, if one was given.
+    pub timestamp: Option<i64>,
+    pub exemplar: Option<OwnedExemplar>,
+}
+
+impl OwnedSample {
+    /// Returns a `Sample` that borrows from this one.
+    pub fn as_borrowed(&self) -> Sample<'_> {
+        Sample {
+            var: &self.var,
+            labels: borrowed_labels(&self.labels),
+            value: &self.value,
+            timestamp: self.timestamp,
+            exemplar: self.exemplar.as_ref().map(|exemplar| Exemplar {
+                labels: borrowed_labels(&exemplar.labels),
+                value: exemplar.value,
+                timestamp: exemplar.timestamp,
+            }),
+        }
+    }
+}
+
+impl From<&Sample<'_>> for OwnedSample {
+    fn from(sample: &Sample) -> Self {
+        OwnedSample {
+            var: sample.var.to_string(),
+            labels: owned_labels(&sample.labels),
+            value: sample.value.to_string(),
+            timestamp: sample.timestamp,
+            exemplar: sample.exemplar.as_ref().map(OwnedExemplar::from),
+        }
+    }
+}
+
+/// A `MetricFamily` that owns its data, so it can outlive the exposition it was parsed from.
+#[derive(Clone, Debug, Default)]
+pub struct OwnedMetricFamily {
+    pub var: Option<String>,
+    pub help: Option<String>,
+    pub r#type: SampleType,
+    pub unit: Option<String>,
+    pub samples: Vec<OwnedSample>,
+}
+
+impl OwnedMetricFamily {
+    /// Returns a `MetricFamily` that borrows from this one, so that it can be
+    /// decoded or handed to an `Exporter` like a freshly parsed family.
+    pub fn as_borrowed(&self) -> MetricFamily<'_> {
+        MetricFamily {
+            var: self.var.as_deref(),
+            help: self.help.as_deref().map(Cow::Borrowed),
+            r#type: self.r#type,
+            unit: self.unit.as_deref(),
+            samples: self.samples.iter().map(OwnedSample::as_borrowed).collect(),
+        }
+    }
+}
+
+impl From<&MetricFamily<'_>> for OwnedMetricFamily {
+    fn from(family: &MetricFamily) -> Self {
+        OwnedMetricFamily {
+            var: family.var.map(|s| s.to_string()),
+            help: family.help.as_ref().map(|s| s.to_string()),
+            r#type: family.r#type,
+            unit: family.unit.map(|s| s.to_string()),
+            samples: family.samples.iter().map(OwnedSample::from).collect(),
+        }
+    }
+}
+
+impl MetricFamily<'_> {
+    /// Copies the family into an `OwnedMetricFamily`, which doesn't borrow from
+    /// the exposition buffer and so can be stored or sent to another thread.
+    pub fn to_owned_family(&self) -> OwnedMetricFamily {
+        OwnedMetricFamily::from(self)
+    }
+}
+
+impl Sample<'_> {
+    /// Copies the sample into an `OwnedSample`.
+    pub fn to_owned_sample(&self) -> OwnedSample {
+        OwnedSample::from(self)
+    }
+}
+
+/// Parses a sample value as defined by the exposition format. In addition to
+/// ordinary floating point numbers (with an optional sign and exponent), this
+/// accepts the special values `NaN`, `+Inf` and `-Inf`.
+pub fn parse_value(value: &str) -> Option<f64> {
+    match value {
+        "NaN" => Some(f64::NAN),
+        "+Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        _ => value.parse::<f64>().ok(),
+    }
+}
+
+/// Splits the label called `name` out of a label set, returning the remaining
+/// labels along with the value of the removed label, if present.
+fn split_label<'a, 'b>(labels: &'b LabelSet<'a>, name: &str) -> (LabelSet<'a>, Option<&'b str>) {
+    let mut value = None;
+    let mut rest = LabelSet::with_capacity(labels.len());
+    for (label, v) in labels.iter() {
+        if *label == name {
+            value = Some(v.as_ref());
+        } else {
+            rest.push((label, v.clone()));
+        }
+    }
+    (rest, value)
+}
+
+/// Unescapes the `\\`, `\"` and `\n` sequences allowed in label values and
+/// help text. The input is borrowed as-is when it contains no escapes.
+fn unescape(value: &str) -> Cow<'_, str> {
+    if !value.contains('\\') {
+        return Cow::Borrowed(value);
+    }
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(c @ ('\\' | '"')) => out.push(c),
+            Some(c) => {
+                warn!("unknown escape sequence \\{} in {:?}", c, value);
+                out.push('\\');
+                out.push(c);
+            }
+            None => {
+                warn!("trailing backslash in {:?}", value);
+                out.push('\\');
+            }
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Parses a timestamp into milliseconds since the epoch. Timestamps are whole
+/// milliseconds in the Prometheus text format, but seconds with an optional
+/// fraction in OpenMetrics, which is given by `seconds`.
+fn parse_timestamp(metric_name: &str, timestamp: &str, seconds: bool) -> Result<i64, String> {
+    let millis = if seconds {
+        timestamp
+            .parse::<f64>()
+            .map_err(|err| err.to_string())
+            .and_then(|seconds| {
+                let millis = (seconds * 1000.0).round();
+                if millis.is_finite() && millis.abs() < i64::MAX as f64 {
+                    Ok(millis as i64)
+                } else {
+                    Err("out of range".to_string())
+                }
+            })
+    } else {
+        timestamp.parse::<i64>().map_err(|err| err.to_string())
+    };
+    millis.map_err(|err| {
+        format!(
+            "invalid timestamp {:?} for {}: {}",
+            timestamp, metric_name, err
+        )
+    })
+}
+
+/// Parses an exemplar. Exemplars are only defined by OpenMetrics, so their
+/// timestamps are always read as seconds.
+fn parse_exemplar<'a>(
+    metric_name: &str,
     pair: Pair<'a, Rule>,
-) -> LabelSet<'a> {
-    assert_eq!(pair.as_rule(), Rule::labels);
+    lenient: bool,
+) -> Result<Exemplar<'a>, String> {
+    assert_eq!(pair.as_rule(), Rule::exemplar);
+    let mut inner = pair.into_inner();
+    let labels = parse_labels(metric_name, None, None, inner.next().unwrap(), lenient)?;
+    let value = parse_exemplar_value(metric_name, inner.next().unwrap().as_str())?;
+    let timestamp = match inner.next() {
+        Some(pair) => Some(parse_timestamp(metric_name, pair.as_str(), true)?),
+        None => None,
+    };
+    Ok(Exemplar {
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+fn parse_exemplar_value(metric_name: &str, value: &str) -> Result<f64, String> {
+    parse_value(value)
+        .ok_or_else(|| format!("invalid exemplar value {:?} for {}", value, metric_name))
+}
+
+/// The labels identifying the scrape target, which are attached to every sample.
+fn target_labels<'a>(instance: Option<&'a str>, job: Option<&'a str>) -> LabelSet<'a> {
     let mut labels = LabelSet::new();
     if let Some(instance) = instance {
-        labels.push(("instance", instance));
+        labels.push(("instance", Cow::Borrowed(instance)));
     }
     if let Some(job) = job {
-        labels.push(("job", job));
+        labels.push(("job", Cow::Borrowed(job)));
     }
-    labels.extend(pair.into_inner().map(|label| {
+    labels
+}
+
+/// Parses the labels of a sample or exemplar, after the target labels.
+/// A label that is given twice is an error, unless `lenient` is true, in which
+/// case the last value is kept.
+fn parse_labels<'a>(
+    metric_name: &str,
+    instance: Option<&'a str>,
+    job: Option<&'a str>,
+    pair: Pair<'a, Rule>,
+    lenient: bool,
+) -> Result<LabelSet<'a>, String> {
+    assert_eq!(pair.as_rule(), Rule::labels);
+    let mut labels = target_labels(instance, job);
+    let start = labels.len();
+    for label in pair.into_inner() {
         let mut inner = label.into_inner();
         let name = inner.next().unwrap().as_str();
-        let value = inner.next().unwrap().as_str();
+        let value = unescape(inner.next().unwrap().as_str());
         for extra_pair in inner {
             warn!("unexpected token after label: {:?}", extra_pair);
         }
-        (name, value)
-    }));
-    labels
+        push_label(&mut labels, start, metric_name, name, value, lenient)?;
+    }
+    Ok(labels)
+}
+
+/// Adds a label from the exposition to `labels`, of which those from `start`
+/// on also came from the exposition. Returns an error if the label was already
+/// given, unless `lenient` is true, in which case the new value replaces the old.
+fn push_label<'a>(
+    labels: &mut LabelSet<'a>,
+    start: usize,
+    metric_name: &str,
+    name: &'a str,
+    value: Cow<'a, str>,
+    lenient: bool,
+) -> Result<(), String> {
+    match labels[start..].iter_mut().find(|(n, _)| *n == name) {
+        Some(existing) if lenient => {
+            warn!(
+                "duplicate label {} for {}, keeping {:?} over {:?}",
+                name, metric_name, value, existing.1
+            );
+            existing.1 = value;
+        }
+        Some(_) => return Err(format!("duplicate label {} for {}", name, metric_name)),
+        None => labels.push((name, value)),
+    }
+    Ok(())
 }
 
 fn parse_exposition<'a>(
     instance: Option<&'a str>,
     job: Option<&'a str>,
     pair: Pair<'a, Rule>,
-) -> Vec<MetricFamily<'a>> {
+    openmetrics: bool,
+    lenient: bool,
+) -> Result<Vec<MetricFamily<'a>>, Box<pest::error::Error<Rule>>> {
     assert_eq!(pair.as_rule(), Rule::exposition);
-    pair.into_inner()
-        .flat_map(|p| match p.as_rule() {
-            Rule::metricfamily => MetricFamily::parse(instance, job, p),
-            Rule::EOI => None,
+    let mut families = Vec::new();
+    for p in pair.into_inner() {
+        match p.as_rule() {
+            Rule::metricfamily => {
+                match MetricFamily::parse(instance, job, p, openmetrics, lenient) {
+                    Ok(family) => families.push(family),
+                    Err(invalid) => reject(invalid, lenient)?,
+                }
+            }
+            Rule::eof | Rule::EOI => {}
             _ => unreachable!(),
-        })
-        .collect()
+        }
+    }
+    Ok(families)
+}
+
+/// A metric family that matches the grammar but can't be interpreted, such as
+/// one with a duplicate label, along with where and why it went wrong.
+type Invalid<'a> = (Position<'a>, String);
+
+/// Rejects an invalid metric family. This is an error unless `lenient` is
+/// true, in which case the family is skipped with a warning.
+fn reject(invalid: Invalid, lenient: bool) -> Result<(), Box<pest::error::Error<Rule>>> {
+    let (position, message) = invalid;
+    if lenient {
+        warn!(
+            "skipping metric family at line {}: {}",
+            position.line_col().0,
+            message
+        );
+        return Ok(());
+    }
+    Err(Box::new(pest::error::Error::new_from_pos(
+        ErrorVariant::CustomError { message },
+        position,
+    )))
+}
+
+/// Returned when an exposition doesn't match the grammar, or when a metric
+/// family in it can't be interpreted, such as a sample with a duplicate label.
+#[derive(Debug)]
+pub struct ParseError {
+    /// The line on which the error occurred, starting at 1.
+    pub line: usize,
+    /// The column at which the error occurred, starting at 1.
+    pub column: usize,
+    /// The rules the parser expected to match at this position. This is empty
+    /// if the exposition matched the grammar but couldn't be interpreted.
+    pub expected: Vec<Rule>,
+    /// A human readable description of the error, including the offending line.
+    pub message: String,
+}
+
+impl From<pest::error::Error<Rule>> for ParseError {
+    fn from(err: pest::error::Error<Rule>) -> Self {
+        let (line, column) = match err.line_col {
+            LineColLocation::Pos(pos) => pos,
+            LineColLocation::Span(start, _) => start,
+        };
+        let expected = match &err.variant {
+            ErrorVariant::ParsingError { positives, .. } => positives.clone(),
+            ErrorVariant::CustomError { .. } => Vec::new(),
+        };
+        ParseError {
+            line,
+            column,
+            expected,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "parse error: {}", self.message)
+    }
 }
 
+impl std::error::Error for ParseError {}
+
+/// Parses a Prometheus text exposition into its metric families.
+///
+/// The `instance` and `job` labels, if given, are added to every sample.
+/// The returned families borrow from `input`; use `OwnedMetricFamily` to keep
+/// them around longer. If the exposition ends with the OpenMetrics `# EOF`
+/// marker, the `_total` suffix is removed from the samples of counters, and
+/// timestamps are read as seconds rather than milliseconds.
 pub fn parse<'a>(
     instance: Option<&'a str>,
     job: Option<&'a str>,
     input: &'a str,
-) -> Option<Vec<MetricFamily<'a>>> {
-    match PrometheusParser::parse(Rule::exposition, input) {
-        Ok(mut iter) => {
-            let out = parse_exposition(instance, job, iter.next().unwrap());
-            for extra_pair in iter {
-                warn!("unexpected token after exposition: {:?}", extra_pair);
+) -> Result<Vec<MetricFamily<'a>>, ParseError> {
+    parse_with(instance, job, input, None, false).map_err(|err| ParseError::from(*err))
+}
+
+/// Whether an exposition is in the OpenMetrics format, which always ends with
+/// the `# EOF` marker.
+fn is_openmetrics(input: &str) -> bool {
+    input.trim_end_matches(['\r', '\n']).ends_with("# EOF")
+}
+
+/// Parses an exposition, applying the OpenMetrics rules if `openmetrics` is
+/// true. If it's `None`, they are applied if the exposition ends with `# EOF`.
+/// If `lenient` is true, mistakes within a sample are tolerated where possible.
+///
+/// The exposition is parsed with the pest grammar, or with the hand-written
+/// scanner in `scan` if the `fast-parse` feature is enabled. Both accept the
+/// same input and produce the same families.
+fn parse_with<'a>(
+    instance: Option<&'a str>,
+    job: Option<&'a str>,
+    input: &'a str,
+    openmetrics: Option<bool>,
+    lenient: bool,
+) -> Result<Vec<MetricFamily<'a>>, Box<pest::error::Error<Rule>>> {
+    let openmetrics = openmetrics.unwrap_or_else(|| is_openmetrics(input));
+    if cfg!(feature = "fast-parse") {
+        scan::parse(instance, job, input, openmetrics, lenient)
+    } else {
+        parse_with_grammar(instance, job, input, openmetrics, lenient)
+    }
+}
+
+fn parse_with_grammar<'a>(
+    instance: Option<&'a str>,
+    job: Option<&'a str>,
+    input: &'a str,
+    openmetrics: bool,
+    lenient: bool,
+) -> Result<Vec<MetricFamily<'a>>, Box<pest::error::Error<Rule>>> {
+    let mut iter = PrometheusParser::parse(Rule::exposition, input)?;
+    let out = parse_exposition(instance, job, iter.next().unwrap(), openmetrics, lenient)?;
+    for extra_pair in iter {
+        warn!("unexpected token after exposition: {:?}", extra_pair);
+    }
+    Ok(out)
+}
+
+/// The name of the metric described by a `# HELP`, `# TYPE` or `# UNIT` line,
+/// or `None` if the line isn't a descriptor.
+fn descriptor_name(line: &str) -> Option<&str> {
+    ["# HELP ", "# TYPE ", "# UNIT "]
+        .iter()
+        .find_map(|prefix| line.strip_prefix(prefix))
+        .map(|rest| rest.split([' ', '\r', '\n']).next().unwrap_or(rest))
+}
+
+/// The name of the metric of a sample line.
+fn sample_name(line: &str) -> &str {
+    line.split(['{', ' ', '\r', '\n']).next().unwrap_or(line)
+}
+
+/// Whether a sample called `name` can belong to the family `var`, such as
+/// `foo_bucket` to the histogram `foo`.
+fn is_family_sample(name: &str, var: &str) -> bool {
+    name.strip_prefix(var)
+        .is_some_and(|suffix| suffix.is_empty() || suffix.starts_with('_'))
+}
+
+/// Splits an exposition into chunks that each hold a single metric family,
+/// along with the line number on which each chunk starts. A chunk ends just
+/// before the next descriptor that follows a sample, or before a sample of
+/// another metric: one that can't belong to the family named by the chunk's
+/// descriptors or, if it has none, one named differently from the sample
+/// before it. That way a malformed line only costs the family it is in.
+fn split_families(input: &str) -> Vec<(usize, &str)> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut start_line = 1;
+    let mut offset = 0;
+    // The metric named by the chunk's descriptors, and that of its last sample.
+    let mut family = None;
+    let mut last = None;
+    for (i, line) in input.split_inclusive('\n').enumerate() {
+        let split = if let Some(name) = descriptor_name(line) {
+            let split = last.take().is_some();
+            if split {
+                family = None;
+            }
+            family.get_or_insert(name);
+            split
+        } else if line.trim().is_empty() || line.starts_with('#') {
+            false
+        } else {
+            let name = sample_name(line);
+            let split = match (family, last) {
+                (Some(var), _) => !is_family_sample(name, var),
+                (None, Some(last)) => name != last,
+                (None, None) => false,
+            };
+            if split {
+                family = None;
+            }
+            last = Some(name);
+            split
+        };
+        if split && start < offset {
+            chunks.push((start_line, &input[start..offset]));
+            start = offset;
+            start_line = i + 1;
+        }
+        offset += line.len();
+    }
+    if start < input.len() {
+        chunks.push((start_line, &input[start..]));
+    }
+    chunks
+}
+
+/// Parses a Prometheus text exposition like `parse`, but on a best-effort
+/// basis: a metric family that can't be parsed is skipped with a warning,
+/// rather than failing the whole exposition. If a sample has the same label
+/// more than once, the last value is kept.
+pub fn parse_lenient<'a>(
+    instance: Option<&'a str>,
+    job: Option<&'a str>,
+    input: &'a str,
+) -> Vec<MetricFamily<'a>> {
+    if let Ok(families) = parse_with(instance, job, input, None, true) {
+        return families;
+    }
+    // Only the last family holds the "# EOF" marker, so check for it up front.
+    let openmetrics = is_openmetrics(input);
+    let mut out = Vec::new();
+    for (line, chunk) in split_families(input) {
+        match parse_with(instance, job, chunk, Some(openmetrics), true) {
+            Ok(families) => out.extend(families),
+            Err(err) => {
+                let err = ParseError::from(*err);
+                warn!(
+                    "skipping malformed metric family at line {}, column {}: expected {:?}",
+                    line + err.line - 1,
+                    err.column,
+                    err.expected
+                )
             }
-            Some(out)
         }
-        Err(err) => {
-            error!("parse error: {}", err);
-            None
+    }
+    out
+}
+
+/// Parses a Prometheus text exposition like `parse`, but one metric family at a
+/// time, so that each can be exported and dropped before the next is parsed.
+/// This keeps memory use down for very large expositions. Iteration stops after
+/// the first error, but the families before it have already been returned.
+pub fn parse_iter<'a>(
+    instance: Option<&'a str>,
+    job: Option<&'a str>,
+    input: &'a str,
+) -> impl Iterator<Item = Result<MetricFamily<'a>, ParseError>> + 'a {
+    // Only the last family holds the "# EOF" marker, so check for it up front.
+    let openmetrics = is_openmetrics(input);
+    let mut chunks = split_families(input).into_iter();
+    let mut pending = Vec::new().into_iter();
+    std::iter::from_fn(move || loop {
+        if let Some(family) = pending.next() {
+            return Some(Ok(family));
+        }
+        let (_, chunk) = chunks.next()?;
+        match parse_with(instance, job, chunk, Some(openmetrics), false) {
+            // A chunk without descriptors may hold more than one family.
+            Ok(families) => pending = families.into_iter(),
+            Err(err) => {
+                chunks = Vec::new().into_iter();
+                let offset = chunk.as_ptr() as usize - input.as_ptr() as usize;
+                return Some(Err(relocate(*err, input, offset)));
+            }
+        }
+    })
+}
+
+/// Decodes a protobuf exposition, a stream of length-delimited
+/// `io.prometheus.client.MetricFamily` messages, into metric families.
+///
+/// The `instance` and `job` labels, if given, are added to every sample. The
+/// samples of histograms and summaries are named and labeled as they would be
+/// in the text format, so the families can be exported like parsed ones. If
+/// `lenient` is true, a sample with the same label more than once keeps the
+/// last value, rather than its family being dropped.
+pub fn parse_protobuf(
+    instance: Option<&str>,
+    job: Option<&str>,
+    input: &[u8],
+    lenient: bool,
+) -> Result<Vec<OwnedMetricFamily>, prost::DecodeError> {
+    protobuf::decode(instance, job, input, lenient)
+}
+
+/// The body of a scrape, in one of the formats that can be parsed.
+#[derive(Clone, Debug)]
+pub enum Exposition {
+    /// The Prometheus text format, or OpenMetrics.
+    Text(String),
+    /// The Prometheus protobuf format.
+    Protobuf(Vec<u8>),
+}
+
+impl Exposition {
+    /// The size of the body, in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Exposition::Text(text) => text.len(),
+            Exposition::Protobuf(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Exposition::Text(text) => text.as_bytes(),
+            Exposition::Protobuf(bytes) => bytes,
         }
     }
 }
+
+/// Converts an error in the part of `input` that starts at byte `offset`, so
+/// that it reports its position within the whole of `input`.
+fn relocate(err: pest::error::Error<Rule>, input: &str, offset: usize) -> ParseError {
+    let pos = match err.location {
+        InputLocation::Pos(pos) => pos,
+        InputLocation::Span((start, _)) => start,
+    };
+    match Position::new(input, offset + pos) {
+        Some(position) => pest::error::Error::new_from_pos(err.variant, position).into(),
+        None => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openmetrics_timestamps_are_seconds() {
+        let families = parse(None, None, "foo 1 1520879607.789\n# EOF").unwrap();
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].samples[0].timestamp, Some(1520879607789));
+
+        let families = parse(None, None, "foo 1 1520879607\n# EOF\n").unwrap();
+        assert_eq!(families[0].samples[0].timestamp, Some(1520879607000));
+    }
+
+    #[test]
+    fn prometheus_timestamps_are_millis() {
+        let families = parse(None, None, "foo 1 1520879607789\n").unwrap();
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].samples[0].timestamp, Some(1520879607789));
+    }
+
+    #[test]
+    fn bucket_with_exemplar() {
+        let input = concat!(
+            "# TYPE foo histogram\n",
+            "foo_bucket{le=\"0.1\"} 8 # {trace_id=\"abc\"} 0.07 1623.5\n",
+            "foo_bucket{le=\"+Inf\"} 10\n",
+            "foo_sum 1.5\n",
+            "foo_count 10\n",
+        );
+        let families = parse(None, None, input).unwrap();
+        assert_eq!(families.len(), 1);
+        let exemplar = families[0].samples[0].exemplar.as_ref().unwrap();
+        assert_eq!(exemplar.labels, vec![("trace_id", Cow::Borrowed("abc"))]);
+        assert_eq!(exemplar.value, 0.07);
+        assert_eq!(exemplar.timestamp, Some(1623500));
+        assert!(families[0].samples[1].exemplar.is_none());
+
+        let histogram = families[0].as_histogram().unwrap();
+        assert_eq!(
+            histogram[0].buckets,
+            vec![(0.1, 8.0), (f64::INFINITY, 10.0)]
+        );
+    }
+
+    #[test]
+    fn duplicate_label_is_an_error() {
+        let err = parse(None, None, "b 1\na{x=\"1\",x=\"2\"} 1\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 1));
+        assert!(err.expected.is_empty());
+        assert!(err.message.contains("duplicate label x for a"), "{}", err);
+
+        let families = parse_lenient(None, None, "a{x=\"1\",x=\"2\"} 1\n");
+        assert_eq!(
+            families[0].samples[0].labels,
+            vec![("x", Cow::Borrowed("2"))]
+        );
+    }
+
+    #[test]
+    fn descriptor_mismatch_is_an_error() {
+        let input = "# HELP a Help.\n# TYPE b counter\nb 1\n";
+        let err = parse(None, None, input).unwrap_err();
+        assert_eq!((err.line, err.column), (2, 1));
+        assert!(err.message.contains("metric name mismatch"), "{}", err);
+        assert!(parse_lenient(None, None, input).is_empty());
+    }
+
+    #[test]
+    fn invalid_counter_names_are_an_error() {
+        let input = "# TYPE a counter\nb_total 1\n# EOF\n";
+        let err = parse(None, None, input).unwrap_err();
+        assert_eq!((err.line, err.column), (1, 1));
+        assert!(err.message.contains("invalid sample names"), "{}", err);
+    }
+
+    #[test]
+    fn lenient_skips_malformed_sample() {
+        let families = parse_lenient(None, None, "a 1\nb x\nc 3\n");
+        let names: Vec<_> = families.iter().map(|f| f.samples[0].var).collect();
+        assert_eq!(names, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn lenient_keeps_families_around_malformed_one() {
+        let input = concat!(
+            "# TYPE h histogram\n",
+            "h_bucket{le=\"+Inf\"} 2\n",
+            "h_sum 1.5\n",
+            "h_count 2\n",
+            "# TYPE g gauge\n",
+            "g 1 x\n",
+            "other{ 2\n",
+            "# HELP c Help.\n",
+            "# TYPE c counter\n",
+            "c 3\n",
+        );
+        let families = parse_lenient(None, None, input);
+        let names: Vec<_> = families.iter().map(|f| f.var).collect();
+        assert_eq!(names, vec![Some("h"), Some("c")]);
+        assert_eq!(families[0].samples.len(), 3);
+        assert_eq!(families[1].help.as_deref(), Some("Help."));
+    }
+
+    #[test]
+    fn split_families_at_name_changes() {
+        let input = "# TYPE a gauge\na 1\nb 2\n# comment\nb 3\nc_total 4\n";
+        let chunks = split_families(input);
+        assert_eq!(
+            chunks,
+            vec![
+                (1, "# TYPE a gauge\na 1\n"),
+                (3, "b 2\n# comment\nb 3\n"),
+                (6, "c_total 4\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_special_values() {
+        assert!(parse_value("NaN").unwrap().is_nan());
+        assert_eq!(parse_value("+Inf"), Some(f64::INFINITY));
+        assert_eq!(parse_value("-Inf"), Some(f64::NEG_INFINITY));
+        assert_eq!(parse_value("1.5e+07"), Some(1.5e7));
+        assert_eq!(parse_value("2E-3"), Some(0.002));
+        assert_eq!(parse_value("+42"), Some(42.0));
+        assert_eq!(parse_value("-0.5"), Some(-0.5));
+        assert_eq!(parse_value("twelve"), None);
+        assert_eq!(parse_value(""), None);
+    }
+
+    #[test]
+    fn unescape_sequences() {
+        assert!(matches!(unescape("plain"), Cow::Borrowed("plain")));
+        assert_eq!(unescape(r"a\\b"), r"a\b");
+        assert_eq!(unescape(r#"a\"b"#), r#"a"b"#);
+        assert_eq!(unescape(r"a\nb"), "a\nb");
+        assert_eq!(unescape(r#"a\"b\\c"#), r#"a"b\c"#);
+        // Unknown escapes and a trailing backslash are kept as they are.
+        assert_eq!(unescape(r"a\tb"), r"a\tb");
+        assert_eq!(unescape("a\\"), "a\\");
+    }
+
+    #[test]
+    fn label_values_are_unescaped() {
+        let input = "a{path=\"a\\\"b\\\\c\",text=\"x\\ny\",le=\"1\"} 1\n";
+        let families = parse(None, None, input).unwrap();
+        let labels = &families[0].samples[0].labels;
+        assert_eq!(labels[0], ("path", Cow::Borrowed("a\"b\\c")));
+        assert_eq!(labels[1], ("text", Cow::Borrowed("x\ny")));
+
+        let (rest, le) = split_label(labels, "le");
+        assert_eq!(le, Some("1"));
+        assert_eq!(rest, labels[..2]);
+    }
+
+    #[test]
+    fn empty_label_braces() {
+        let families = parse(Some("localhost:9100"), Some("node"), "metric{} 1\n").unwrap();
+        let sample = &families[0].samples[0];
+        assert_eq!(sample.var, "metric");
+        assert_eq!(
+            sample.labels,
+            vec![
+                ("instance", Cow::Borrowed("localhost:9100")),
+                ("job", Cow::Borrowed("node")),
+            ]
+        );
+        assert_eq!(sample.value, "1");
+
+        let families = parse(None, None, "metric{} 1\n").unwrap();
+        assert!(families[0].samples[0].labels.is_empty());
+    }
+
+    #[test]
+    fn multi_line_help() {
+        let input = concat!(
+            "# HELP a First line.\\nSecond line, with a \\\\ backslash.\n",
+            "# TYPE a gauge\n",
+            "a 1\n",
+        );
+        let families = parse(None, None, input).unwrap();
+        assert_eq!(
+            families[0].help.as_deref(),
+            Some("First line.\nSecond line, with a \\ backslash.")
+        );
+    }
+
+    #[test]
+    fn comments_between_families() {
+        let input = concat!(
+            "# A note before anything else.\n",
+            "# TYPE a gauge\n",
+            "a 1\n",
+            "# some note\n",
+            "#\n",
+            "# HELPER isn't a descriptor.\n",
+            "# TYPE b counter\n",
+            "b 2\n",
+        );
+        let families = parse(None, None, input).unwrap();
+        let names: Vec<_> = families.iter().map(|f| f.var).collect();
+        assert_eq!(names, vec![Some("a"), Some("b")]);
+        assert_eq!(families[1].r#type, SampleType::Counter);
+    }
+
+    #[test]
+    fn unlabeled_samples_get_target_labels() {
+        let input = concat!(
+            "# TYPE h histogram\n",
+            "h_bucket{le=\"1\"} 1\n",
+            "h_bucket{le=\"+Inf\"} 2\n",
+            "h_sum 3\n",
+            "h_count 2\n",
+        );
+        let families = parse(Some("localhost:9100"), Some("node"), input).unwrap();
+        assert_eq!(
+            families[0].samples[2].labels,
+            vec![
+                ("instance", Cow::Borrowed("localhost:9100")),
+                ("job", Cow::Borrowed("node")),
+            ]
+        );
+        let series = families[0].as_histogram().unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].buckets.len(), 2);
+        assert_eq!(series[0].sum, Some(3.0));
+        assert_eq!(series[0].count, Some(2.0));
+    }
+}
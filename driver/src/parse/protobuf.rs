@@ -0,0 +1,328 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Decoding the protobuf exposition format, a stream of length-delimited
+//! `io.prometheus.client.MetricFamily` messages, into the same families that
+//! are parsed from the text format.
+
+use prost::Message;
+
+use super::{OwnedExemplar, OwnedLabelSet, OwnedMetricFamily, OwnedSample, SampleType};
+
+/// The messages of `io/prometheus/client/metrics.proto`. Only the fields of
+/// classic metrics are declared, so native histograms are read as having only
+/// their sum and count.
+#[derive(Clone, PartialEq, Message)]
+struct MetricFamily {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    help: String,
+    #[prost(int32, tag = "3")]
+    r#type: i32,
+    #[prost(message, repeated, tag = "4")]
+    metric: Vec<Metric>,
+    #[prost(string, tag = "5")]
+    unit: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Metric {
+    #[prost(message, repeated, tag = "1")]
+    label: Vec<LabelPair>,
+    #[prost(message, optional, tag = "2")]
+    gauge: Option<Gauge>,
+    #[prost(message, optional, tag = "3")]
+    counter: Option<Counter>,
+    #[prost(message, optional, tag = "4")]
+    summary: Option<Summary>,
+    #[prost(message, optional, tag = "5")]
+    untyped: Option<Untyped>,
+    #[prost(message, optional, tag = "7")]
+    histogram: Option<Histogram>,
+    /// Milliseconds since the epoch, or zero if not given.
+    #[prost(int64, tag = "6")]
+    timestamp_ms: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct LabelPair {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Gauge {
+    #[prost(double, tag = "1")]
+    value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Counter {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(message, optional, tag = "2")]
+    exemplar: Option<Exemplar>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Quantile {
+    #[prost(double, tag = "1")]
+    quantile: f64,
+    #[prost(double, tag = "2")]
+    value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Summary {
+    #[prost(uint64, tag = "1")]
+    sample_count: u64,
+    #[prost(double, tag = "2")]
+    sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    quantile: Vec<Quantile>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Untyped {
+    #[prost(double, tag = "1")]
+    value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Histogram {
+    #[prost(uint64, tag = "1")]
+    sample_count: u64,
+    /// Used instead of `sample_count` by histograms with float counts.
+    #[prost(double, tag = "4")]
+    sample_count_float: f64,
+    #[prost(double, tag = "2")]
+    sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    bucket: Vec<Bucket>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Bucket {
+    #[prost(uint64, tag = "1")]
+    cumulative_count: u64,
+    /// Used instead of `cumulative_count` by histograms with float counts.
+    #[prost(double, tag = "4")]
+    cumulative_count_float: f64,
+    #[prost(double, tag = "2")]
+    upper_bound: f64,
+    #[prost(message, optional, tag = "3")]
+    exemplar: Option<Exemplar>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Exemplar {
+    #[prost(message, repeated, tag = "1")]
+    label: Vec<LabelPair>,
+    #[prost(double, tag = "2")]
+    value: f64,
+    #[prost(message, optional, tag = "3")]
+    timestamp: Option<Timestamp>,
+}
+
+/// `google.protobuf.Timestamp`.
+#[derive(Clone, PartialEq, Message)]
+struct Timestamp {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+/// Decodes a protobuf exposition. The `instance` and `job` labels, if given,
+/// are added to every sample, as they are when parsing text. A family with a
+/// label given twice in one sample is dropped, unless `lenient` is true, in
+/// which case the last value is kept.
+pub(super) fn decode(
+    instance: Option<&str>,
+    job: Option<&str>,
+    mut input: &[u8],
+    lenient: bool,
+) -> Result<Vec<OwnedMetricFamily>, prost::DecodeError> {
+    let mut target_labels = OwnedLabelSet::new();
+    if let Some(instance) = instance {
+        target_labels.push(("instance".to_string(), instance.to_string()));
+    }
+    if let Some(job) = job {
+        target_labels.push(("job".to_string(), job.to_string()));
+    }
+    let mut families = Vec::new();
+    while !input.is_empty() {
+        let family = MetricFamily::decode_length_delimited(&mut input)?;
+        families.extend(convert_family(&target_labels, family, lenient));
+    }
+    Ok(families)
+}
+
+fn convert_family(
+    target_labels: &OwnedLabelSet,
+    family: MetricFamily,
+    lenient: bool,
+) -> Option<OwnedMetricFamily> {
+    let r#type = match family.r#type {
+        0 => SampleType::Counter,
+        1 => SampleType::Gauge,
+        2 => SampleType::Summary,
+        3 => SampleType::Untyped,
+        // Gauge histograms are written like histograms.
+        4 | 5 => SampleType::Histogram,
+        other => {
+            error!("unknown type {} for metric {}", other, family.name);
+            return None;
+        }
+    };
+    let mut samples = Vec::new();
+    for metric in family.metric {
+        let mut labels = target_labels.clone();
+        if !push_labels(&mut labels, &family.name, metric.label, lenient) {
+            return None;
+        }
+        let timestamp = (metric.timestamp_ms != 0).then_some(metric.timestamp_ms);
+        let mut push = |suffix: &str, labels: OwnedLabelSet, value: f64, exemplar| {
+            samples.push(OwnedSample {
+                var: format!("{}{}", family.name, suffix),
+                labels,
+                value: format_value(value),
+                timestamp,
+                exemplar,
+            })
+        };
+        match r#type {
+            SampleType::Counter => {
+                let counter = metric.counter.unwrap_or_default();
+                let exemplar = convert_exemplar(&family.name, counter.exemplar, lenient);
+                push("", labels, counter.value, exemplar);
+            }
+            SampleType::Gauge => push("", labels, metric.gauge.unwrap_or_default().value, None),
+            SampleType::Untyped => push("", labels, metric.untyped.unwrap_or_default().value, None),
+            SampleType::Summary => {
+                let summary = metric.summary.unwrap_or_default();
+                for quantile in summary.quantile {
+                    let mut labels = labels.clone();
+                    labels.push(("quantile".to_string(), format_value(quantile.quantile)));
+                    push("", labels, quantile.value, None);
+                }
+                push("_sum", labels.clone(), summary.sample_sum, None);
+                push("_count", labels, summary.sample_count as f64, None);
+            }
+            SampleType::Histogram => {
+                let histogram = metric.histogram.unwrap_or_default();
+                let count = if histogram.sample_count_float > 0.0 {
+                    histogram.sample_count_float
+                } else {
+                    histogram.sample_count as f64
+                };
+                let mut has_inf = false;
+                for bucket in histogram.bucket {
+                    has_inf |= bucket.upper_bound == f64::INFINITY;
+                    let mut labels = labels.clone();
+                    labels.push(("le".to_string(), format_value(bucket.upper_bound)));
+                    let cumulative_count = if bucket.cumulative_count_float > 0.0 {
+                        bucket.cumulative_count_float
+                    } else {
+                        bucket.cumulative_count as f64
+                    };
+                    let exemplar = convert_exemplar(&family.name, bucket.exemplar, lenient);
+                    push("_bucket", labels, cumulative_count, exemplar);
+                }
+                // The +Inf bucket is implied by the count, and usually left out.
+                if !has_inf {
+                    let mut labels = labels.clone();
+                    labels.push(("le".to_string(), "+Inf".to_string()));
+                    push("_bucket", labels, count, None);
+                }
+                push("_sum", labels.clone(), histogram.sample_sum, None);
+                push("_count", labels, count, None);
+            }
+        }
+    }
+    let non_empty = |s: String| (!s.is_empty()).then_some(s);
+    Some(OwnedMetricFamily {
+        var: Some(family.name),
+        help: non_empty(family.help),
+        r#type,
+        unit: non_empty(family.unit),
+        samples,
+    })
+}
+
+/// Adds the labels of a metric after the target labels. Returns false if a
+/// label was given twice, unless `lenient` is true, in which case the last
+/// value is kept.
+fn push_labels(
+    labels: &mut OwnedLabelSet,
+    metric_name: &str,
+    pairs: Vec<LabelPair>,
+    lenient: bool,
+) -> bool {
+    let start = labels.len();
+    for LabelPair { name, value } in pairs {
+        match labels[start..].iter_mut().find(|(n, _)| *n == name) {
+            Some(existing) if lenient => {
+                warn!(
+                    "duplicate label {} for {}, keeping {:?} over {:?}",
+                    name, metric_name, value, existing.1
+                );
+                existing.1 = value;
+            }
+            Some(_) => {
+                error!("duplicate label {} for {}", name, metric_name);
+                return false;
+            }
+            None => labels.push((name, value)),
+        }
+    }
+    true
+}
+
+fn convert_exemplar(
+    metric_name: &str,
+    exemplar: Option<Exemplar>,
+    lenient: bool,
+) -> Option<OwnedExemplar> {
+    let exemplar = exemplar?;
+    let mut labels = OwnedLabelSet::new();
+    if !push_labels(&mut labels, metric_name, exemplar.label, lenient) {
+        return None;
+    }
+    Some(OwnedExemplar {
+        labels,
+        value: exemplar.value,
+        timestamp: exemplar
+            .timestamp
+            .map(|t| t.seconds * 1000 + i64::from(t.nanos) / 1_000_000),
+    })
+}
+
+/// Formats a value as it would appear in the text format, so that it can be
+/// read back with `parse_value`.
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,543 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A hand-written parser for the exposition format, used in place of the pest
+//! grammar when the `fast-parse` feature is enabled. It scans the exposition a
+//! line at a time without backtracking, but accepts exactly what
+//! `prometheus.pest` accepts and produces the same metric families. Errors are
+//! reported as pest errors, so callers can't tell the two apart.
+
+use pest::error::{Error, ErrorVariant};
+use pest::Position;
+
+use super::{
+    parse_exemplar_value, parse_timestamp, push_label, reject, target_labels, unescape, Exemplar,
+    Invalid, LabelSet, MetricFamily, Rule, Sample,
+};
+
+type ScanResult<T> = Result<T, Box<Error<Rule>>>;
+
+/// The most descriptors the grammar allows at the start of a family. A fourth
+/// starts a new family.
+const MAX_DESCRIPTORS: usize = 3;
+
+/// A sample line, split into its parts but not yet interpreted.
+struct RawSample<'a> {
+    name: &'a str,
+    /// Label names and their escaped values.
+    labels: Vec<(&'a str, &'a str)>,
+    value: &'a str,
+    timestamp: Option<&'a str>,
+    exemplar: Option<RawExemplar<'a>>,
+}
+
+struct RawExemplar<'a> {
+    labels: Vec<(&'a str, &'a str)>,
+    value: &'a str,
+    timestamp: Option<&'a str>,
+}
+
+/// A position within one line of the exposition.
+struct Cursor<'a> {
+    input: &'a str,
+    line: &'a str,
+    /// The offset of the line within `input`.
+    start: usize,
+    /// The offset within the line.
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(input: &'a str, line: &'a str, start: usize) -> Self {
+        Cursor {
+            input,
+            line,
+            start,
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.line.as_bytes().get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.line.as_bytes().get(self.pos + offset).copied()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.line.len()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8, rule: Rule) -> ScanResult<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(rule))
+        }
+    }
+
+    /// Consumes bytes while `f` holds, returning them.
+    fn take_while(&mut self, f: impl Fn(u8) -> bool) -> &'a str {
+        let start = self.pos;
+        let bytes = self.line.as_bytes();
+        while self.pos < bytes.len() && f(bytes[self.pos]) {
+            self.pos += 1;
+        }
+        &self.line[start..self.pos]
+    }
+
+    /// An error at the current position, saying that `rule` was expected.
+    fn error(&self, rule: Rule) -> Box<Error<Rule>> {
+        let position = Position::new(self.input, self.start + self.pos).unwrap();
+        Box::new(Error::new_from_pos(
+            ErrorVariant::ParsingError {
+                positives: vec![rule],
+                negatives: vec![],
+            },
+            position,
+        ))
+    }
+
+    /// A metric name: a letter followed by letters, digits and underscores.
+    fn metric_name(&mut self) -> ScanResult<&'a str> {
+        if !self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            return Err(self.error(Rule::metricname));
+        }
+        Ok(self.take_while(|c| c.is_ascii_alphanumeric() || c == b'_'))
+    }
+
+    /// A label name: a letter or underscore followed by letters, digits and underscores.
+    fn label_name(&mut self) -> ScanResult<&'a str> {
+        if !self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == b'_')
+        {
+            return Err(self.error(Rule::labelname));
+        }
+        Ok(self.take_while(|c| c.is_ascii_alphanumeric() || c == b'_'))
+    }
+
+    /// A string that may contain the escapes `\\`, `\n` and `\"`, up to the next
+    /// unescaped double quote or the end of the line. It is returned still escaped.
+    fn escaped_string(&mut self) -> ScanResult<&'a str> {
+        let start = self.pos;
+        let bytes = self.line.as_bytes();
+        while self.pos < bytes.len() {
+            match bytes[self.pos] {
+                b'"' => break,
+                b'\r' => return Err(self.error(Rule::escapedstring)),
+                b'\\' => match bytes.get(self.pos + 1) {
+                    Some(b'\\' | b'n' | b'"') => self.pos += 2,
+                    _ => return Err(self.error(Rule::escapedstring)),
+                },
+                _ => self.pos += 1,
+            }
+        }
+        Ok(&self.line[start..self.pos])
+    }
+
+    /// A set of labels in braces, which may be empty and may end with a comma.
+    fn labels(&mut self) -> ScanResult<Vec<(&'a str, &'a str)>> {
+        let mut labels = Vec::new();
+        self.expect(b'{', Rule::labels)?;
+        if self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == b'_')
+        {
+            loop {
+                let name = self.label_name()?;
+                self.expect(b'=', Rule::label)?;
+                self.expect(b'"', Rule::label)?;
+                let value = self.escaped_string()?;
+                self.expect(b'"', Rule::label)?;
+                labels.push((name, value));
+                let next_is_label = self
+                    .peek_at(1)
+                    .is_some_and(|c| c.is_ascii_alphabetic() || c == b'_');
+                if self.peek() == Some(b',') && next_is_label {
+                    self.pos += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+        self.eat(b',');
+        self.expect(b'}', Rule::labels)?;
+        Ok(labels)
+    }
+
+    /// A decimal number, with an optional sign, fraction and exponent.
+    fn real_number(&mut self) -> Option<&'a str> {
+        let bytes = self.line.as_bytes();
+        let start = self.pos;
+        let is_sign = |i: usize| matches!(bytes.get(i), Some(b'+' | b'-'));
+        let digits_from = |mut i: usize| {
+            while bytes.get(i).is_some_and(|c| c.is_ascii_digit()) {
+                i += 1;
+            }
+            i
+        };
+        let mut i = start + is_sign(start) as usize;
+        let end = digits_from(i);
+        if end > i {
+            i = end;
+            if bytes.get(i) == Some(&b'.') {
+                i = digits_from(i + 1);
+            }
+            if matches!(bytes.get(i), Some(b'e' | b'E')) {
+                let exponent = i + 1 + is_sign(i + 1) as usize;
+                let end = digits_from(exponent);
+                // An exponent without digits isn't part of the number.
+                if end > exponent {
+                    i = end;
+                }
+            }
+            self.pos = i;
+            return Some(&self.line[start..i]);
+        }
+        None
+    }
+
+    /// A sample value: a decimal number, `NaN`, or an infinity with a sign.
+    /// Matching is case insensitive for the special values.
+    fn number(&mut self) -> ScanResult<&'a str> {
+        if let Some(number) = self.real_number() {
+            return Ok(number);
+        }
+        let bytes = self.line.as_bytes();
+        let start = self.pos;
+        let is_sign = |i: usize| matches!(bytes.get(i), Some(b'+' | b'-'));
+        let matches_at = |i: usize, word: &[u8]| {
+            bytes
+                .get(i..i + word.len())
+                .is_some_and(|s| s.eq_ignore_ascii_case(word))
+        };
+        if is_sign(start) && matches_at(start + 1, b"inf") {
+            self.pos = start + 4;
+        } else if matches_at(start, b"nan") {
+            self.pos = start + 3;
+        } else {
+            return Err(self.error(Rule::number));
+        }
+        Ok(&self.line[start..self.pos])
+    }
+
+    /// A timestamp, which is a decimal number. It is interpreted later, as
+    /// its unit depends on the format of the exposition.
+    fn timestamp(&mut self) -> ScanResult<&'a str> {
+        self.real_number()
+            .ok_or_else(|| self.error(Rule::timestamp))
+    }
+
+    /// A space followed by a timestamp, if there is one.
+    fn optional_timestamp(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        if self.eat(b' ') {
+            if let Ok(timestamp) = self.timestamp() {
+                return Some(timestamp);
+            }
+        }
+        self.pos = start;
+        None
+    }
+
+    /// An exemplar, e.g. ` # {trace_id="abc"} 0.07 1623.5`, if there is one.
+    fn optional_exemplar(&mut self) -> Option<RawExemplar<'a>> {
+        let start = self.pos;
+        let exemplar = self.exemplar();
+        if exemplar.is_none() {
+            self.pos = start;
+        }
+        exemplar
+    }
+
+    fn exemplar(&mut self) -> Option<RawExemplar<'a>> {
+        if !(self.eat(b' ') && self.eat(b'#') && self.eat(b' ')) {
+            return None;
+        }
+        let labels = self.labels().ok()?;
+        if !self.eat(b' ') {
+            return None;
+        }
+        let value = self.number().ok()?;
+        let timestamp = self.optional_timestamp();
+        Some(RawExemplar {
+            labels,
+            value,
+            timestamp,
+        })
+    }
+
+    /// A sample line, such as `http_requests_total{code="200"} 1027 1395066363000`.
+    fn sample(&mut self) -> ScanResult<RawSample<'a>> {
+        let name = self.metric_name()?;
+        let labels = if self.peek() == Some(b'{') {
+            self.labels()?
+        } else {
+            Vec::new()
+        };
+        if self.take_while(|c| c == b' ').is_empty() {
+            return Err(self.error(Rule::metric));
+        }
+        let value = self.number()?;
+        let timestamp = self.optional_timestamp();
+        let exemplar = self.optional_exemplar();
+        if !self.at_end() {
+            return Err(self.error(Rule::metric));
+        }
+        Ok(RawSample {
+            name,
+            labels,
+            value,
+            timestamp,
+            exemplar,
+        })
+    }
+
+    /// The rest of a `# HELP`, `# TYPE` or `# UNIT` line, after the keyword and
+    /// the space that follows it. Returns the metric name and the value.
+    fn descriptor(&mut self, keyword: &str) -> ScanResult<(&'a str, &'a str)> {
+        let name = self.metric_name()?;
+        self.expect(b' ', Rule::metricdescriptor)?;
+        let value = match keyword {
+            "HELP" => {
+                let help = self.escaped_string()?;
+                if !self.at_end() {
+                    // An unescaped double quote.
+                    return Err(self.error(Rule::escapedstring));
+                }
+                help
+            }
+            "TYPE" => {
+                let value = &self.line[self.pos..];
+                if !matches!(
+                    value,
+                    "counter" | "gauge" | "histogram" | "summary" | "untyped"
+                ) {
+                    return Err(self.error(Rule::metrictype));
+                }
+                self.pos = self.line.len();
+                value
+            }
+            _ => {
+                let unit = self.take_while(|c| c.is_ascii_alphanumeric() || c == b'_');
+                if unit.is_empty() || !self.at_end() {
+                    return Err(self.error(Rule::unit));
+                }
+                unit
+            }
+        };
+        Ok((name, value))
+    }
+}
+
+/// A metric family being built from consecutive lines.
+struct Pending<'a> {
+    family: MetricFamily<'a>,
+    /// The offset of the family's first line within the exposition.
+    start: usize,
+    descriptors: usize,
+    /// The number of sample lines, including any that weren't added to the family.
+    samples: usize,
+    /// Set once a line of the family couldn't be interpreted, after which the
+    /// rest are only checked for syntax and the family is rejected.
+    invalid: Option<Invalid<'a>>,
+}
+
+/// Interprets a sample, as `MetricFamily::parse_sample` does.
+fn build_sample<'a>(
+    instance: Option<&'a str>,
+    job: Option<&'a str>,
+    raw: RawSample<'a>,
+    openmetrics: bool,
+    lenient: bool,
+) -> Result<Sample<'a>, String> {
+    let mut labels = target_labels(instance, job);
+    let start = labels.len();
+    for (name, value) in raw.labels {
+        push_label(&mut labels, start, raw.name, name, unescape(value), lenient)?;
+    }
+    let timestamp = match raw.timestamp {
+        Some(timestamp) => Some(parse_timestamp(raw.name, timestamp, openmetrics)?),
+        None => None,
+    };
+    let exemplar = match raw.exemplar {
+        Some(exemplar) => {
+            let mut labels = LabelSet::new();
+            for (name, value) in exemplar.labels {
+                push_label(&mut labels, 0, raw.name, name, unescape(value), lenient)?;
+            }
+            let value = parse_exemplar_value(raw.name, exemplar.value)?;
+            let timestamp = match exemplar.timestamp {
+                Some(timestamp) => Some(parse_timestamp(raw.name, timestamp, true)?),
+                None => None,
+            };
+            Some(Exemplar {
+                labels,
+                value,
+                timestamp,
+            })
+        }
+        None => None,
+    };
+    Ok(Sample {
+        var: raw.name,
+        labels,
+        value: raw.value,
+        timestamp,
+        exemplar,
+    })
+}
+
+/// Parses an exposition like `super::parse_with`, without the pest grammar.
+pub(super) fn parse<'a>(
+    instance: Option<&'a str>,
+    job: Option<&'a str>,
+    input: &'a str,
+    openmetrics: bool,
+    lenient: bool,
+) -> ScanResult<Vec<MetricFamily<'a>>> {
+    let mut families = Vec::new();
+    // Families that can't be interpreted are only rejected once the whole
+    // exposition is known to be well formed, as they are by the grammar.
+    let mut rejected = Vec::new();
+    let mut pending: Option<Pending<'a>> = None;
+    let mut any_family = false;
+    let mut eof = false;
+    let position = |offset: usize| Position::new(input, offset).unwrap();
+    let finish = |pending: Option<Pending<'a>>,
+                  families: &mut Vec<MetricFamily<'a>>,
+                  rejected: &mut Vec<Invalid<'a>>| {
+        let Some(mut pending) = pending else {
+            return;
+        };
+        if openmetrics && pending.invalid.is_none() {
+            if let Err(message) = pending.family.apply_openmetrics_rules() {
+                pending.invalid = Some((position(pending.start), message));
+            }
+        }
+        match pending.invalid {
+            None => families.push(pending.family),
+            Some(invalid) => rejected.push(invalid),
+        }
+    };
+    let mut offset = 0;
+    for line in input.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let terminated = line.ends_with('\n');
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let mut cursor = Cursor::new(input, line, start);
+        if eof {
+            return Err(cursor.error(Rule::EOI));
+        }
+        if line.is_empty() {
+            // A blank line ends the family.
+            finish(pending.take(), &mut families, &mut rejected);
+            continue;
+        }
+        if !line.starts_with('#') {
+            let raw = cursor.sample()?;
+            if !terminated {
+                cursor.pos = line.len();
+                return Err(cursor.error(Rule::metric));
+            }
+            any_family = true;
+            let pending = pending.get_or_insert_with(|| Pending {
+                family: MetricFamily::default(),
+                start,
+                descriptors: 0,
+                samples: 0,
+                invalid: None,
+            });
+            pending.samples += 1;
+            if pending.invalid.is_none() {
+                match build_sample(instance, job, raw, openmetrics, lenient) {
+                    Ok(sample) => pending.family.samples.push(sample),
+                    Err(message) => pending.invalid = Some((position(start), message)),
+                }
+            }
+            continue;
+        }
+        let keyword = ["HELP", "TYPE", "UNIT"].into_iter().find(|keyword| {
+            line[1..]
+                .strip_prefix(' ')
+                .and_then(|l| l.strip_prefix(keyword))
+                .is_some_and(|l| l.starts_with(' '))
+        });
+        if let Some(keyword) = keyword {
+            cursor.pos = 2 + keyword.len();
+            cursor.expect(b' ', Rule::metricdescriptor)?;
+            let (name, value) = cursor.descriptor(keyword)?;
+            if !terminated {
+                return Err(cursor.error(Rule::metricdescriptor));
+            }
+            any_family = true;
+            if pending.as_ref().is_some_and(|pending| {
+                pending.samples > 0 || pending.descriptors == MAX_DESCRIPTORS
+            }) {
+                finish(pending.take(), &mut families, &mut rejected);
+            }
+            let pending = pending.get_or_insert_with(|| Pending {
+                family: MetricFamily::default(),
+                start,
+                descriptors: 0,
+                samples: 0,
+                invalid: None,
+            });
+            pending.descriptors += 1;
+            if pending.invalid.is_none() {
+                if let Err(message) = pending.family.apply_descriptor(keyword, name, value) {
+                    pending.invalid = Some((position(start), message));
+                }
+            }
+            continue;
+        }
+        if line == "# EOF" {
+            if !any_family {
+                return Err(cursor.error(Rule::metricfamily));
+            }
+            eof = true;
+            continue;
+        }
+        // Anything else starting with "#" is a comment.
+    }
+    if !any_family {
+        let position = Position::new(input, input.len()).unwrap();
+        return Err(Box::new(Error::new_from_pos(
+            ErrorVariant::ParsingError {
+                positives: vec![Rule::metricfamily],
+                negatives: vec![],
+            },
+            position,
+        )));
+    }
+    finish(pending, &mut families, &mut rejected);
+    for invalid in rejected {
+        reject(invalid, lenient)?;
+    }
+    Ok(families)
+}
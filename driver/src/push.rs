@@ -0,0 +1,116 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use bytes::Bytes;
+use hyper::header::HeaderMap;
+use hyper::Uri;
+use tokio::runtime;
+
+use crate::fetch::Client;
+
+/// How many request bodies may wait to be sent before new ones are dropped.
+const MAX_PENDING: usize = 16;
+
+/// Sends request bodies to a remote endpoint, for exporters that write over HTTP.
+/// Requests are made from a separate thread so that a slow server doesn't hold
+/// up scraping. If bodies pile up faster than they can be sent, new ones are
+/// dropped.
+pub struct Pusher {
+    /// The name of the destination, for log messages.
+    name: &'static str,
+    tx: Option<SyncSender<Bytes>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Pusher {
+    /// Starts a thread that POSTs each body to `url` with the given headers.
+    /// Failed requests are counted as failures of `exporter`, which is the
+    /// name of the exporter using the pusher.
+    pub fn new(
+        name: &'static str,
+        exporter: &'static str,
+        client: Client,
+        url: Uri,
+        headers: HeaderMap,
+    ) -> Self {
+        let (tx, rx) = mpsc::sync_channel(MAX_PENDING);
+        let writer = thread::spawn(move || write_loop(name, exporter, client, url, headers, rx));
+        Pusher {
+            name,
+            tx: Some(tx),
+            writer: Some(writer),
+        }
+    }
+
+    /// Queues `body` to be sent. Returns false if it had to be dropped.
+    pub fn push(&self, body: Bytes) -> bool {
+        let Some(tx) = &self.tx else {
+            return false;
+        };
+        match tx.try_send(body) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("{} writes are falling behind, dropping a batch", self.name);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("{} writer has stopped", self.name);
+                false
+            }
+        }
+    }
+
+    /// Waits for the writer to finish sending whatever is still pending.
+    pub fn close(&mut self) {
+        self.tx = None;
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                error!("{} writer panicked", self.name);
+            }
+        }
+    }
+}
+
+/// Sends each body received from `rx`, until the pusher is closed.
+fn write_loop(
+    name: &str,
+    exporter: &str,
+    client: Client,
+    url: Uri,
+    headers: HeaderMap,
+    rx: Receiver<Bytes>,
+) {
+    let rt = match runtime::Builder::new_current_thread()
+        .enable_time()
+        .enable_io()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(err) => {
+            error!("unable to start {} writer: {}", name, err);
+            return;
+        }
+    };
+    for body in rx {
+        if let Err(err) = rt.block_on(client.post(url.clone(), &headers, body)) {
+            error!("unable to write to {}: {}", name, err);
+            crate::export_failed(exporter);
+        }
+    }
+    debug!("{} writer stopped", name);
+}
@@ -0,0 +1,214 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::parse::LabelSet;
+
+/// What a relabeling rule does to the samples it is applied to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Drop the sample if the value of `source_label` matches `regex`.
+    Drop,
+    /// Drop the sample unless the value of `source_label` matches `regex`.
+    Keep,
+    /// Remove the labels whose names match `regex`.
+    LabelDrop,
+    /// Remove the labels whose names don't match `regex`.
+    LabelKeep,
+    /// Rename `source_label` to `target_label`, replacing any existing label
+    /// with that name.
+    Rename,
+}
+
+/// A relabeling rule, as written in the `relabel` section of a configuration file.
+/// Modeled on Prometheus' `metric_relabel_configs`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelabelConfig {
+    pub action: Action,
+    /// Matched against the whole of a label name or value, like in Prometheus.
+    pub regex: Option<String>,
+    pub source_label: Option<String>,
+    pub target_label: Option<String>,
+}
+
+/// A compiled relabeling rule.
+#[derive(Clone, Debug)]
+struct Rule {
+    action: Action,
+    regex: Regex,
+    source_label: String,
+    target_label: String,
+}
+
+/// Rewrites the labels of each sample after it is parsed, and before it is
+/// exported, by applying a list of rules in order.
+#[derive(Clone, Debug, Default)]
+pub struct Relabeler {
+    rules: Vec<Rule>,
+}
+
+impl Relabeler {
+    /// Compiles the rules, checking that each has the settings its action needs.
+    pub fn new(configs: &[RelabelConfig]) -> Result<Self, String> {
+        let mut rules = Vec::with_capacity(configs.len());
+        for (i, config) in configs.iter().enumerate() {
+            let needs_source =
+                matches!(config.action, Action::Drop | Action::Keep | Action::Rename);
+            let needs_target = config.action == Action::Rename;
+            let needs_regex = config.action != Action::Rename;
+            if needs_source && config.source_label.is_none() {
+                return Err(format!("relabel rule {} needs a source_label", i + 1));
+            }
+            if needs_target && config.target_label.is_none() {
+                return Err(format!("relabel rule {} needs a target_label", i + 1));
+            }
+            if needs_regex && config.regex.is_none() {
+                return Err(format!("relabel rule {} needs a regex", i + 1));
+            }
+            let regex = config.regex.as_deref().unwrap_or(".*");
+            let regex = Regex::new(&format!("^(?:{})$", regex))
+                .map_err(|err| format!("relabel rule {} has an invalid regex: {}", i + 1, err))?;
+            rules.push(Rule {
+                action: config.action,
+                regex,
+                source_label: config.source_label.clone().unwrap_or_default(),
+                target_label: config.target_label.clone().unwrap_or_default(),
+            });
+        }
+        Ok(Relabeler { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Applies the rules to the labels of a sample.
+    /// Returns false if the sample should be dropped.
+    pub fn apply<'a>(&'a self, labels: &mut LabelSet<'a>) -> bool {
+        for rule in self.rules.iter() {
+            match rule.action {
+                Action::Drop | Action::Keep => {
+                    // A missing label has an empty value, as in Prometheus.
+                    let value = labels
+                        .iter()
+                        .find(|(name, _)| *name == rule.source_label)
+                        .map_or("", |(_, value)| value.as_ref());
+                    if rule.regex.is_match(value) != (rule.action == Action::Keep) {
+                        return false;
+                    }
+                }
+                Action::LabelDrop => labels.retain(|(name, _)| !rule.regex.is_match(name)),
+                Action::LabelKeep => labels.retain(|(name, _)| rule.regex.is_match(name)),
+                Action::Rename => {
+                    let Some(i) = labels
+                        .iter()
+                        .position(|(name, _)| *name == rule.source_label)
+                    else {
+                        continue;
+                    };
+                    let (_, value) = labels.remove(i);
+                    labels.retain(|(name, _)| *name != rule.target_label);
+                    labels.push((rule.target_label.as_str(), value));
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Selects the metric families to export by name. A family is kept if its name
+/// matches any of the include patterns, or if there are none, and doesn't match
+/// any of the exclude patterns. Exclusions take precedence.
+#[derive(Clone, Debug, Default)]
+pub struct MetricFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl MetricFilter {
+    /// Compiles the patterns, which must match the whole of a metric name.
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    Regex::new(&format!("^(?:{})$", pattern))
+                        .map_err(|err| format!("invalid metric pattern {:?}: {}", pattern, err))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(MetricFilter {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Returns true if the metric family called `name` should be exported.
+    pub fn matches(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|regex| regex.is_match(name)))
+            && !self.exclude.iter().any(|regex| regex.is_match(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> MetricFilter {
+        let strings =
+            |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        MetricFilter::new(&strings(include), &strings(exclude)).unwrap()
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = filter(&[], &[]);
+        assert!(filter.is_empty());
+        assert!(filter.matches("up"));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let filter = filter(&["node_.*", "up"], &["node_cpu_.*"]);
+        assert!(filter.matches("up"));
+        assert!(filter.matches("node_load1"));
+        assert!(!filter.matches("go_goroutines"));
+        // Patterns must match the whole name.
+        assert!(!filter.matches("upstream"));
+        // Exclusions take precedence where the patterns overlap.
+        assert!(!filter.matches("node_cpu_seconds_total"));
+    }
+
+    #[test]
+    fn exclude_only() {
+        let filter = filter(&[], &["go_.*"]);
+        assert!(filter.matches("up"));
+        assert!(!filter.matches("go_goroutines"));
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let err = MetricFilter::new(&["(".to_string()], &[]).unwrap_err();
+        assert!(err.contains("invalid metric pattern"), "{}", err);
+    }
+}
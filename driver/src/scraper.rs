@@ -0,0 +1,1506 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Scraping targets and handing the results to an `Exporter`, which is only
+//! built with the `scraper` feature.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Instant;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use chrono::DateTime;
+use flate2::read::MultiGzDecoder;
+use hyper::Uri;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::signal;
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::task;
+use tokio::time::MissedTickBehavior;
+use tracing::Instrument;
+
+use crate::{
+    discovery, dump, export_failed, fetch, http, metrics, parse, relabel, telemetry, template,
+    validate, DriverArgs, Exporter,
+};
+
+pub trait Args {
+    /// The flags and settings shared by every binary.
+    fn driver_args(&self) -> &DriverArgs;
+
+    /// The (host, port) address to listen on for connections,
+    /// or `None` to run without serving any HTTP endpoints.
+    fn addr(&self) -> Option<(&str, u16)> {
+        let args = self.driver_args();
+        if args.no_listen || args.host.is_empty() {
+            None
+        } else {
+            Some((args.host.as_str(), args.port))
+        }
+    }
+
+    /// The PEM file holding the certificate chain to serve the HTTP endpoints
+    /// with over TLS. If neither it nor `tls_key` is given, they are served
+    /// over plain HTTP.
+    fn tls_cert(&self) -> Option<&str> {
+        self.driver_args().tls_cert.as_deref()
+    }
+
+    /// The PEM file holding the private key for `tls_cert`.
+    fn tls_key(&self) -> Option<&str> {
+        self.driver_args().tls_key.as_deref()
+    }
+
+    /// The "user:password" that requests to the HTTP endpoints, other than
+    /// the index page, must give with basic authentication. If `None`, no
+    /// authentication is required.
+    fn web_auth(&self) -> Option<&str> {
+        self.driver_args().web_auth.as_deref()
+    }
+
+    /// The template for the instance label of each target, such as
+    /// "{host}:{port}". See `template::target_variable` for the variables.
+    fn instance(&self) -> Option<&str> {
+        self.driver_args().instance.as_deref()
+    }
+
+    /// The template for the job label of each target.
+    fn job(&self) -> Option<&str> {
+        self.driver_args().job.as_deref()
+    }
+
+    /// How often metrics will be scraped.
+    fn interval(&self) -> Duration {
+        self.driver_args().interval
+    }
+
+    /// How long to wait for a scrape to complete before giving up on it.
+    fn scrape_timeout(&self) -> Duration {
+        let args = self.driver_args();
+        args.scrape_timeout.unwrap_or(args.interval)
+    }
+
+    /// How many times to retry a scrape that failed with a transient error.
+    fn max_retries(&self) -> u32 {
+        self.driver_args().max_retries
+    }
+
+    /// How many scrapes to hold in memory before dropping samples.
+    fn buffer(&self) -> usize {
+        self.driver_args().buffer
+    }
+
+    /// Whether to wait for space when the buffer is full, rather than dropping the scrape.
+    fn block_on_full(&self) -> bool {
+        self.driver_args().block_on_full
+    }
+
+    /// Whether to lengthen the scrape interval while the writer is falling
+    /// behind, rather than scrape at a fixed rate.
+    fn adaptive_interval(&self) -> bool {
+        self.driver_args().adaptive_interval
+    }
+
+    /// How long to wait on shutdown for the writer to export the scrapes still
+    /// buffered and close the exporters, or `None` to wait for as long as it takes.
+    fn shutdown_timeout(&self) -> Option<Duration> {
+        let args = self.driver_args();
+        (!args.shutdown_timeout.is_zero()).then_some(args.shutdown_timeout)
+    }
+
+    /// How often to flush the exporters while running, or `None` to leave it
+    /// to them to flush when their buffers fill up, and on shutdown.
+    fn flush_interval(&self) -> Option<Duration> {
+        let args = self.driver_args();
+        (!args.flush_interval.is_zero()).then_some(args.flush_interval)
+    }
+
+    /// What to do when a scrape falls due while the loop is still busy, for
+    /// example after the process was suspended.
+    fn missed_tick_behavior(&self) -> MissedTickBehavior {
+        self.driver_args().missed_ticks.behavior()
+    }
+
+    /// Whether to skip metric families that can't be parsed, rather than
+    /// dropping the whole scrape.
+    fn lenient(&self) -> bool {
+        self.driver_args().lenient
+    }
+
+    /// Whether to warn about counters that decrease between scrapes without
+    /// the target being restarted.
+    fn check_counters(&self) -> bool {
+        self.driver_args().check_counters
+    }
+
+    /// The most distinct values a label of a target's metric family may have
+    /// within `label_value_window`. Labels with more are dropped from that
+    /// family from then on. If `None`, labels are never dropped this way.
+    fn label_value_limit(&self) -> Option<usize> {
+        let args = self.driver_args();
+        (args.label_value_limit > 0).then_some(args.label_value_limit)
+    }
+
+    /// The window of time over which the values of each label are counted.
+    fn label_value_window(&self) -> Duration {
+        self.driver_args().label_value_window
+    }
+
+    /// Whether to make conditional requests, and skip the scrapes of targets
+    /// whose metrics haven't changed since they were last scraped.
+    fn skip_unchanged(&self) -> bool {
+        self.driver_args().skip_unchanged
+    }
+
+    /// A directory to write the raw body of each scrape to, for debugging.
+    fn dump_dir(&self) -> Option<&str> {
+        self.driver_args().dump_dir.as_deref()
+    }
+
+    /// The most files to keep in the dump directory.
+    fn dump_max_files(&self) -> usize {
+        self.driver_args().dump_max_files
+    }
+
+    /// The rules used to rewrite or drop samples before they are exported.
+    fn relabeler(&self) -> &relabel::Relabeler {
+        &self.driver_args().relabeler
+    }
+
+    /// Patterns for the names of the metric families to export.
+    /// If empty, all families are exported unless excluded.
+    fn include_metrics(&self) -> &[String] {
+        &self.driver_args().include_metric
+    }
+
+    /// Patterns for the names of metric families that should not be exported.
+    /// Takes precedence over `include_metrics`.
+    fn exclude_metrics(&self) -> &[String] {
+        &self.driver_args().exclude_metric
+    }
+
+    /// The URLs of the Prometheus client endpoints to scrape.
+    /// If the only target is "-", then read from stdin. Targets that are
+    /// `file://` URLs or plain paths are read once, rather than scraped.
+    fn targets(&self) -> Vec<&str> {
+        let args = self.driver_args();
+        let targets: Vec<&str> = args
+            .target
+            .iter()
+            .chain(args.extra_targets.iter())
+            .map(|t| t.as_str())
+            .collect();
+        if targets.is_empty() {
+            args.config_targets.iter().map(|t| t.as_str()).collect()
+        } else {
+            targets
+        }
+    }
+
+    /// The path to scrape on targets whose URL has no path, such as "/metrics".
+    fn metrics_path(&self) -> &str {
+        &self.driver_args().metrics_path
+    }
+
+    /// A file listing more targets to scrape, in the JSON format of Prometheus'
+    /// file-based service discovery. It is read again when it changes.
+    fn targets_file(&self) -> Option<&str> {
+        self.driver_args().targets_file.as_deref()
+    }
+
+    /// The time, in milliseconds since the epoch, to give the samples read from
+    /// files or stdin that don't have their own timestamp. If `None`, stdin is
+    /// read at the current time, and each file at its modification time.
+    fn timestamp(&self) -> Option<u64> {
+        self.driver_args().timestamp
+    }
+
+    /// Whether to skip verification of TLS certificates presented by HTTPS targets.
+    fn insecure_skip_verify(&self) -> bool {
+        self.driver_args().insecure_skip_verify
+    }
+
+    /// The largest response body accepted from a target, after decompression.
+    /// Scrapes with larger bodies fail rather than exhaust memory.
+    fn max_body_bytes(&self) -> usize {
+        self.driver_args().max_body_bytes
+    }
+
+    /// The most samples to accept from one scrape, after filtering and
+    /// relabeling. Scrapes with more are dropped entirely. If `None`, there is
+    /// no limit.
+    fn sample_limit(&self) -> Option<usize> {
+        let args = self.driver_args();
+        (args.sample_limit > 0).then_some(args.sample_limit)
+    }
+
+    /// Credentials sent in the `Authorization` header of each scrape.
+    /// Either a bearer token, or "user:password" for basic authentication.
+    fn authorization(&self) -> Option<&str> {
+        self.driver_args().authorization.as_deref()
+    }
+
+    /// The URL of an HTTP proxy to connect to targets through. If not given,
+    /// the `HTTP_PROXY` and `HTTPS_PROXY` environment variables are used.
+    fn proxy(&self) -> Option<&str> {
+        self.driver_args().proxy.as_deref()
+    }
+
+    /// Whether to scrape each target a single time and exit, rather than polling.
+    fn oneshot(&self) -> bool {
+        let args = self.driver_args();
+        args.once || args.dry_run
+    }
+
+    /// Whether this is a dry run, which scrapes and parses each target once
+    /// without writing anything. A dry run fails if any scrape can't be parsed,
+    /// or is over the sample limit.
+    fn dry_run(&self) -> bool {
+        self.driver_args().dry_run
+    }
+
+    /// The URL of an OpenTelemetry collector's OTLP/HTTP trace endpoint.
+    /// If given, each scrape is traced as it is collected, parsed and exported.
+    fn otlp_endpoint(&self) -> Option<&str> {
+        self.driver_args().otlp_endpoint.as_deref()
+    }
+
+    /// Whether to enable the `/-/reload` and `/-/quit` endpoints.
+    fn enable_lifecycle_endpoints(&self) -> bool {
+        self.driver_args().enable_lifecycle
+    }
+
+    /// Re-reads the settings that can be changed while running.
+    /// Called when the `/-/reload` endpoint is requested.
+    fn reload(&self) -> Result<Reload, String>;
+}
+
+/// The settings that can be safely changed without restarting, by requesting
+/// `/-/reload`. Changes to any other settings, such as the listening address,
+/// the output or the TLS and authorization settings, need a restart.
+pub struct Reload {
+    /// The URLs of the Prometheus client endpoints to scrape.
+    /// Reading from stdin can't be enabled by a reload.
+    pub targets: Vec<String>,
+    /// How often metrics will be scraped.
+    pub interval: Duration,
+}
+
+/// A Prometheus client endpoint to scrape.
+#[derive(Clone)]
+struct Target {
+    url: Uri,
+    /// The instance label to add to the target's samples.
+    instance: Option<String>,
+    /// The job label to add to the target's samples.
+    job: Option<String>,
+    /// Other labels to add to the target's samples, from its target group.
+    labels: Arc<Vec<(String, String)>>,
+}
+
+/// Parses the URLs of the targets to scrape. Targets without a path are scraped
+/// at `metrics_path`. Each target gets its own instance label, expanded from the
+/// `instance` template if one was given, and otherwise its address. The job
+/// label is expanded from the `job` template.
+fn parse_targets(
+    targets: &[&str],
+    instance: Option<&str>,
+    job: Option<&str>,
+    metrics_path: &str,
+) -> Result<Vec<Target>, String> {
+    targets
+        .iter()
+        .map(|target| parse_target(target, instance, job, metrics_path, &BTreeMap::new()))
+        .collect()
+}
+
+/// Parses the URL of a target, expanding the instance and job templates with
+/// its address and the labels of its group in a targets file.
+fn parse_target(
+    target: &str,
+    instance: Option<&str>,
+    job: Option<&str>,
+    metrics_path: &str,
+    group_labels: &BTreeMap<String, String>,
+) -> Result<Target, String> {
+    if target == "-" {
+        return Err("stdin can't be combined with other targets".to_string());
+    }
+    let url = target
+        .parse::<Uri>()
+        .map_err(|err| format!("invalid URI {}: {}", target, err))?;
+    let url = with_metrics_path(url, metrics_path)
+        .map_err(|err| format!("invalid metrics path {}: {}", metrics_path, err))?;
+    let expand = |template| {
+        template::expand(template, |name| {
+            template::target_variable(&url, group_labels, name)
+        })
+    };
+    let instance = match instance {
+        Some(instance) => Some(expand(instance)?),
+        None => url.authority().map(|f| f.as_str().to_string()),
+    };
+    let job = job.map(expand).transpose()?;
+    Ok(Target {
+        url,
+        instance,
+        job,
+        labels: Arc::default(),
+    })
+}
+
+/// Builds the targets listed in the groups of a targets file. A target given as
+/// "host:port" is scraped over `__scheme__` at `__metrics_path__`, if its group
+/// has those labels, and otherwise over HTTP at `metrics_path`. An `instance`
+/// label in the group takes precedence over the instance given on the command line.
+/// Other labels starting with "__" are dropped, as in Prometheus, but can still
+/// be used in the instance and job templates.
+fn discovered_targets(
+    groups: &[discovery::TargetGroup],
+    instance: Option<&str>,
+    job: Option<&str>,
+    metrics_path: &str,
+) -> Result<Vec<Target>, String> {
+    let mut out = Vec::new();
+    for group in groups {
+        let scheme = group
+            .labels
+            .get("__scheme__")
+            .map_or("http", |s| s.as_str());
+        let metrics_path = group
+            .labels
+            .get("__metrics_path__")
+            .map_or(metrics_path, |p| p.as_str());
+        let group_instance = group.labels.get("instance");
+        let labels: Arc<Vec<(String, String)>> = Arc::new(
+            group
+                .labels
+                .iter()
+                .filter(|(name, _)| !name.starts_with("__") && *name != "instance")
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        );
+        for target in group.targets.iter() {
+            let url = if target.contains("://") {
+                target.clone()
+            } else {
+                format!("{}://{}", scheme, target)
+            };
+            let mut target = parse_target(&url, instance, job, metrics_path, &group.labels)?;
+            if let Some(group_instance) = group_instance {
+                target.instance = Some(group_instance.clone());
+            }
+            target.labels = labels.clone();
+            out.push(target);
+        }
+    }
+    Ok(out)
+}
+
+/// Adds the labels of a target to a sample's labels, replacing any label with
+/// the same name that the target exposed.
+fn add_target_labels<'a>(labels: &mut parse::LabelSet<'a>, target_labels: &'a [(String, String)]) {
+    for (name, value) in target_labels {
+        match labels.iter_mut().find(|(n, _)| n == name) {
+            Some(existing) => existing.1 = Cow::Borrowed(value.as_str()),
+            None => labels.push((name.as_str(), Cow::Borrowed(value.as_str()))),
+        }
+    }
+}
+
+/// Replaces the path of `url` with `metrics_path` if it has none, keeping any query.
+fn with_metrics_path(url: Uri, metrics_path: &str) -> Result<Uri, hyper::http::Error> {
+    if url.path() != "/" {
+        return Ok(url);
+    }
+    let mut parts = url.into_parts();
+    let path_and_query = match parts.path_and_query.as_ref().and_then(|p| p.query()) {
+        Some(query) => format!("{}?{}", metrics_path, query),
+        None => metrics_path.to_string(),
+    };
+    parts.path_and_query = Some(path_and_query.parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+/// Returns the path named by a target, if it is a local file rather than a URL.
+/// Files can be given either as a `file://` URL or as a plain path.
+fn file_path(target: &str) -> Option<PathBuf> {
+    if let Some(path) = target.strip_prefix("file://") {
+        Some(PathBuf::from(path))
+    } else if target == "-" || target.contains("://") {
+        None
+    } else {
+        Some(PathBuf::from(target))
+    }
+}
+
+/// A raw scrape of one target, waiting to be parsed and exported.
+struct Scrape {
+    /// The instance label of the target the scrape came from.
+    instance: Option<String>,
+    /// The job label of the target the scrape came from.
+    job: Option<String>,
+    /// Other labels to add to the scraped samples.
+    labels: Arc<Vec<(String, String)>>,
+    timestamp_millis: u64,
+    exposition: parse::Exposition,
+    /// The span that the collection, parsing and export of the scrape are traced under.
+    span: tracing::Span,
+}
+
+/// Scrapes a target and sends the result to the writer. If the writer's buffer
+/// is full, the scrape is dropped, unless a `send_timeout` is given to wait for space.
+/// Returns true if the scrape was handed to the writer.
+async fn collect(
+    client: Arc<fetch::Client>,
+    target: Target,
+    tx: Sender<Scrape>,
+    send_timeout: Option<Duration>,
+) -> bool {
+    let Target {
+        url,
+        instance,
+        job,
+        labels,
+    } = target;
+    debug!("collecting sample from {}", url);
+    let span = tracing::info_span!("scrape", target = %url);
+    let timer = metrics::SCRAPE_DURATION.start_timer();
+    let result = client
+        .fetch(url.clone())
+        .instrument(tracing::info_span!(parent: &span, "collect"))
+        .await;
+    timer.observe_duration();
+    let (timestamp_millis, exposition) = match result {
+        Ok(result) => result,
+        Err(err) if err.is::<fetch::NotModified>() => {
+            debug!("{} is unchanged since the last scrape", url);
+            return true;
+        }
+        Err(err) => {
+            error!("unable to collect sample: {}", err);
+            metrics::SCRAPE_FAILURES.inc();
+            return false;
+        }
+    };
+    debug!("collected sample {}", timestamp_millis);
+    let scrape = Scrape {
+        instance,
+        job,
+        labels,
+        timestamp_millis,
+        exposition,
+        span,
+    };
+    // Counted before sending, so the writer can't take it off the queue first.
+    metrics::QUEUE_DEPTH.inc();
+    let dropped = match send_timeout {
+        None => match tx.try_send(scrape) {
+            Ok(()) => return true,
+            Err(err) => {
+                error!("unable to send sample {}: {}", timestamp_millis, err);
+                matches!(err, TrySendError::Full(_))
+            }
+        },
+        Some(timeout) => match tx.send_timeout(scrape, timeout).await {
+            Ok(()) => return true,
+            Err(err) => {
+                error!("unable to send sample {}: {}", timestamp_millis, err);
+                matches!(err, SendTimeoutError::Timeout(_))
+            }
+        },
+    };
+    metrics::QUEUE_DEPTH.dec();
+    if dropped {
+        metrics::DROPPED_SCRAPES.inc();
+    }
+    false
+}
+
+/// Scrapes each target once, without listening for connections.
+async fn collect_each_once(args: &impl Args, targets: Vec<Target>, tx: Sender<Scrape>) -> ExitCode {
+    let client = Arc::new(fetch::Client::new(args));
+    let tasks: Vec<_> = targets
+        .into_iter()
+        .map(|target| tokio::spawn(collect(client.clone(), target, tx.clone(), None)))
+        .collect();
+    let mut exit_code = ExitCode::SUCCESS;
+    for task in tasks {
+        if !matches!(task.await, Ok(true)) {
+            exit_code = ExitCode::FAILURE;
+        }
+    }
+    exit_code
+}
+
+/// Scrapes the targets every interval until asked to quit, while serving the
+/// HTTP endpoints. Fails if the HTTP server couldn't be started.
+async fn polling_loop(
+    args: &impl Args,
+    mut targets: Vec<Target>,
+    mut targets_file: Option<discovery::TargetsFile>,
+    mut discovered: Vec<Target>,
+    tx: Sender<Scrape>,
+    api: Option<Arc<dyn http::Api>>,
+) -> ExitCode {
+    if args.interval().is_zero() {
+        error!("the scrape interval must be longer than zero");
+        return ExitCode::FAILURE;
+    }
+    let tls = match (args.tls_cert(), args.tls_key()) {
+        (Some(cert), Some(key)) if args.addr().is_some() => match http::tls_acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                error!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        },
+        (None, None) => None,
+        (Some(_), Some(_)) => {
+            warn!("TLS settings are unused without a listener");
+            None
+        }
+        _ => {
+            error!("a TLS certificate and key must be given together");
+            return ExitCode::FAILURE;
+        }
+    };
+    let listener = match args.addr() {
+        Some(addr) => match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!(
+                    "listening on {}:{}{}",
+                    addr.0,
+                    addr.1,
+                    if tls.is_some() { " with TLS" } else { "" }
+                );
+                metrics::register();
+                Some(listener)
+            }
+            Err(err) => {
+                error!("error binding to {}:{}: {}", addr.0, addr.1, err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => {
+            if args.enable_lifecycle_endpoints() {
+                warn!("lifecycle endpoints are unavailable without a listener");
+            }
+            None
+        }
+    };
+
+    let client = Arc::new(fetch::Client::new(args));
+    let (reload_tx, mut reload_rx) = watch::channel(());
+    let (quit_tx, mut quit_rx) = watch::channel(());
+    let lifecycle = args.enable_lifecycle_endpoints().then(|| {
+        Arc::new(http::Lifecycle {
+            reload: reload_tx,
+            quit: quit_tx,
+        })
+    });
+    let svc = match http::Svc::new(lifecycle, api, args.web_auth()) {
+        Ok(svc) => svc,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut interval = args.interval();
+    // The interval actually scraped at, which may be longer than `interval`
+    // while the writer is falling behind.
+    let mut current_interval = interval;
+    let mut sample_interval = tokio::time::interval(interval);
+    sample_interval.set_missed_tick_behavior(args.missed_tick_behavior());
+    let mut discovery_interval = tokio::time::interval(discovery::REFRESH_INTERVAL);
+    let mut connections = task::JoinSet::new();
+
+    loop {
+        tokio::select! {
+            _ = signal::ctrl_c() => {
+                info!("Interrupt signal received.");
+                break
+            }
+            Ok(()) = quit_rx.changed() => {
+                info!("Quit requested.");
+                break
+            }
+            _ = sample_interval.tick() => {
+              debug!("scheduling sample");
+              if args.adaptive_interval() {
+                  if let Some(adapted) = adapt_interval(current_interval, interval) {
+                      if adapted > current_interval {
+                          warn!("writer is falling behind, scraping every {:?}", adapted);
+                      } else {
+                          info!("writer is catching up, scraping every {:?}", adapted);
+                      }
+                      current_interval = adapted;
+                      let start = tokio::time::Instant::now() + current_interval;
+                      sample_interval = tokio::time::interval_at(start, current_interval);
+                      sample_interval.set_missed_tick_behavior(args.missed_tick_behavior());
+                  }
+              }
+              // When blocking, wait at most until the next scrape is due.
+              let send_timeout = args.block_on_full().then_some(current_interval);
+              for target in targets.iter().chain(discovered.iter()) {
+                  tokio::spawn(collect(
+                      client.clone(),
+                      target.clone(),
+                      tx.clone(),
+                      send_timeout,
+                  ));
+              }
+            }
+            _ = discovery_interval.tick(), if targets_file.is_some() => {
+              let file = targets_file.as_mut().unwrap();
+              if let Some(groups) = file.read_if_changed() {
+                  update_discovered(args, groups, &mut discovered);
+              }
+            }
+            Ok(()) = reload_rx.changed() => {
+              info!("reloading configuration");
+              let reload = match args.reload() {
+                  Ok(reload) if reload.interval.is_zero() => {
+                      error!("unable to reload configuration: the scrape interval must be longer than zero");
+                      continue;
+                  }
+                  Ok(reload) => reload,
+                  Err(err) => {
+                      error!("unable to reload configuration: {}", err);
+                      continue;
+                  }
+              };
+              let new_targets: Vec<&str> = reload.targets.iter().map(|t| t.as_str()).collect();
+              match parse_targets(&new_targets, args.instance(), args.job(), args.metrics_path()) {
+                  Ok(new_targets) => targets = new_targets,
+                  Err(err) => {
+                      error!("unable to reload configuration: {}", err);
+                      continue;
+                  }
+              }
+              if reload.interval != interval {
+                  interval = reload.interval;
+                  current_interval = interval;
+                  // Don't scrape again right away, which would duplicate the last scrape.
+                  let start = tokio::time::Instant::now() + interval;
+                  sample_interval = tokio::time::interval_at(start, interval);
+                  sample_interval.set_missed_tick_behavior(args.missed_tick_behavior());
+              }
+              if let Some(file) = targets_file.as_mut() {
+                  update_discovered(args, file.read(), &mut discovered);
+              }
+              info!(
+                  "scraping {} targets every {:?}",
+                  targets.len() + discovered.len(),
+                  interval
+              );
+            }
+            Ok((tcp_stream, _)) = accept(listener.as_ref()) => {
+              connections.spawn(http::serve(tcp_stream, tls.clone(), svc.clone()));
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+    // Let the connections still being served, such as the one that asked to
+    // quit, send their responses before the runtime is shut down.
+    let served = tokio::time::timeout(CONNECTION_GRACE_PERIOD, async {
+        while connections.join_next().await.is_some() {}
+    });
+    if served.await.is_err() {
+        debug!("closing {} connections still open", connections.len());
+    }
+    ExitCode::SUCCESS
+}
+
+/// Replaces the targets discovered from the targets file with those of the
+/// groups just read from it. If the file couldn't be read, or lists an invalid
+/// target, the targets found in it before are kept.
+fn update_discovered(
+    args: &impl Args,
+    groups: Result<Vec<discovery::TargetGroup>, String>,
+    discovered: &mut Vec<Target>,
+) {
+    match groups.and_then(|groups| {
+        discovered_targets(&groups, args.instance(), args.job(), args.metrics_path())
+    }) {
+        Ok(targets) => {
+            info!("discovered {} targets", targets.len());
+            *discovered = targets;
+        }
+        Err(err) => error!("unable to update targets from file: {}", err),
+    }
+}
+
+/// The most that the scrape interval is lengthened by when adapting to a slow writer.
+const MAX_INTERVAL_FACTOR: u32 = 8;
+
+/// Adapts the scrape interval to how far behind the writer is. The interval is
+/// doubled while the queue of scrapes is at least three quarters full, and
+/// halved back towards `base` once the queue is no more than a quarter full.
+/// Returns the new interval if it changed.
+fn adapt_interval(current: Duration, base: Duration) -> Option<Duration> {
+    let depth = metrics::QUEUE_DEPTH.get();
+    let capacity = metrics::QUEUE_CAPACITY.get();
+    let adapted = if depth * 4 >= capacity * 3 {
+        (current * 2).min(base * MAX_INTERVAL_FACTOR)
+    } else if depth * 4 <= capacity {
+        (current / 2).max(base)
+    } else {
+        current
+    };
+    (adapted != current).then_some(adapted)
+}
+
+/// How long to wait on shutdown for the HTTP connections still open to be served.
+const CONNECTION_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Accepts the next connection, or waits forever if there is no listener.
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The magic bytes at the start of a gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Converts raw input to text, decompressing it first if it is gzipped.
+fn decode_input(input: Vec<u8>) -> std::io::Result<String> {
+    let input = if input.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(input.as_slice()).read_to_end(&mut decompressed)?;
+        decompressed
+    } else {
+        input
+    };
+    fetch::decode_utf8(input)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+/// Parses a time given in the configuration, either as an RFC 3339 date and
+/// time such as "2024-05-01T12:00:00Z", or as milliseconds since the epoch.
+/// Returns milliseconds since the epoch.
+pub fn parse_timestamp(s: &str) -> Result<u64, String> {
+    if let Ok(millis) = s.parse::<u64>() {
+        return Ok(millis);
+    }
+    let time = DateTime::parse_from_rfc3339(s)
+        .map_err(|err| format!("invalid timestamp {:?}: {}", s, err))?;
+    u64::try_from(time.timestamp_millis())
+        .map_err(|_| format!("timestamp {:?} is before the epoch", s))
+}
+
+/// Parses a duration given on the command line or in the configuration, such
+/// as "500ms", "30s", "5m" or "1h30m". The units are ms, s, m, h, d and w. A
+/// bare number is a number of seconds, as durations used to be given.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    if let Ok(seconds) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    let mut rest = s;
+    let mut total = Duration::ZERO;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (count, tail) = rest.split_at(split);
+        let count: u64 = count
+            .parse()
+            .map_err(|_| format!("invalid duration {:?}", s))?;
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        let unit = match unit {
+            "ms" => Duration::from_millis(1),
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            "d" => Duration::from_secs(24 * 60 * 60),
+            "w" => Duration::from_secs(7 * 24 * 60 * 60),
+            _ => {
+                return Err(format!(
+                    "invalid duration unit {:?}, expected ms, s, m, h, d or w",
+                    unit
+                ))
+            }
+        };
+        total = u32::try_from(count)
+            .ok()
+            .and_then(|count| unit.checked_mul(count))
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(|| format!("duration {:?} is too long", s))?;
+        rest = tail;
+    }
+    Ok(total)
+}
+
+/// A duration in a configuration file, either as a number of seconds or as a
+/// string read by `parse_duration`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ConfigDuration {
+    Seconds(u64),
+    Text(String),
+}
+
+impl ConfigDuration {
+    pub fn to_duration(self) -> Result<Duration, String> {
+        match self {
+            ConfigDuration::Seconds(seconds) => Ok(Duration::from_secs(seconds)),
+            ConfigDuration::Text(text) => parse_duration(&text),
+        }
+    }
+}
+
+fn read_from_stdin(
+    instance: Option<&str>,
+    job: Option<&str>,
+    timestamp: Option<u64>,
+    tx: Sender<Scrape>,
+) -> ExitCode {
+    let mut input = Vec::new();
+    let result = std::io::stdin()
+        .read_to_end(&mut input)
+        .and_then(|_| decode_input(input));
+    let input = match result {
+        Ok(input) => input,
+        Err(err) => {
+            error!("error reading from stdin: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let timestamp = timestamp.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    });
+    let scrape = Scrape {
+        instance: instance.map(|s| s.to_string()),
+        job: job.map(|s| s.to_string()),
+        labels: Arc::default(),
+        timestamp_millis: timestamp,
+        exposition: parse::Exposition::Text(input),
+        span: tracing::info_span!("scrape", target = "-"),
+    };
+    metrics::QUEUE_DEPTH.inc();
+    if let Err(err) = tx.try_send(scrape) {
+        error!("unable to send sample: {}", err);
+        metrics::QUEUE_DEPTH.dec();
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Reads each file once and sends it to the writer. Directories are expanded
+/// into the files they contain, in order of their names. Gzipped files are
+/// decompressed. Samples that don't have their own timestamp are given
+/// `timestamp`, if set, and otherwise the modification time of their file.
+async fn read_files(
+    paths: Vec<PathBuf>,
+    instance: Option<&str>,
+    job: Option<&str>,
+    timestamp: Option<u64>,
+    tx: &Sender<Scrape>,
+) -> ExitCode {
+    let mut exit_code = ExitCode::SUCCESS;
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path);
+            continue;
+        }
+        match std::fs::read_dir(&path) {
+            Ok(entries) => {
+                let mut entries: Vec<PathBuf> = entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.is_file())
+                    .collect();
+                entries.sort();
+                files.extend(entries);
+            }
+            Err(err) => {
+                error!("error reading directory {}: {}", path.display(), err);
+                exit_code = ExitCode::FAILURE;
+            }
+        }
+    }
+    for path in files {
+        debug!("reading {}", path.display());
+        let result = std::fs::read(&path)
+            .and_then(decode_input)
+            .and_then(|exposition| {
+                let modified = std::fs::metadata(&path)?.modified()?;
+                Ok((modified, exposition))
+            });
+        let (modified, exposition) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                error!("error reading {}: {}", path.display(), err);
+                exit_code = ExitCode::FAILURE;
+                continue;
+            }
+        };
+        let timestamp_millis = timestamp.unwrap_or_else(|| {
+            modified
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        });
+        let scrape = Scrape {
+            instance: instance.map(|s| s.to_string()),
+            job: job.map(|s| s.to_string()),
+            labels: Arc::default(),
+            timestamp_millis,
+            exposition: parse::Exposition::Text(exposition),
+            span: tracing::info_span!("scrape", target = %path.display()),
+        };
+        // Wait for the writer, rather than dropping files when the buffer is full.
+        metrics::QUEUE_DEPTH.inc();
+        if let Err(err) = tx.send(scrape).await {
+            error!("unable to send sample: {}", err);
+            metrics::QUEUE_DEPTH.dec();
+            return ExitCode::FAILURE;
+        }
+    }
+    exit_code
+}
+
+/// Exports a metric family, treating a panic in the exporter like any other
+/// failure, so that one bad sample can't stop all further writes.
+fn export(
+    exporter: &mut (dyn Exporter + Send),
+    timestamp_millis: u64,
+    family: &parse::MetricFamily,
+) -> bool {
+    match panic::catch_unwind(AssertUnwindSafe(|| {
+        exporter.export(timestamp_millis, family)
+    })) {
+        Ok(exported) => exported,
+        Err(_) => {
+            error!(
+                "{} exporter panicked on metric family {:?}",
+                exporter.name(),
+                family.var
+            );
+            false
+        }
+    }
+}
+
+/// Parses the body of a scrape. Protobuf expositions are decoded into
+/// `decoded`, which the returned families borrow from.
+fn parse_scrape<'a>(
+    exposition: &'a parse::Exposition,
+    decoded: &'a mut Vec<parse::OwnedMetricFamily>,
+    instance: Option<&'a str>,
+    job: Option<&'a str>,
+    lenient: bool,
+) -> Result<Vec<parse::MetricFamily<'a>>, String> {
+    match exposition {
+        parse::Exposition::Text(text) if lenient => Ok(parse::parse_lenient(instance, job, text)),
+        parse::Exposition::Text(text) => {
+            parse::parse(instance, job, text).map_err(|err| err.to_string())
+        }
+        parse::Exposition::Protobuf(body) => {
+            *decoded = parse::parse_protobuf(instance, job, body, lenient)
+                .map_err(|err| format!("unable to decode protobuf exposition: {}", err))?;
+            Ok(decoded
+                .iter()
+                .map(parse::OwnedMetricFamily::as_borrowed)
+                .collect())
+        }
+    }
+}
+
+/// How the writer processes each scrape before exporting it.
+struct Processing {
+    lenient: bool,
+    relabeler: relabel::Relabeler,
+    filter: relabel::MetricFilter,
+    validator: Option<validate::CounterValidator>,
+    limiter: Option<validate::CardinalityLimiter>,
+    dumper: Option<dump::Dumper>,
+    sample_limit: Option<usize>,
+    flush_interval: Option<Duration>,
+}
+
+/// Flushes each exporter, treating a panic like `export` does.
+fn flush(exporters: &mut [Box<dyn Exporter + Send>]) {
+    debug!("flushing exporters");
+    for exporter in exporters.iter_mut() {
+        if panic::catch_unwind(AssertUnwindSafe(|| exporter.flush())).is_err() {
+            error!("{} exporter panicked while flushing", exporter.name());
+        }
+    }
+}
+
+/// Tells each exporter that a scrape has been exported, treating a panic
+/// like `export` does.
+fn end_scrape(exporters: &mut [Box<dyn Exporter + Send>]) {
+    for exporter in exporters.iter_mut() {
+        if panic::catch_unwind(AssertUnwindSafe(|| exporter.end_scrape())).is_err() {
+            error!(
+                "{} exporter panicked at the end of a scrape",
+                exporter.name()
+            );
+        }
+    }
+}
+
+/// Waits for the next tick of `interval`, or forever if there is none.
+async fn tick(interval: Option<&mut tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Parses each scrape and exports it to every exporter, until the channel is
+/// closed. Returns false if any scrape couldn't be parsed, or was dropped for
+/// being over the sample limit.
+async fn writer_loop(
+    mut rx: Receiver<Scrape>,
+    processing: Processing,
+    mut exporters: Vec<Box<dyn Exporter + Send>>,
+) -> bool {
+    debug!("writer started");
+    let Processing {
+        lenient,
+        relabeler,
+        filter,
+        mut validator,
+        mut limiter,
+        mut dumper,
+        sample_limit,
+        flush_interval,
+    } = processing;
+    // Start each exporter's failure count at zero, so that it is served
+    // before the first failure.
+    for exporter in exporters.iter() {
+        metrics::EXPORT_FAILURES.with_label_values(&[exporter.name()]);
+    }
+    let mut flush_ticker = flush_interval.map(|period| {
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    });
+    let mut parsed_all = true;
+    loop {
+        let scrape = tokio::select! {
+            scrape = rx.recv() => scrape,
+            _ = tick(flush_ticker.as_mut()) => {
+                flush(&mut exporters);
+                continue;
+            }
+        };
+        match scrape {
+            Some(Scrape {
+                instance,
+                job,
+                labels,
+                timestamp_millis,
+                exposition,
+                span,
+            }) => {
+                metrics::QUEUE_DEPTH.dec();
+                debug!("processing sample {}", timestamp_millis);
+                if let Some(dumper) = dumper.as_mut() {
+                    dumper.dump(timestamp_millis, instance.as_deref(), &exposition);
+                }
+                let start_marker = Instant::now();
+                let mut decoded = Vec::new();
+                let result = {
+                    let _parse = tracing::info_span!(parent: &span, "parse").entered();
+                    parse_scrape(
+                        &exposition,
+                        &mut decoded,
+                        instance.as_deref(),
+                        job.as_deref(),
+                        lenient,
+                    )
+                };
+                match result {
+                    Ok(mut families) => {
+                        if !labels.is_empty() {
+                            for family in families.iter_mut() {
+                                for sample in family.samples.iter_mut() {
+                                    add_target_labels(&mut sample.labels, &labels);
+                                }
+                            }
+                        }
+                        let samples: usize =
+                            families.iter().map(|family| family.samples.len()).sum();
+                        info!(
+                            "parsed {} metric families with {} samples from {} bytes",
+                            families.len(),
+                            samples,
+                            exposition.len()
+                        );
+                        metrics::PARSED_FAMILIES.inc_by(families.len() as u64);
+                        metrics::PARSED_SAMPLES.inc_by(samples as u64);
+                        metrics::PARSED_BYTES.inc_by(exposition.len() as u64);
+                        if !filter.is_empty() {
+                            families.retain(|family| {
+                                family
+                                    .var
+                                    .or_else(|| family.samples.first().map(|sample| sample.var))
+                                    .is_none_or(|name| filter.matches(name))
+                            });
+                        }
+                        // Counters are checked before relabeling, which could
+                        // merge series that are distinct in the exposition.
+                        let counters = validator
+                            .as_ref()
+                            .map(|validator| validator.check(instance.as_deref(), &families));
+                        if !relabeler.is_empty() {
+                            for family in families.iter_mut() {
+                                family
+                                    .samples
+                                    .retain_mut(|sample| relabeler.apply(&mut sample.labels));
+                            }
+                        }
+                        if let Some(limit) = sample_limit {
+                            let samples: usize =
+                                families.iter().map(|family| family.samples.len()).sum();
+                            if samples > limit {
+                                error!(
+                                    "dropping scrape of {} with {} samples, over the limit of {}",
+                                    instance.as_deref().unwrap_or("unknown instance"),
+                                    samples,
+                                    limit
+                                );
+                                metrics::SCRAPES_OVER_LIMIT.inc();
+                                parsed_all = false;
+                                continue;
+                            }
+                        }
+                        // Only scrapes that are exported count towards what
+                        // later scrapes are checked and limited against.
+                        if let (Some(validator), Some(counters)) = (validator.as_mut(), counters) {
+                            validator.commit(instance.as_deref(), counters);
+                        }
+                        if let Some(limiter) = limiter.as_mut() {
+                            limiter.apply(instance.as_deref(), &mut families);
+                        }
+                        let parse_time = start_marker.elapsed();
+                        info!("parse time: {:?}", parse_time);
+                        metrics::PARSE_DURATION.observe(parse_time.as_secs_f64());
+                        for family in families {
+                            let export_span = tracing::info_span!(
+                                parent: &span,
+                                "export",
+                                metric = family.var.unwrap_or_default()
+                            );
+                            // A family that one exporter fails on is still
+                            // given to the others.
+                            for exporter in exporters.iter_mut() {
+                                if !export_span.in_scope(|| {
+                                    export(exporter.as_mut(), timestamp_millis, &family)
+                                }) {
+                                    export_failed(exporter.name());
+                                }
+                            }
+                            // Yield to the scheduler to allow other tasks to run
+                            task::yield_now().await;
+                        }
+                        end_scrape(&mut exporters);
+                        let write_time = start_marker.elapsed();
+                        info!("write time: {:?}", write_time - parse_time);
+                        metrics::WRITE_DURATION.observe((write_time - parse_time).as_secs_f64());
+                    }
+                    Err(err) => {
+                        error!("{}", err);
+                        metrics::PARSE_FAILURES.inc();
+                        parsed_all = false;
+                    }
+                }
+                debug!("processing done");
+            }
+            None => {
+                debug!("no more samples to process");
+                break;
+            }
+        }
+    }
+    debug!("closing exporters");
+    // Closing can wait on a slow destination, so it is done on another thread
+    // to let the shutdown timeout expire.
+    let closed = task::spawn_blocking(move || {
+        for exporter in exporters.iter_mut() {
+            if panic::catch_unwind(AssertUnwindSafe(|| exporter.close())).is_err() {
+                error!("{} exporter panicked while closing", exporter.name());
+            }
+        }
+    });
+    if closed.await.is_err() {
+        error!("unable to close exporters");
+    }
+    parsed_all
+}
+
+async fn run_async(
+    args: &impl Args,
+    exporters: Vec<Box<dyn Exporter + Send>>,
+    api: Option<Arc<dyn http::Api>>,
+) -> ExitCode {
+    let targets = args.targets();
+    let stdin = targets == ["-"];
+    let (files, urls): (Vec<&str>, Vec<&str>) = if stdin {
+        (Vec::new(), Vec::new())
+    } else {
+        targets
+            .iter()
+            .partition(|target| file_path(target).is_some())
+    };
+    let urls = match parse_targets(&urls, args.instance(), args.job(), args.metrics_path()) {
+        Ok(urls) => urls,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut targets_file = args.targets_file().map(discovery::TargetsFile::new);
+    let discovered = match targets_file.as_mut() {
+        Some(_) if stdin => {
+            error!("stdin can't be combined with a targets file");
+            return ExitCode::FAILURE;
+        }
+        Some(file) => match file.read().and_then(|groups| {
+            discovered_targets(&groups, args.instance(), args.job(), args.metrics_path())
+        }) {
+            Ok(discovered) => {
+                info!("discovered {} targets", discovered.len());
+                discovered
+            }
+            Err(err) => {
+                error!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Vec::new(),
+    };
+    if args.timestamp().is_some() && (!urls.is_empty() || targets_file.is_some()) {
+        error!("a timestamp can only be given for files and stdin, not scraped targets");
+        return ExitCode::FAILURE;
+    }
+    // Files and stdin have no address, so their instance and job labels can't
+    // use any variables.
+    let (local_instance, local_job) = if stdin || !files.is_empty() {
+        let expand = |template: Option<&str>| {
+            template
+                .map(|template| template::expand(template, |_| None))
+                .transpose()
+        };
+        match (expand(args.instance()), expand(args.job())) {
+            (Ok(instance), Ok(job)) => (instance, job),
+            (Err(err), _) | (_, Err(err)) => {
+                error!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        (None, None)
+    };
+    let filter = match relabel::MetricFilter::new(args.include_metrics(), args.exclude_metrics()) {
+        Ok(filter) => filter,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let dumper = match args.dump_dir() {
+        Some(dir) => match dump::Dumper::new(dir, args.dump_max_files()) {
+            Ok(dumper) => Some(dumper),
+            Err(err) => {
+                error!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    // When scraping once, make sure there is room for a scrape of every target.
+    let buffer = if args.oneshot() {
+        args.buffer().max(urls.len() + discovered.len())
+    } else {
+        args.buffer()
+    };
+    let (tx, rx) = channel::<Scrape>(buffer);
+    metrics::QUEUE_CAPACITY.set(buffer as i64);
+    let processing = Processing {
+        lenient: args.lenient(),
+        relabeler: args.relabeler().clone(),
+        filter,
+        validator: args
+            .check_counters()
+            .then(validate::CounterValidator::default),
+        limiter: args
+            .label_value_limit()
+            .map(|limit| validate::CardinalityLimiter::new(limit, args.label_value_window())),
+        dumper,
+        sample_limit: args.sample_limit(),
+        flush_interval: args.flush_interval(),
+    };
+    let mut writer_task = tokio::spawn(writer_loop(rx, processing, exporters));
+
+    let mut exit_code = ExitCode::SUCCESS;
+    if stdin {
+        exit_code = read_from_stdin(
+            local_instance.as_deref(),
+            local_job.as_deref(),
+            args.timestamp(),
+            tx,
+        );
+    } else {
+        if !files.is_empty() {
+            let paths = files.into_iter().filter_map(file_path).collect();
+            exit_code = read_files(
+                paths,
+                local_instance.as_deref(),
+                local_job.as_deref(),
+                args.timestamp(),
+                &tx,
+            )
+            .await;
+        }
+        if urls.is_empty() && targets_file.is_none() {
+            // Let the writer finish once it has processed the files.
+            drop(tx);
+        } else if args.oneshot() {
+            debug!("scraping each target once");
+            let targets = urls.into_iter().chain(discovered).collect();
+            if collect_each_once(args, targets, tx).await == ExitCode::FAILURE {
+                exit_code = ExitCode::FAILURE;
+            }
+        } else {
+            debug!("starting polling loop");
+            if polling_loop(args, urls, targets_file, discovered, tx, api).await
+                == ExitCode::FAILURE
+            {
+                exit_code = ExitCode::FAILURE;
+            }
+        }
+    }
+    debug!("waiting for writer task to complete");
+    let result = match args.shutdown_timeout() {
+        Some(timeout) => match tokio::time::timeout(timeout, &mut writer_task).await {
+            Ok(result) => result,
+            Err(_) => {
+                error!(
+                    "writer didn't finish within {:?}, abandoning {} buffered scrapes",
+                    timeout,
+                    metrics::QUEUE_DEPTH.get()
+                );
+                writer_task.abort();
+                return ExitCode::FAILURE;
+            }
+        },
+        None => writer_task.await,
+    };
+    match result {
+        Ok(true) => {}
+        Ok(false) => {
+            if args.dry_run() {
+                exit_code = ExitCode::FAILURE;
+            }
+        }
+        Err(err) => {
+            error!("error waiting for writer task to complete: {}", err);
+            exit_code = ExitCode::FAILURE;
+        }
+    }
+    debug!("done");
+    exit_code
+}
+
+/// Scrapes `url` once and parses the result, without starting a server or
+/// writer. The instance label is taken from the URL, as it is for scraped targets.
+///
+/// This blocks until the scrape completes, so it must not be called from
+/// within an async runtime.
+pub fn collect_once(url: Uri) -> fetch::FetchResult<Vec<parse::OwnedMetricFamily>> {
+    let rt = runtime::Builder::new_current_thread()
+        .enable_time()
+        .enable_io()
+        .build()?;
+    let client = fetch::Client::default();
+    let (_, exposition) = rt.block_on(client.fetch(url.clone()))?;
+    let instance = url.authority().map(|authority| authority.as_str());
+    match exposition {
+        parse::Exposition::Text(text) => Ok(parse::parse(instance, None, &text)?
+            .iter()
+            .map(parse::OwnedMetricFamily::from)
+            .collect()),
+        parse::Exposition::Protobuf(body) => {
+            Ok(parse::parse_protobuf(instance, None, &body, false)?)
+        }
+    }
+}
+
+/// Scrapes or reads the targets given by `args`, and exports each scrape to
+/// every one of `exporters`, in order. If `api` is given, its endpoints are
+/// served under `/api/` along with the collector's own.
+pub fn run(
+    args: &impl Args,
+    exporters: Vec<Box<dyn Exporter + Send>>,
+    api: Option<Arc<dyn http::Api>>,
+) -> ExitCode {
+    let telemetry = match args.otlp_endpoint().map(telemetry::Telemetry::init) {
+        Some(Ok(telemetry)) => Some(telemetry),
+        Some(Err(err)) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+    let exit_code = match runtime::Builder::new_current_thread()
+        .enable_time()
+        .enable_io()
+        .build()
+    {
+        Ok(rt) => {
+            let exit_code = rt.block_on(run_async(args, exporters, api));
+            // Don't wait for exporters that are still closing after the
+            // shutdown timeout.
+            rt.shutdown_background();
+            exit_code
+        }
+        Err(err) => {
+            error!("error running application thead: {}", err);
+            ExitCode::FAILURE
+        }
+    };
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+    exit_code
+}
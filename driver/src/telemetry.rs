@@ -0,0 +1,66 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Exports the spans recorded while scraping to an OpenTelemetry collector.
+/// Without it, spans are not recorded at all.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Starts exporting spans over OTLP/HTTP to `endpoint`, which is the full
+    /// URL of the collector's trace endpoint.
+    pub fn init(endpoint: &str) -> Result<Self, String> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|err| format!("unable to create OTLP exporter: {}", err))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service_name())
+                    .build(),
+            )
+            .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("driver"));
+        tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+            .map_err(|err| format!("unable to install tracing subscriber: {}", err))?;
+        info!("exporting traces to {}", endpoint);
+        Ok(Telemetry { provider })
+    }
+
+    /// Sends any spans that haven't been exported yet.
+    pub fn shutdown(self) {
+        if let Err(err) = self.provider.shutdown() {
+            error!("unable to flush traces: {}", err);
+        }
+    }
+}
+
+/// The name the process reports itself as, which is the name of the executable.
+fn service_name() -> String {
+    std::env::current_exe()
+        .ok()
+        .and_then(|path| path.file_stem()?.to_str().map(str::to_string))
+        .unwrap_or_else(|| "driver".to_string())
+}
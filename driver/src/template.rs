@@ -0,0 +1,85 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Templates for the instance and job labels of a target, such as
+//! "{host}:{port}", which are expanded with what is known about the target.
+
+use std::collections::BTreeMap;
+
+use hyper::Uri;
+
+/// Expands each `{name}` in `template` to the value that `variable` returns
+/// for it. `{{` and `}}` stand for literal braces. Fails if a variable is
+/// unknown, or a brace isn't closed.
+pub fn expand(template: &str, variable: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        let brace = rest.as_bytes()[i];
+        rest = &rest[i + 1..];
+        if rest.as_bytes().first() == Some(&brace) {
+            out.push(brace as char);
+            rest = &rest[1..];
+            continue;
+        }
+        if brace == b'}' {
+            return Err(format!("unmatched }} in template {:?}", template));
+        }
+        let Some(end) = rest.find('}') else {
+            return Err(format!("unclosed {{ in template {:?}", template));
+        };
+        let name = &rest[..end];
+        match variable(name) {
+            Some(value) => out.push_str(&value),
+            None => {
+                return Err(format!(
+                    "unknown variable {{{}}} in template {:?}",
+                    name, template
+                ))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Looks up a variable of a scraped target:
+///
+/// * `address`: the host and port, as given in the URL.
+/// * `host`: the host name or IP address.
+/// * `port`: the port, or the default port of the scheme if none was given.
+/// * `scheme`: "http" or "https".
+/// * `path`: the path that is scraped, such as "/metrics".
+/// * Any label of the target's group in a targets file, including the
+///   labels starting with "__" that are not added to samples.
+pub fn target_variable(url: &Uri, labels: &BTreeMap<String, String>, name: &str) -> Option<String> {
+    match name {
+        "address" => url.authority().map(|authority| authority.to_string()),
+        "host" => url.host().map(|host| host.to_string()),
+        "port" => url
+            .port_u16()
+            .or_else(|| match url.scheme_str() {
+                Some("https") => Some(443),
+                Some("http") => Some(80),
+                _ => None,
+            })
+            .map(|port| port.to_string()),
+        "scheme" => url.scheme_str().map(|scheme| scheme.to_string()),
+        "path" => Some(url.path().to_string()),
+        _ => labels.get(name).cloned(),
+    }
+}
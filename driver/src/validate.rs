@@ -0,0 +1,331 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checks on the data quality of scraped samples, made before they are exported.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+use crate::parse::{parse_value, MetricFamily, OwnedLabelSet, SampleType};
+
+/// The standard metric holding the time a target's process started, which
+/// changes when the target is restarted and its counters start over.
+const START_TIME_METRIC: &str = "process_start_time_seconds";
+
+/// What was last seen of a target.
+#[derive(Default)]
+pub struct TargetCounters {
+    start_time: Option<f64>,
+    /// The value of each counter series, keyed by sample name and labels.
+    values: HashMap<(String, OwnedLabelSet), f64>,
+}
+
+/// Warns about counters whose value decreases from one scrape of a target to
+/// the next. A decrease is expected when the target has been restarted, so
+/// it isn't reported if the target's `process_start_time_seconds` changed.
+/// Targets that don't expose it have every decrease reported.
+#[derive(Default)]
+pub struct CounterValidator {
+    /// Keyed by the instance the scrape came from.
+    targets: HashMap<Option<String>, TargetCounters>,
+}
+
+impl CounterValidator {
+    /// Compares the counters of a scrape with those of the last scrape of the
+    /// same instance that was committed. Returns the counters of the scrape, to
+    /// be given to `commit` if the scrape is exported.
+    pub fn check(&self, instance: Option<&str>, families: &[MetricFamily]) -> TargetCounters {
+        let start_time = families
+            .iter()
+            .flat_map(|family| family.samples.iter())
+            .find(|sample| sample.var == START_TIME_METRIC)
+            .and_then(|sample| parse_value(sample.value));
+        let empty = TargetCounters::default();
+        let last = self
+            .targets
+            .get(&instance.map(str::to_string))
+            .unwrap_or(&empty);
+        let restarted = last.start_time.is_some() && start_time != last.start_time;
+        let mut values = HashMap::with_capacity(last.values.len());
+        for family in families {
+            if family.r#type != SampleType::Counter {
+                continue;
+            }
+            for sample in family.samples.iter() {
+                let Some(value) = parse_value(sample.value) else {
+                    continue;
+                };
+                let key = (
+                    sample.var.to_string(),
+                    sample
+                        .labels
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), value.to_string()))
+                        .collect(),
+                );
+                if let Some(&previous) = last.values.get(&key) {
+                    if value < previous && !restarted {
+                        let labels: Vec<String> = key
+                            .1
+                            .iter()
+                            .map(|(name, value)| format!("{}={:?}", name, value))
+                            .collect();
+                        warn!(
+                            "counter {}{{{}}} decreased from {} to {}",
+                            sample.var,
+                            labels.join(","),
+                            previous,
+                            value
+                        );
+                        metrics::COUNTER_ANOMALIES.inc();
+                    }
+                }
+                values.insert(key, value);
+            }
+        }
+        if restarted {
+            debug!("{:?} was restarted, counters may have been reset", instance);
+        }
+        TargetCounters { start_time, values }
+    }
+
+    /// Remembers the counters of a scrape of `instance` for comparison with
+    /// the next one. Series that are missing from the scrape are forgotten.
+    pub fn commit(&mut self, instance: Option<&str>, counters: TargetCounters) {
+        self.targets.insert(instance.map(str::to_string), counters);
+    }
+}
+
+/// Labels that are never dropped by the `CardinalityLimiter`, because the
+/// samples of histograms and summaries can't be understood without them.
+const STRUCTURAL_LABELS: [&str; 2] = ["le", "quantile"];
+
+/// Drops the labels whose number of distinct values grows too quickly, which
+/// usually means that a target has put something unbounded, such as a user or
+/// request ID, in a label.
+///
+/// The values of each label are counted per target and metric family, over a
+/// window of time that starts over when it ends. Each family has its own
+/// window, timed by the limiter's clock rather than by the timestamps of the
+/// scrapes. Once a label has more than `limit` values within a window, it is
+/// dropped from that family's samples for as long as the process runs.
+///
+/// Samples that become identical once the label is gone are dropped, as no
+/// single one of them stands for the series they are merged into, and a
+/// counter made of whichever came first would jump around between scrapes.
+pub struct CardinalityLimiter {
+    limit: usize,
+    window: Duration,
+    /// The label values seen in the current window of each family, keyed by
+    /// the instance and the family name.
+    windows: HashMap<(Option<String>, String), Window>,
+    /// The labels dropped from each family, keyed by the instance and the
+    /// family name.
+    dropped: HashMap<(Option<String>, String), Vec<String>>,
+}
+
+/// The label values of a family seen since `start`.
+struct Window {
+    start: Instant,
+    /// Keyed by label name.
+    values: HashMap<String, HashSet<String>>,
+}
+
+impl CardinalityLimiter {
+    pub fn new(limit: usize, window: Duration) -> CardinalityLimiter {
+        CardinalityLimiter {
+            limit,
+            window,
+            windows: HashMap::new(),
+            dropped: HashMap::new(),
+        }
+    }
+
+    /// Counts the label values of a scrape, and removes the labels that have
+    /// been dropped from its samples.
+    pub fn apply(&mut self, instance: Option<&str>, families: &mut [MetricFamily]) {
+        let now = Instant::now();
+        for family in families.iter_mut() {
+            let Some(name) = family
+                .var
+                .or_else(|| family.samples.first().map(|sample| sample.var))
+            else {
+                continue;
+            };
+            let family_key = (instance.map(str::to_string), name.to_string());
+            let window = self
+                .windows
+                .entry(family_key.clone())
+                .or_insert_with(|| Window {
+                    start: now,
+                    values: HashMap::new(),
+                });
+            if now.duration_since(window.start) >= self.window {
+                window.start = now;
+                window.values.clear();
+            }
+            for sample in family.samples.iter() {
+                for (label, value) in sample.labels.iter() {
+                    if STRUCTURAL_LABELS.contains(label)
+                        || self
+                            .dropped
+                            .get(&family_key)
+                            .is_some_and(|dropped| dropped.iter().any(|d| d == label))
+                    {
+                        continue;
+                    }
+                    let values = window.values.entry(label.to_string()).or_default();
+                    values.insert(value.to_string());
+                    if values.len() > self.limit {
+                        warn!(
+                            "label {} of {} has more than {} values, dropping it",
+                            label, name, self.limit
+                        );
+                        metrics::DROPPED_LABELS.inc();
+                        window.values.remove(*label);
+                        self.dropped
+                            .entry(family_key.clone())
+                            .or_default()
+                            .push(label.to_string());
+                    }
+                }
+            }
+            let Some(dropped) = self.dropped.get(&family_key) else {
+                continue;
+            };
+            for sample in family.samples.iter_mut() {
+                sample
+                    .labels
+                    .retain(|(label, _)| !dropped.iter().any(|d| d == label));
+            }
+            let mut counts: HashMap<_, usize> = HashMap::with_capacity(family.samples.len());
+            for sample in family.samples.iter() {
+                *counts
+                    .entry((sample.var, sample.labels.clone()))
+                    .or_default() += 1;
+            }
+            let before = family.samples.len();
+            family
+                .samples
+                .retain(|sample| counts[&(sample.var, sample.labels.clone())] == 1);
+            if family.samples.len() < before {
+                debug!(
+                    "dropped {} samples of {} that only differed in dropped labels",
+                    before - family.samples.len(),
+                    name
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse;
+
+    #[test]
+    fn uncommitted_counters_are_not_remembered() {
+        let mut validator = CounterValidator::default();
+        let scrapes = [
+            "# TYPE c counter\nc 10\n",
+            "# TYPE c counter\nc 1\n",
+            "# TYPE c counter\nc 20\n",
+        ];
+        let families = parse(None, None, scrapes[0]).unwrap();
+        let counters = validator.check(None, &families);
+        validator.commit(None, counters);
+        // A scrape that is checked but not exported doesn't move the baseline.
+        let families = parse(None, None, scrapes[1]).unwrap();
+        validator.check(None, &families);
+        let families = parse(None, None, scrapes[2]).unwrap();
+        let counters = validator.check(None, &families);
+        assert_eq!(
+            counters.values.values().copied().collect::<Vec<_>>(),
+            vec![20.0]
+        );
+        validator.commit(None, counters);
+        assert_eq!(
+            validator.targets[&None]
+                .values
+                .values()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![20.0]
+        );
+    }
+
+    #[test]
+    fn drops_label_over_limit() {
+        let mut limiter = CardinalityLimiter::new(2, Duration::from_secs(3600));
+        let first = "# TYPE a counter\n\
+                     a{user=\"1\",code=\"200\"} 1\n\
+                     a{user=\"2\",code=\"200\"} 2\n\
+                     # TYPE b gauge\n\
+                     b{user=\"1\"} 1\n";
+        let mut families = parse(None, None, first).unwrap();
+        limiter.apply(Some("x"), &mut families);
+        assert_eq!(families[0].samples.len(), 2);
+        assert_eq!(families[0].samples[0].labels.len(), 2);
+
+        // A third value trips the limit of `a`, but not of `b` or of another
+        // instance, and the samples that now collide are dropped.
+        let exposition = "# TYPE a counter\n\
+                          a{user=\"3\",code=\"200\"} 3\n\
+                          a{user=\"4\",code=\"200\"} 4\n\
+                          a{user=\"5\",code=\"500\"} 5\n\
+                          # TYPE b gauge\n\
+                          b{user=\"2\"} 1\n";
+        let mut families = parse(None, None, exposition).unwrap();
+        limiter.apply(Some("x"), &mut families);
+        let a: Vec<_> = families[0]
+            .samples
+            .iter()
+            .map(|sample| (sample.labels.clone(), sample.value))
+            .collect();
+        assert_eq!(a, vec![(vec![("code", "500".into())], "5")]);
+        assert_eq!(families[1].samples[0].labels, vec![("user", "2".into())]);
+
+        let mut families = parse(None, None, first).unwrap();
+        limiter.apply(Some("y"), &mut families);
+        assert_eq!(families[0].samples.len(), 2);
+        assert_eq!(families[0].samples[0].labels.len(), 2);
+    }
+
+    #[test]
+    fn window_starts_over() {
+        let mut limiter = CardinalityLimiter::new(1, Duration::ZERO);
+        for user in 0..3 {
+            let exposition = format!("a{{user=\"{}\"}} 1\n", user);
+            let mut families = parse(None, None, &exposition).unwrap();
+            limiter.apply(None, &mut families);
+            assert_eq!(families[0].samples[0].labels.len(), 1);
+        }
+    }
+
+    #[test]
+    fn structural_labels_are_kept() {
+        let mut limiter = CardinalityLimiter::new(1, Duration::from_secs(3600));
+        let exposition = "# TYPE h histogram\n\
+                          h_bucket{le=\"1\"} 1\n\
+                          h_bucket{le=\"+Inf\"} 2\n\
+                          h_sum 3\n\
+                          h_count 2\n";
+        let mut families = parse(None, None, exposition).unwrap();
+        limiter.apply(None, &mut families);
+        assert_eq!(families[0].samples.len(), 4);
+    }
+}
@@ -0,0 +1,54 @@
+// Settings loaded from a configuration file.
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+
+use driver::DriverConfig;
+use serde::Deserialize;
+
+/// Settings read from a TOML configuration file. Each setting is optional and
+/// is named after the corresponding command-line flag, with underscores in
+/// place of dashes. Settings given on the command line take precedence.
+#[derive(Default, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub driver: DriverConfig,
+
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub token: Option<String>,
+    pub write_retries: Option<u32>,
+    pub output: Option<String>,
+    /// Settings that neither the binary nor the driver knows of.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("unable to read {}: {}", path, err))?;
+        let config: Config =
+            toml::from_str(&text).map_err(|err| format!("unable to parse {}: {}", path, err))?;
+        match config.unknown.keys().next() {
+            Some(name) => Err(format!(
+                "unable to parse {}: unknown setting {:?}",
+                path, name
+            )),
+            None => Ok(config),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt::Write;
+
+use bytes::Bytes;
+use driver::fetch::Client;
+use driver::parse::{parse_value, MetricFamily, Sample};
+use driver::push::Pusher;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::Uri;
+
+/// The most lines sent in a single write. InfluxDB recommends batches of 5000 lines.
+const MAX_BATCH_LINES: usize = 5000;
+
+/// Builds the URL of the v2 write endpoint of the InfluxDB server at `server`.
+/// Timestamps are written with nanosecond precision.
+pub fn write_url(server: &str, org: &str, bucket: &str) -> Result<Uri, String> {
+    format!(
+        "{}/api/v2/write?org={}&bucket={}&precision=ns",
+        server.trim_end_matches('/'),
+        percent_encode(org),
+        percent_encode(bucket)
+    )
+    .parse()
+    .map_err(|err| format!("{}: {}", server, err))
+}
+
+/// Writes samples to InfluxDB in its line protocol. The samples of each scrape
+/// are sent together, in the background.
+pub struct InfluxExporter {
+    /// The lines of the scrape being exported.
+    batch: String,
+    lines: usize,
+    pusher: Pusher,
+}
+
+impl InfluxExporter {
+    /// Creates an exporter that writes to `url` using `client`, authenticating
+    /// with `token` if given.
+    pub fn new(client: Client, url: Uri, token: Option<&str>) -> Result<Self, String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        if let Some(token) = token {
+            let mut value = HeaderValue::from_str(&format!("Token {}", token))
+                .map_err(|err| format!("invalid token: {}", err))?;
+            value.set_sensitive(true);
+            headers.insert(header::AUTHORIZATION, value);
+        }
+        Ok(InfluxExporter {
+            batch: String::new(),
+            lines: 0,
+            pusher: Pusher::new("InfluxDB", "influxdb", client, url, headers),
+        })
+    }
+
+    /// Hands the current batch to the writer thread.
+    fn flush(&mut self) -> bool {
+        if self.lines == 0 {
+            return true;
+        }
+        let batch = std::mem::take(&mut self.batch);
+        self.lines = 0;
+        self.pusher.push(Bytes::from(batch))
+    }
+
+    /// Appends a sample to the batch, in line protocol.
+    fn append(&mut self, timestamp_millis: u64, sample: &Sample) {
+        let value = match parse_value(sample.value) {
+            Some(value) if value.is_finite() => value,
+            // InfluxDB can't store NaN or infinite values.
+            Some(_) => {
+                debug!("skipping non-finite sample {}", sample.var);
+                return;
+            }
+            None => {
+                driver::unparseable_sample(sample);
+                return;
+            }
+        };
+        let timestamp_millis = match sample.timestamp.map(u64::try_from) {
+            Some(Ok(timestamp_millis)) => timestamp_millis,
+            Some(Err(_)) => {
+                warn!(
+                    "skipping sample {} with a timestamp before the epoch",
+                    sample.var
+                );
+                return;
+            }
+            None => timestamp_millis,
+        };
+        escape(&mut self.batch, sample.var, &[',', ' ']);
+        let mut labels: Vec<_> = sample
+            .labels
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        // InfluxDB works best when tags are sorted by key.
+        labels.sort();
+        for (label, value) in labels {
+            self.batch.push(',');
+            escape(&mut self.batch, label, &[',', '=', ' ']);
+            self.batch.push('=');
+            escape(&mut self.batch, value, &[',', '=', ' ']);
+        }
+        let _ = writeln!(
+            self.batch,
+            " value={} {}",
+            value,
+            timestamp_millis as u128 * 1_000_000
+        );
+        self.lines += 1;
+    }
+}
+
+impl driver::Exporter for InfluxExporter {
+    fn name(&self) -> &'static str {
+        "influxdb"
+    }
+
+    fn export(&mut self, timestamp_millis: u64, family: &MetricFamily) -> bool {
+        let mut ok = true;
+        for sample in family.samples.iter() {
+            if self.lines >= MAX_BATCH_LINES {
+                ok &= self.flush();
+            }
+            self.append(timestamp_millis, sample);
+        }
+        ok
+    }
+
+    fn flush(&mut self) {
+        self.flush();
+    }
+
+    fn end_scrape(&mut self) {
+        if !self.flush() {
+            driver::export_failed(self.name());
+        }
+    }
+
+    fn close(&mut self) {
+        self.flush();
+        self.pusher.close();
+    }
+}
+
+/// Appends `s` to `out`, escaping the given special characters with a backslash.
+/// Newlines can't be escaped in line protocol, so they are written as "\n".
+fn escape(out: &mut String, s: &str, special: &[char]) {
+    for c in s.chars() {
+        if c == '\n' {
+            out.push_str("\\n");
+            continue;
+        }
+        if special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+/// Percent-encodes `s` for use in a URL query.
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use driver::Exporter;
+    use std::io::{BufRead, BufReader, Read, Write as _};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    /// Answers `requests` POSTs on `listener`, returning their bodies.
+    fn receive(listener: TcpListener, requests: usize) -> JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let mut bodies = Vec::new();
+            while bodies.len() < requests {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while bodies.len() < requests && reader.read_line(&mut line).unwrap() > 0 {
+                    let mut length = 0;
+                    loop {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                length = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    bodies.push(String::from_utf8(body).unwrap());
+                    stream
+                        .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                        .unwrap();
+                    line.clear();
+                }
+            }
+            bodies
+        })
+    }
+
+    fn open(listener: &TcpListener) -> InfluxExporter {
+        let server = format!("http://{}", listener.local_addr().unwrap());
+        let url = write_url(&server, "org", "bucket").unwrap();
+        InfluxExporter::new(Client::default(), url, Some("token")).unwrap()
+    }
+
+    #[test]
+    fn escapes_line_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut exporter = open(&listener);
+        let input = "# TYPE m gauge\n\
+                     m{path=\"/a b,c=d\",empty=\"\",note=\"x\\ny\"} 1.5 1000\n";
+        let families = driver::parse::parse(None, None, input).unwrap();
+        assert!(exporter.export(2000, &families[0]));
+        assert_eq!(
+            exporter.batch,
+            "m,note=x\\ny,path=/a\\ b\\,c\\=d value=1.5 1000000000\n"
+        );
+        let mut name = String::new();
+        escape(&mut name, "a b,c", &[',', ' ']);
+        assert_eq!(name, "a\\ b\\,c");
+    }
+
+    #[test]
+    fn skips_timestamps_before_epoch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut exporter = open(&listener);
+        let families = driver::parse::parse(None, None, "m 1 -5\nm{a=\"b\"} 2\n").unwrap();
+        assert!(exporter.export(2000, &families[0]));
+        assert_eq!(exporter.batch, "m,a=b value=2 2000000000\n");
+    }
+
+    #[test]
+    fn sends_batch_at_line_limit_and_end_of_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut exporter = open(&listener);
+        let server = receive(listener, 2);
+        let mut input = String::from("# TYPE m gauge\n");
+        for series in 0..=MAX_BATCH_LINES {
+            input.push_str(&format!("m{{series=\"{}\"}} 1\n", series));
+        }
+        let families = driver::parse::parse(None, None, &input).unwrap();
+        assert!(exporter.export(1000, &families[0]));
+        assert_eq!(exporter.lines, 1);
+        exporter.end_scrape();
+        assert_eq!(exporter.lines, 0);
+        exporter.close();
+        let lines: Vec<usize> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|body| body.lines().count())
+            .collect();
+        assert_eq!(lines, vec![MAX_BATCH_LINES, 1]);
+    }
+}
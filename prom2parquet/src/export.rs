@@ -13,27 +13,57 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use arrow::array::*;
 use arrow::datatypes::{DataType, Field, Fields, Schema, TimeUnit};
-use driver::parse::{MetricFamily, Sample};
+use chrono::DateTime;
+use driver::parse::{
+    parse_value, HistogramSeries, LabelSet, MetricFamily, OwnedLabelSet, Sample, SampleType,
+    SummarySeries,
+};
 use parquet::arrow::ArrowWriter;
 use parquet::basic::Compression;
 use parquet::file::properties::WriterProperties;
 
+/// Map builder used for histogram buckets and summary quantiles.
+type DistributionBuilder = MapBuilder<Float64Builder, Float64Builder>;
+
 struct RecordBatchBuilder {
     pub schema: Arc<Schema>,
     name_builder: StringBuilder,
+    unit_builder: StringBuilder,
     labels_builder: MapBuilder<StringBuilder, StringBuilder>,
     timestamp_builder: TimestampMillisecondBuilder,
     value_builder: Float64Builder,
+    buckets_builder: DistributionBuilder,
+    quantiles_builder: DistributionBuilder,
+    sum_builder: Float64Builder,
+    count_builder: Float64Builder,
+    reset_builder: BooleanBuilder,
+    /// The labels written to columns of their own, rather than to the map.
+    promoted_labels: Vec<String>,
+    promoted_builders: Vec<StringBuilder>,
+}
+
+/// The type of a map column from `f64` keys to `f64` values.
+fn distribution_type() -> DataType {
+    let kv_struct = DataType::Struct(Fields::from(vec![
+        Field::new("keys", DataType::Float64, false),
+        Field::new("values", DataType::Float64, true),
+    ]));
+    DataType::Map(Arc::new(Field::new("entries", kv_struct, false)), false)
 }
 
 impl RecordBatchBuilder {
-    fn new() -> Self {
+    /// Creates a builder that writes each of `promoted_labels` to a nullable
+    /// column named after it. Fails if one is named like another column.
+    fn new(promoted_labels: Vec<String>) -> Result<Self, String> {
         // Define schema
         let timestamp_field = Field::new(
             "timestamp",
@@ -47,127 +77,585 @@ impl RecordBatchBuilder {
         ]));
 
         let var_field = Field::new("metric", DataType::Utf8, false);
+        let unit_field = Field::new("unit", DataType::Utf8, true);
         let labels_field = Field::new(
             "labels",
             DataType::Map(Arc::new(Field::new("entries", kv_struct, false)), false),
             false,
         );
         let scalar_field = Field::new("scalar", DataType::Float64, true);
+        // Histograms and summaries are stored one row per series, with the
+        // buckets or quantiles in a map. These are null for other metric types.
+        let buckets_field = Field::new("buckets", distribution_type(), true);
+        let quantiles_field = Field::new("quantiles", distribution_type(), true);
+        let sum_field = Field::new("sum", DataType::Float64, true);
+        let count_field = Field::new("count", DataType::Float64, true);
+        // Whether a counter was reset, if resets are being detected.
+        let reset_field = Field::new("reset", DataType::Boolean, true);
 
-        let schema = Arc::new(Schema::new(vec![
+        let mut fields = vec![
             timestamp_field,
             var_field,
+            unit_field,
             labels_field,
             scalar_field,
-        ]));
+            buckets_field,
+            quantiles_field,
+            sum_field,
+            count_field,
+            reset_field,
+        ];
+        for label in promoted_labels.iter() {
+            if fields.iter().any(|field| field.name() == label) {
+                return Err(format!(
+                    "can't promote label {:?}, which is already a column",
+                    label
+                ));
+            }
+            fields.push(Field::new(label, DataType::Utf8, true));
+        }
+        let schema = Arc::new(Schema::new(fields));
 
         let name_builder = StringBuilder::new();
         let labels_builder = MapBuilder::new(None, StringBuilder::new(), StringBuilder::new());
         let timestamp_builder = TimestampMillisecondBuilder::new().with_timezone("UTC");
         let value_builder = Float64Builder::new();
+        let buckets_builder = MapBuilder::new(None, Float64Builder::new(), Float64Builder::new());
+        let quantiles_builder = MapBuilder::new(None, Float64Builder::new(), Float64Builder::new());
 
-        Self {
+        Ok(Self {
             schema,
             name_builder,
+            unit_builder: StringBuilder::new(),
             labels_builder,
             timestamp_builder,
             value_builder,
-        }
+            buckets_builder,
+            quantiles_builder,
+            sum_builder: Float64Builder::new(),
+            count_builder: Float64Builder::new(),
+            reset_builder: BooleanBuilder::new(),
+            promoted_builders: promoted_labels
+                .iter()
+                .map(|_| StringBuilder::new())
+                .collect(),
+            promoted_labels,
+        })
     }
 
-    fn append_scalar(&mut self, timestamp: i64, sample: &Sample) -> bool {
+    /// Appends the columns shared by all metric types.
+    fn append_series(&mut self, timestamp: i64, name: &str, unit: Option<&str>, labels: &LabelSet) {
         self.timestamp_builder.append_value(timestamp);
-        self.name_builder.append_value(sample.var);
-        for (key, value) in sample.labels.iter() {
+        self.name_builder.append_value(name);
+        self.unit_builder.append_option(unit);
+        for (label, builder) in self
+            .promoted_labels
+            .iter()
+            .zip(self.promoted_builders.iter_mut())
+        {
+            builder.append_option(
+                labels
+                    .iter()
+                    .find(|(key, _)| key == label)
+                    .map(|(_, value)| value),
+            );
+        }
+        for (key, value) in labels.iter() {
+            if self.promoted_labels.iter().any(|label| label == key) {
+                continue;
+            }
             self.labels_builder.keys().append_value(key);
             self.labels_builder.values().append_value(value);
         }
         self.labels_builder.append(true).unwrap();
-        match sample.value.parse::<f64>() {
-            Ok(value) => {
-                self.value_builder.append_value(value);
-                true
-            }
-            Err(err) => {
-                error!("unable to parse scalar value {:?}: {}", sample.value, err);
-                self.value_builder.append_null();
-                false
-            }
+    }
+
+    fn append_scalar(
+        &mut self,
+        timestamp: i64,
+        unit: Option<&str>,
+        sample: &Sample,
+        reset: Option<bool>,
+    ) {
+        self.append_series(
+            sample.timestamp.unwrap_or(timestamp),
+            sample.var,
+            unit,
+            &sample.labels,
+        );
+        self.buckets_builder.append(false).unwrap();
+        self.quantiles_builder.append(false).unwrap();
+        self.sum_builder.append_null();
+        self.count_builder.append_null();
+        self.reset_builder.append_option(reset);
+        let value = parse_value(sample.value);
+        if value.is_none() {
+            // The row is kept without a value, so the rest of the family is
+            // still written.
+            driver::unparseable_sample(sample);
         }
+        self.value_builder.append_option(value);
+    }
+
+    fn append_histogram(
+        &mut self,
+        timestamp: i64,
+        name: &str,
+        unit: Option<&str>,
+        histogram: &HistogramSeries,
+    ) {
+        self.append_series(
+            histogram.timestamp.unwrap_or(timestamp),
+            name,
+            unit,
+            &histogram.labels,
+        );
+        self.value_builder.append_null();
+        for &(le, count) in histogram.buckets.iter() {
+            self.buckets_builder.keys().append_value(le);
+            self.buckets_builder.values().append_value(count);
+        }
+        self.buckets_builder.append(true).unwrap();
+        self.quantiles_builder.append(false).unwrap();
+        self.sum_builder.append_option(histogram.sum);
+        self.count_builder.append_option(histogram.count);
+        self.reset_builder.append_null();
+    }
+
+    fn append_summary(
+        &mut self,
+        timestamp: i64,
+        name: &str,
+        unit: Option<&str>,
+        summary: &SummarySeries,
+    ) {
+        self.append_series(
+            summary.timestamp.unwrap_or(timestamp),
+            name,
+            unit,
+            &summary.labels,
+        );
+        self.value_builder.append_null();
+        self.buckets_builder.append(false).unwrap();
+        for &(quantile, value) in summary.quantiles.iter() {
+            self.quantiles_builder.keys().append_value(quantile);
+            self.quantiles_builder.values().append_value(value);
+        }
+        self.quantiles_builder.append(true).unwrap();
+        self.sum_builder.append_option(summary.sum);
+        self.count_builder.append_option(summary.count);
+        self.reset_builder.append_null();
+    }
+
+    /// The number of rows appended since the last call to `finish()`.
+    fn len(&self) -> usize {
+        self.timestamp_builder.len()
     }
 
     fn finish(&mut self) -> RecordBatch {
         let timestamp = self.timestamp_builder.finish();
         let name = self.name_builder.finish();
+        let unit = self.unit_builder.finish();
         let labels = self.labels_builder.finish();
         let value = self.value_builder.finish();
+        let buckets = self.buckets_builder.finish();
+        let quantiles = self.quantiles_builder.finish();
+        let sum = self.sum_builder.finish();
+        let count = self.count_builder.finish();
+        let reset = self.reset_builder.finish();
 
-        RecordBatch::try_new(
-            self.schema.clone(),
-            vec![
-                Arc::new(timestamp),
-                Arc::new(name),
-                Arc::new(labels),
-                Arc::new(value),
-            ],
-        )
-        .unwrap()
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(timestamp),
+            Arc::new(name),
+            Arc::new(unit),
+            Arc::new(labels),
+            Arc::new(value),
+            Arc::new(buckets),
+            Arc::new(quantiles),
+            Arc::new(sum),
+            Arc::new(count),
+            Arc::new(reset),
+        ];
+        for builder in self.promoted_builders.iter_mut() {
+            columns.push(Arc::new(builder.finish()));
+        }
+        RecordBatch::try_new(self.schema.clone(), columns).unwrap()
+    }
+}
+
+/// How output is split into files by sample timestamp.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Partition {
+    /// One file per hour, named `YYYY/MM/DD/HH.parquet`.
+    Hourly,
+    /// One file per day, named `YYYY/MM/DD.parquet`.
+    Daily,
+}
+
+impl Partition {
+    fn length_millis(self) -> i64 {
+        match self {
+            Partition::Hourly => 60 * 60 * 1000,
+            Partition::Daily => 24 * 60 * 60 * 1000,
+        }
+    }
+
+    /// The path of the partition starting at `start_millis`, relative to the output directory.
+    fn path(self, start_millis: i64) -> Option<String> {
+        let start = DateTime::from_timestamp_millis(start_millis)?;
+        Some(match self {
+            Partition::Hourly => start.format("%Y/%m/%d/%H").to_string(),
+            Partition::Daily => start.format("%Y/%m/%d").to_string(),
+        })
     }
 }
 
 pub struct ParquetExporter {
-    writer: ArrowWriter<File>,
+    output: PathBuf,
+    partition: Option<Partition>,
+    props: WriterProperties,
+    /// The time range covered by the current partition's file.
+    partition_range: Range<i64>,
+    /// The writer for the current file, or `None` if no file is open.
+    writer: Option<ArrowWriter<File>>,
     builder: RecordBatchBuilder,
+    row_group_size: usize,
+    /// The bits of the last value written for each scalar series, if deduplicating.
+    last_values: Option<HashMap<(String, OwnedLabelSet), u64>>,
+    /// The last value seen for each counter series, if detecting resets.
+    last_counters: Option<HashMap<(String, OwnedLabelSet), f64>>,
 }
 
 impl ParquetExporter {
-    pub fn new(path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let builder = RecordBatchBuilder::new();
-
-        let file = std::fs::File::create(path)?;
+    /// Creates a new exporter writing to `output`. Samples are accumulated across
+    /// scrapes and written out in row groups of `row_group_size` rows.
+    ///
+    /// If `partition` is given, `output` is a directory, and a new file is
+    /// started within it whenever a sample falls outside the current partition.
+    /// Otherwise, if `append` is true, `output` is a directory holding a dataset
+    /// of numbered files, and a new one is added to it. If neither is given,
+    /// `output` is a single file, which is overwritten if it exists.
+    ///
+    /// Each of `promoted_labels` is written to a column of its own, which is
+    /// null for samples without the label, and left out of the `labels` map.
+    pub fn new(
+        output: &str,
+        row_group_size: usize,
+        compression: Compression,
+        partition: Option<Partition>,
+        append: bool,
+        promoted_labels: Vec<String>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
         let props = WriterProperties::builder()
-            .set_compression(Compression::UNCOMPRESSED)
+            .set_compression(compression)
+            .set_max_row_group_size(row_group_size)
             .build();
-        let writer = ArrowWriter::try_new(file, builder.schema.clone(), Some(props))?;
-        Ok(Self { writer, builder })
+        let mut exporter = Self {
+            output: PathBuf::from(output),
+            partition,
+            props,
+            partition_range: 0..0,
+            writer: None,
+            builder: RecordBatchBuilder::new(promoted_labels)?,
+            row_group_size,
+            last_values: None,
+            last_counters: None,
+        };
+        match partition {
+            Some(_) => std::fs::create_dir_all(output)?,
+            None if append => {
+                std::fs::create_dir_all(output)?;
+                let path = next_dataset_file(&exporter.output)?;
+                info!("writing to {}", path.display());
+                exporter.open(path)?;
+            }
+            None => exporter.open(PathBuf::from(output))?,
+        }
+        Ok(exporter)
     }
-}
 
-impl driver::Exporter for ParquetExporter {
-    fn export(&mut self, timestamp_millis: u64, family: &MetricFamily) -> bool {
-        for sample in family.samples.iter() {
-            if !self.builder.append_scalar(timestamp_millis as i64, sample) {
+    fn open(&mut self, path: PathBuf) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let file = File::create(&path)?;
+        let writer =
+            ArrowWriter::try_new(file, self.builder.schema.clone(), Some(self.props.clone()))?;
+        self.writer = Some(writer);
+        Ok(())
+    }
+
+    /// Makes sure that a sample with the given timestamp will be written to the
+    /// right partition, closing the current file and starting a new one if needed.
+    fn roll(&mut self, timestamp_millis: i64) -> bool {
+        let Some(partition) = self.partition else {
+            return true;
+        };
+        if self.partition_range.contains(&timestamp_millis) {
+            return true;
+        }
+        let start = timestamp_millis - timestamp_millis.rem_euclid(partition.length_millis());
+        let Some(name) = partition.path(start) else {
+            error!("timestamp {} is out of range", timestamp_millis);
+            return false;
+        };
+        if !self.flush() {
+            return false;
+        }
+        self.close_writer();
+        // A backlog may revisit a partition that already has a file, which
+        // we can't append to. Start a new file alongside it instead.
+        let mut path = self.output.join(format!("{}.parquet", name));
+        let mut n = 0;
+        while path.exists() {
+            n += 1;
+            path = self.output.join(format!("{}.{}.parquet", name, n));
+        }
+        if let Some(dir) = path.parent() {
+            if let Err(err) = std::fs::create_dir_all(dir) {
+                error!("unable to create directory {}: {}", dir.display(), err);
                 return false;
             }
         }
+        info!("writing to {}", path.display());
+        if let Err(err) = self.open(path) {
+            error!("unable to open partition: {}", err);
+            return false;
+        }
+        self.partition_range = start..start + partition.length_millis();
+        // Each file should be readable on its own, so start it with a row for
+        // every series.
+        if let Some(last_values) = self.last_values.as_mut() {
+            last_values.clear();
+        }
+        true
+    }
+
+    /// Skips writing a scalar sample when its value is identical to the last one
+    /// written for the same series. A series then holds a row only when its value
+    /// changes, and readers should carry each value forward until the next row.
+    pub fn enable_dedup(&mut self) {
+        self.last_values = Some(HashMap::new());
+    }
+
+    /// Records whether each counter sample is a reset, which is when its value is
+    /// lower than the last one seen for its series, in the `reset` column.
+    pub fn enable_reset_detection(&mut self) {
+        self.last_counters = Some(HashMap::new());
+    }
+
+    /// Whether the counter `sample` is lower than the last value seen for its
+    /// series, or `None` if resets aren't being detected.
+    fn is_reset(&mut self, sample: &Sample) -> Option<bool> {
+        let (last_counters, value) = (self.last_counters.as_mut()?, parse_value(sample.value)?);
+        let labels = sample
+            .labels
+            .iter()
+            .map(|(label, value)| (label.to_string(), value.to_string()))
+            .collect();
+        Some(
+            last_counters
+                .insert((sample.var.to_string(), labels), value)
+                .is_some_and(|last| value < last),
+        )
+    }
+
+    /// Whether `sample` has the same value as the last one written for its
+    /// series, if deduplicating. Otherwise its value is remembered as the last.
+    fn is_unchanged(&mut self, sample: &Sample) -> bool {
+        let (Some(last_values), Some(value)) =
+            (self.last_values.as_mut(), parse_value(sample.value))
+        else {
+            return false;
+        };
+        let labels = sample
+            .labels
+            .iter()
+            .map(|(label, value)| (label.to_string(), value.to_string()))
+            .collect();
+        last_values.insert((sample.var.to_string(), labels), value.to_bits())
+            == Some(value.to_bits())
+    }
+
+    /// Writes any accumulated samples out as a row group.
+    fn flush(&mut self) -> bool {
+        if self.builder.len() == 0 {
+            return true;
+        }
+        let Some(writer) = self.writer.as_mut() else {
+            error!("unable to write samples: Parquet writer already closed");
+            return false;
+        };
         let record_batch = self.builder.finish();
-        match self.writer.write(&record_batch) {
-            Ok(_) => true,
-            Err(err) => {
-                error!("unable to write record batch: {}", err);
-                false
+        if let Err(err) = writer.write(&record_batch) {
+            error!("unable to write record batch: {}", err);
+            return false;
+        }
+        if let Err(err) = writer.flush() {
+            error!("unable to flush row group: {}", err);
+            return false;
+        }
+        true
+    }
+
+    fn close_writer(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            if let Err(err) = writer.close() {
+                error!("unable to close Parquet writer: {}", err);
+            }
+        }
+    }
+}
+
+/// The path of the next file to add to a dataset in `dir`. Files are named
+/// "part-00000.parquet", "part-00001.parquet" and so on, numbered after the
+/// highest one already there, so they sort in the order they were written.
+fn next_dataset_file(dir: &Path) -> std::io::Result<PathBuf> {
+    let mut next = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("part-"))
+            .and_then(|name| name.strip_suffix(".parquet"))
+            .and_then(|number| number.parse::<u64>().ok());
+        if let Some(number) = number {
+            next = next.max(number + 1);
+        }
+    }
+    Ok(dir.join(format!("part-{:05}.parquet", next)))
+}
+
+impl driver::Exporter for ParquetExporter {
+    fn name(&self) -> &'static str {
+        "parquet"
+    }
+
+    fn export(&mut self, timestamp_millis: u64, family: &MetricFamily) -> bool {
+        let timestamp = timestamp_millis as i64;
+        match family.r#type {
+            SampleType::Counter | SampleType::Gauge | SampleType::Untyped => {
+                for sample in family.samples.iter() {
+                    if !self.roll(sample.timestamp.unwrap_or(timestamp)) {
+                        return false;
+                    }
+                    let reset = match family.r#type {
+                        SampleType::Counter => self.is_reset(sample),
+                        _ => None,
+                    };
+                    if self.is_unchanged(sample) {
+                        continue;
+                    }
+                    self.builder
+                        .append_scalar(timestamp, family.unit, sample, reset);
+                }
+            }
+            SampleType::Histogram => {
+                let (Some(name), Some(histograms)) = (family.var, family.as_histogram()) else {
+                    error!("unable to decode histogram {:?}", family.var);
+                    return false;
+                };
+                for histogram in histograms.iter() {
+                    if !self.roll(histogram.timestamp.unwrap_or(timestamp)) {
+                        return false;
+                    }
+                    self.builder
+                        .append_histogram(timestamp, name, family.unit, histogram);
+                }
+            }
+            SampleType::Summary => {
+                let (Some(name), Some(summaries)) = (family.var, family.as_summary()) else {
+                    error!("unable to decode summary {:?}", family.var);
+                    return false;
+                };
+                for summary in summaries.iter() {
+                    if !self.roll(summary.timestamp.unwrap_or(timestamp)) {
+                        return false;
+                    }
+                    self.builder
+                        .append_summary(timestamp, name, family.unit, summary);
+                }
             }
         }
+        if self.builder.len() >= self.row_group_size {
+            return self.flush();
+        }
+        true
+    }
+
+    fn flush(&mut self) {
+        self.flush();
     }
 
     fn close(&mut self) {
-        // This is *seriously* hacky, but it's the only way to close the ArrowWriter 
-        // behind a mutable reference. 
-        // TODO: Keep working on this, and try to find a better way to finalize writes at the end.
-        let new_writer = match ArrowWriter::try_new(
-            File::open("/dev/null").unwrap(),
-            self.builder.schema.clone(),
+        self.flush();
+        self.close_writer();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use driver::Exporter;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    /// Creates an empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("parquet-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn writes_row_group_once_full() {
+        let dir = test_dir("row_group");
+        let path = dir.join("out.parquet");
+        let mut exporter = ParquetExporter::new(
+            path.to_str().unwrap(),
+            3,
+            Compression::UNCOMPRESSED,
             None,
-        ) {
-            Ok(writer) => writer,
-            Err(err) => {
-                error!("unable to create dummy Parquet writer: {}", err);
-                return;
-            }
-        };
-        let old_writer = std::mem::replace(&mut self.writer, new_writer);
-        if let Err(err) = old_writer.close() {
-            error!("unable to close Parquet writer: {}", err);
+            false,
+            Vec::new(),
+        )
+        .unwrap();
+        let input = "# TYPE a gauge\na{x=\"1\"} 1\na{x=\"2\"} 2\n\
+                     # TYPE b gauge\nb 1\nb{x=\"1\"} 2\n\
+                     # TYPE c gauge\nc 1\n";
+        let families = driver::parse::parse(None, None, input).unwrap();
+        assert!(exporter.export(1000, &families[0]));
+        assert_eq!(exporter.builder.len(), 2);
+        // The row group is written once it holds at least `row_group_size` rows.
+        assert!(exporter.export(1000, &families[1]));
+        assert_eq!(exporter.builder.len(), 0);
+        assert!(exporter.export(1000, &families[2]));
+        exporter.close();
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        let row_groups: Vec<i64> = reader
+            .metadata()
+            .row_groups()
+            .iter()
+            .map(|row_group| row_group.num_rows())
+            .collect();
+        // The writer caps each row group at `row_group_size` rows, so the first
+        // batch spans two of them.
+        assert_eq!(row_groups, vec![3, 1, 1]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn numbers_dataset_files_after_highest() {
+        let dir = test_dir("dataset");
+        assert_eq!(
+            next_dataset_file(&dir).unwrap(),
+            dir.join("part-00000.parquet")
+        );
+        for name in ["part-00003.parquet", "part-x.parquet", "notes.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
         }
+        assert_eq!(
+            next_dataset_file(&dir).unwrap(),
+            dir.join("part-00004.parquet")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
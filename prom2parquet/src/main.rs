@@ -17,92 +17,207 @@
 #[macro_use]
 extern crate log;
 
+mod config;
 mod export;
 
 use std::process::ExitCode;
-use std::time::Duration;
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use config::Config;
 use env_logger::Env;
+use parquet::basic::{Compression, GzipLevel, ZstdLevel};
 
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// The IP address to listen on for connections.
-    /// Only needed when running as a server.
-    #[arg(long, default_value = "127.0.0.1")]
-    host: String,
-
-    /// The port number to use.
-    /// Only needed when running as a server.
-    #[arg(short, long, default_value_t = 8080)]
-    port: u16,
-
-    // The instance label to use for all samples.
-    // If not provided, the address of the source URL will be used.
+    #[command(flatten)]
+    driver: driver::DriverArgs,
+
+    /// How many samples to accumulate before writing them out as a row group.
+    /// Larger row groups compress better and are faster to read.
+    #[arg(long, default_value_t = 100_000, value_parser = clap::value_parser!(u64).range(1..))]
+    row_group_size: u64,
+
+    /// The compression codec used for the Parquet file.
+    #[arg(long, value_enum, default_value_t = Codec::Uncompressed)]
+    compression: Codec,
+
+    /// Split the output into one file per hour or day, based on sample timestamps.
+    /// The output is then a directory, holding files named like "YYYY/MM/DD/HH.parquet".
+    #[arg(long, value_enum)]
+    partition: Option<export::Partition>,
+
+    /// Add to a dataset instead of overwriting the output. The output is then a
+    /// directory, and each run writes a new file in it, named like
+    /// "part-00001.parquet". Readers should treat all the files in the
+    /// directory as one table. Partitioned output is always added to.
     #[arg(long)]
-    instance: Option<String>,
+    append: bool,
 
-    // The job label to use for all samples.
-    // If not provided, the address of the source URL will be used.
-    #[arg(long)]
-    job: Option<String>,
-
-    /// How often metrics will be scraped, in seconds.
-    #[arg(short, long, default_value_t = 5)]
-    interval: u64,
+    /// Write the value of this label to a column of its own, named after the
+    /// label, instead of to the labels map. Queries that filter or group by the
+    /// label are then faster. May be repeated to promote several labels.
+    #[arg(long, value_name = "NAME")]
+    promote_label: Vec<String>,
 
-    /// How many scrapes to hold in memory before dropping samples.
-    #[arg(short, long, default_value_t = 5)]
-    buffer: usize,
+    /// Only write a sample when its value differs from the last one written for
+    /// the same series. Histograms and summaries are always written.
+    #[arg(long)]
+    dedup: bool,
 
-    /// The URL of a Prometheus client endpoint to scrape.
-    /// If "-", then read from stdin.
-    target: String,
+    /// Record when a counter resets, which is when its value is lower than the
+    /// last one seen for the same series, usually because the target restarted.
+    #[arg(long)]
+    detect_resets: bool,
 
     /// The path to the Parquet file to store metrics.
-    output: String,
+    /// If partitioning or appending, the directory to hold the Parquet files.
+    output: Option<String>,
 }
 
-impl driver::Args for Args {
-    fn addr(&self) -> (&str, u16) {
-        (self.host.as_str(), self.port)
+impl Args {
+    /// Parses the command line, and then reads the configuration file if one was given.
+    fn load() -> Result<Args, String> {
+        let matches = Args::command().get_matches();
+        let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        args.driver.set_command_line(&matches);
+        if let Some(path) = &args.driver.config {
+            args.apply_config(Config::load(path)?)?;
+        }
+        // Targets may all come from the targets file, leaving only the output.
+        // Either may be set in the configuration, so this is checked after it.
+        if args.driver.targets_file.is_some() && args.output.is_none() {
+            if let Some(output) = args.driver.target.take() {
+                args.output = Some(output);
+                args.driver.command_line.remove("target");
+                args.driver.command_line.insert("output".to_string());
+            }
+        }
+        if driver::Args::targets(&args).is_empty() && args.driver.targets_file.is_none() {
+            return Err("no target given on the command line or in the configuration".into());
+        }
+        if !args.driver.dry_run && args.output.is_none() {
+            return Err("no output given on the command line or in the configuration".into());
+        }
+        Ok(args)
     }
 
-    fn instance(&self) -> Option<&str> {
-        self.instance.as_deref()
+    /// Applies the settings from a configuration file,
+    /// except for those that were given on the command line.
+    fn apply_config(&mut self, config: Config) -> Result<(), String> {
+        self.driver.apply_config(config.driver)?;
+        macro_rules! apply {
+            ($field:ident) => {
+                if let Some(value) = config.$field {
+                    if !self.driver.command_line.contains(stringify!($field)) {
+                        self.$field = value.into();
+                    }
+                }
+            };
+            ($field:ident => $id:ident, $convert:expr) => {
+                if let Some(value) = config.$field {
+                    if !self.driver.command_line.contains(stringify!($id)) {
+                        self.$id = $convert(value)?;
+                    }
+                }
+            };
+        }
+        apply!(row_group_size);
+        apply!(compression => compression, |c: String| {
+            Codec::from_str(&c, true).map_err(|_| format!("invalid compression {:?}", c))
+        });
+        apply!(partition => partition, |p: String| {
+            export::Partition::from_str(&p, true)
+                .map(Some)
+                .map_err(|_| format!("invalid partition {:?}", p))
+        });
+        apply!(append);
+        apply!(promote_label);
+        apply!(dedup);
+        apply!(detect_resets);
+        apply!(output);
+        Ok(())
     }
+}
 
-    fn job(&self) -> Option<&str> {
-        self.job.as_deref()
-    }
+#[derive(Clone, Copy, ValueEnum)]
+enum Codec {
+    Uncompressed,
+    Snappy,
+    Zstd,
+    Gzip,
+}
 
-    fn interval(&self) -> Duration {
-        Duration::from_secs(self.interval)
+impl Codec {
+    fn compression(self) -> Compression {
+        match self {
+            Codec::Uncompressed => Compression::UNCOMPRESSED,
+            Codec::Snappy => Compression::SNAPPY,
+            Codec::Zstd => Compression::ZSTD(ZstdLevel::default()),
+            Codec::Gzip => Compression::GZIP(GzipLevel::default()),
+        }
     }
+}
 
-    fn buffer(&self) -> usize {
-        self.buffer
+impl driver::Args for Args {
+    fn driver_args(&self) -> &driver::DriverArgs {
+        &self.driver
     }
 
-    fn target(&self) -> &str {
-        self.target.as_str()
+    fn reload(&self) -> Result<driver::Reload, String> {
+        let Some(path) = &self.driver.config else {
+            return Err("no configuration file was given".to_string());
+        };
+        let mut args = self.clone();
+        args.apply_config(Config::load(path)?)?;
+        Ok(driver::Reload {
+            targets: args.targets().iter().map(|t| t.to_string()).collect(),
+            interval: args.interval(),
+        })
     }
 }
 
 fn main() -> ExitCode {
-    // Parse command-line arguments
-    let args = Args::parse();
-
     // Initialize logging
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let writer = Box::new(match export::ParquetExporter::new(&args.output) {
-        Ok(writer) => writer,
+    // Parse command-line arguments and the configuration file
+    let args = match Args::load() {
+        Ok(args) => args,
         Err(err) => {
-            error!("error opening output file: {}", err);
+            error!("{}", err);
             return ExitCode::FAILURE;
         }
-    });
-    driver::run(&args, writer)
+    };
+    if args.driver.dry_run {
+        return driver::run(
+            &args,
+            vec![Box::new(driver::DryRunExporter::default())],
+            None,
+        );
+    }
+
+    let mut writer = Box::new(
+        match export::ParquetExporter::new(
+            args.output.as_deref().unwrap(),
+            args.row_group_size as usize,
+            args.compression.compression(),
+            args.partition,
+            args.append,
+            args.promote_label.clone(),
+        ) {
+            Ok(writer) => writer,
+            Err(err) => {
+                error!("error opening output file: {}", err);
+                return ExitCode::FAILURE;
+            }
+        },
+    );
+    if args.dedup {
+        writer.enable_dedup();
+    }
+    if args.detect_resets {
+        writer.enable_reset_detection();
+    }
+    driver::run(&args, vec![writer], None)
 }
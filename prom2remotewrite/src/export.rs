@@ -0,0 +1,421 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use bytes::Bytes;
+use driver::fetch::{self, Client};
+use driver::parse::{parse_value, LabelSet, MetricFamily, SampleType};
+use driver::push::Pusher;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::Uri;
+use prost::Message;
+
+/// The most samples sent in a single request, matching Prometheus' default
+/// `max_samples_per_send`.
+const MAX_BATCH_SAMPLES: usize = 2000;
+
+/// The messages of the remote write 1.0 protocol, from `prompb/remote.proto`
+/// and `prompb/types.proto`. Only the fields we send are declared.
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    /// Sorted by name, including `__name__`.
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    /// Milliseconds since the epoch.
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// Forwards samples with the Prometheus remote write protocol. The samples of
+/// each scrape are sent together as a snappy-compressed `WriteRequest`, in the
+/// background.
+pub struct RemoteWriteExporter {
+    /// The series of the scrape being exported.
+    batch: Vec<TimeSeries>,
+    /// The timestamp of the scrape being exported.
+    timestamp_millis: u64,
+    pusher: Pusher,
+}
+
+impl RemoteWriteExporter {
+    /// Creates an exporter that writes to `url` using `client`, authenticating
+    /// with `credentials` if given.
+    pub fn new(client: Client, url: Uri, credentials: Option<&str>) -> Result<Self, String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-protobuf"),
+        );
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("snappy"));
+        headers.insert(
+            "X-Prometheus-Remote-Write-Version",
+            HeaderValue::from_static("0.1.0"),
+        );
+        if let Some(credentials) = credentials {
+            let mut value = HeaderValue::from_str(&fetch::authorization_header(credentials))
+                .map_err(|err| format!("invalid remote credentials: {}", err))?;
+            value.set_sensitive(true);
+            headers.insert(header::AUTHORIZATION, value);
+        }
+        Ok(RemoteWriteExporter {
+            batch: Vec::new(),
+            timestamp_millis: 0,
+            pusher: Pusher::new("remote write", "remote_write", client, url, headers),
+        })
+    }
+
+    /// Encodes the current batch and hands it to the writer thread.
+    fn flush(&mut self) -> bool {
+        if self.batch.is_empty() {
+            return true;
+        }
+        let request = WriteRequest {
+            timeseries: std::mem::take(&mut self.batch),
+        };
+        let body = match snap::raw::Encoder::new().compress_vec(&request.encode_to_vec()) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("unable to compress remote write request: {}", err);
+                return false;
+            }
+        };
+        self.pusher.push(Bytes::from(body))
+    }
+
+    /// Appends a series with a single sample to the batch. `extra` is a label
+    /// that was decoded out of `labels`, such as `le` or `quantile`.
+    fn append(
+        &mut self,
+        name: String,
+        labels: &LabelSet,
+        extra: Option<(&str, String)>,
+        value: f64,
+        timestamp: i64,
+    ) -> bool {
+        let mut ok = true;
+        if self.batch.len() >= MAX_BATCH_SAMPLES {
+            ok = self.flush();
+        }
+        let mut series_labels: Vec<Label> = labels
+            .iter()
+            // Prometheus treats an empty label the same as a missing one.
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| Label {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .chain(extra.map(|(name, value)| Label {
+                name: name.to_string(),
+                value,
+            }))
+            .collect();
+        series_labels.push(Label {
+            name: "__name__".to_string(),
+            value: name,
+        });
+        series_labels.sort_by(|a, b| a.name.cmp(&b.name));
+        self.batch.push(TimeSeries {
+            labels: series_labels,
+            samples: vec![Sample { value, timestamp }],
+        });
+        ok
+    }
+
+    /// Appends the samples of a histogram, decoded so that bucket bounds are
+    /// written the same way Prometheus writes them.
+    fn append_histogram(&mut self, var: &str, family: &MetricFamily) -> Option<bool> {
+        let mut ok = true;
+        for series in family.as_histogram()? {
+            let timestamp = self.timestamp(series.timestamp);
+            for (le, count) in series.buckets.iter() {
+                ok &= self.append(
+                    format!("{}_bucket", var),
+                    &series.labels,
+                    Some(("le", format_bound(*le))),
+                    *count,
+                    timestamp,
+                );
+            }
+            if let Some(sum) = series.sum {
+                ok &= self.append(format!("{}_sum", var), &series.labels, None, sum, timestamp);
+            }
+            if let Some(count) = series.count {
+                ok &= self.append(
+                    format!("{}_count", var),
+                    &series.labels,
+                    None,
+                    count,
+                    timestamp,
+                );
+            }
+        }
+        Some(ok)
+    }
+
+    /// Appends the samples of a summary, decoded so that quantiles are written
+    /// the same way Prometheus writes them.
+    fn append_summary(&mut self, var: &str, family: &MetricFamily) -> Option<bool> {
+        let mut ok = true;
+        for series in family.as_summary()? {
+            let timestamp = self.timestamp(series.timestamp);
+            for (quantile, value) in series.quantiles.iter() {
+                ok &= self.append(
+                    var.to_string(),
+                    &series.labels,
+                    Some(("quantile", format_bound(*quantile))),
+                    *value,
+                    timestamp,
+                );
+            }
+            if let Some(sum) = series.sum {
+                ok &= self.append(format!("{}_sum", var), &series.labels, None, sum, timestamp);
+            }
+            if let Some(count) = series.count {
+                ok &= self.append(
+                    format!("{}_count", var),
+                    &series.labels,
+                    None,
+                    count,
+                    timestamp,
+                );
+            }
+        }
+        Some(ok)
+    }
+
+    /// The timestamp of a sample, defaulting to that of the scrape.
+    fn timestamp(&self, timestamp: Option<i64>) -> i64 {
+        timestamp.unwrap_or(self.timestamp_millis as i64)
+    }
+}
+
+impl driver::Exporter for RemoteWriteExporter {
+    fn name(&self) -> &'static str {
+        "remote_write"
+    }
+
+    fn export(&mut self, timestamp_millis: u64, family: &MetricFamily) -> bool {
+        self.timestamp_millis = timestamp_millis;
+        let mut ok = true;
+        if let Some(var) = family.var {
+            let decoded = match family.r#type {
+                SampleType::Histogram => self.append_histogram(var, family),
+                SampleType::Summary => self.append_summary(var, family),
+                _ => None,
+            };
+            if let Some(decoded) = decoded {
+                return ok && decoded;
+            }
+        }
+        // Anything that isn't a histogram or summary, or couldn't be decoded as
+        // one, is forwarded sample by sample.
+        for sample in family.samples.iter() {
+            let Some(value) = parse_value(sample.value) else {
+                driver::unparseable_sample(sample);
+                continue;
+            };
+            let timestamp = self.timestamp(sample.timestamp);
+            ok &= self.append(
+                sample.var.to_string(),
+                &sample.labels,
+                None,
+                value,
+                timestamp,
+            );
+        }
+        ok
+    }
+
+    fn flush(&mut self) {
+        self.flush();
+    }
+
+    fn end_scrape(&mut self) {
+        if !self.flush() {
+            driver::export_failed(self.name());
+        }
+    }
+
+    fn close(&mut self) {
+        self.flush();
+        self.pusher.close();
+    }
+}
+
+/// Formats a bucket bound or quantile like Prometheus does, e.g. "1" or "+Inf".
+fn format_bound(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use driver::Exporter;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    /// Answers `requests` POSTs on `listener`, returning the `WriteRequest`
+    /// decoded from each body.
+    fn receive(listener: TcpListener, requests: usize) -> JoinHandle<Vec<WriteRequest>> {
+        thread::spawn(move || {
+            let mut bodies = Vec::new();
+            while bodies.len() < requests {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while bodies.len() < requests && reader.read_line(&mut line).unwrap() > 0 {
+                    let mut length = 0;
+                    loop {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                length = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+                    bodies.push(WriteRequest::decode(body.as_slice()).unwrap());
+                    stream
+                        .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                        .unwrap();
+                    line.clear();
+                }
+            }
+            bodies
+        })
+    }
+
+    fn open(listener: &TcpListener) -> RemoteWriteExporter {
+        let url = format!("http://{}/api/v1/write", listener.local_addr().unwrap());
+        RemoteWriteExporter::new(Client::default(), url.parse().unwrap(), None).unwrap()
+    }
+
+    /// The labels of a series as "name=value" pairs.
+    fn labels(series: &TimeSeries) -> Vec<String> {
+        series
+            .labels
+            .iter()
+            .map(|label| format!("{}={}", label.name, label.value))
+            .collect()
+    }
+
+    #[test]
+    fn encodes_write_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut exporter = open(&listener);
+        let server = receive(listener, 1);
+        let input = "# TYPE h histogram\n\
+                     h_bucket{job=\"a\",le=\"0.5\"} 1\n\
+                     h_bucket{job=\"a\",le=\"+Inf\"} 2\n\
+                     h_sum{job=\"a\"} 3\n\
+                     h_count{job=\"a\"} 2\n\
+                     # TYPE c counter\n\
+                     c{zone=\"\",job=\"a\"} 7 500\n";
+        let families = driver::parse::parse(None, None, input).unwrap();
+        for family in families.iter() {
+            assert!(exporter.export(1000, family));
+        }
+        exporter.end_scrape();
+        exporter.close();
+        let requests = server.join().unwrap();
+        let series = &requests[0].timeseries;
+        let got: Vec<_> = series
+            .iter()
+            .map(|series| (labels(series), series.samples.clone()))
+            .collect();
+        let sample = |value, timestamp| vec![Sample { value, timestamp }];
+        assert_eq!(
+            got,
+            vec![
+                (
+                    vec!["__name__=h_bucket".into(), "job=a".into(), "le=0.5".into()],
+                    sample(1.0, 1000)
+                ),
+                (
+                    vec!["__name__=h_bucket".into(), "job=a".into(), "le=+Inf".into()],
+                    sample(2.0, 1000)
+                ),
+                (
+                    vec!["__name__=h_sum".into(), "job=a".into()],
+                    sample(3.0, 1000)
+                ),
+                (
+                    vec!["__name__=h_count".into(), "job=a".into()],
+                    sample(2.0, 1000)
+                ),
+                // The empty label is left out, and the sample keeps its timestamp.
+                (vec!["__name__=c".into(), "job=a".into()], sample(7.0, 500)),
+            ]
+        );
+    }
+
+    #[test]
+    fn sends_batch_at_sample_limit_and_end_of_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut exporter = open(&listener);
+        let server = receive(listener, 2);
+        let mut input = String::from("# TYPE m gauge\n");
+        for series in 0..=MAX_BATCH_SAMPLES {
+            input.push_str(&format!("m{{series=\"{}\"}} 1\n", series));
+        }
+        let families = driver::parse::parse(None, None, &input).unwrap();
+        assert!(exporter.export(1000, &families[0]));
+        assert_eq!(exporter.batch.len(), 1);
+        exporter.end_scrape();
+        assert!(exporter.batch.is_empty());
+        exporter.close();
+        let sizes: Vec<usize> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|request| request.timeseries.len())
+            .collect();
+        assert_eq!(sizes, vec![MAX_BATCH_SAMPLES, 1]);
+    }
+}
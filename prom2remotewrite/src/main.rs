@@ -0,0 +1,151 @@
+// prom2remotewrite -- Collect Prometheus data and forward it with remote write
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#[macro_use]
+extern crate log;
+
+use std::process::ExitCode;
+
+use clap::{CommandFactory, FromArgMatches, Parser};
+use driver::fetch::Client;
+use env_logger::Env;
+
+mod config;
+use config::Config;
+
+mod export;
+use export::RemoteWriteExporter;
+
+#[derive(Clone, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    driver: driver::DriverArgs,
+
+    /// The URL of the remote write endpoint to send samples to,
+    /// e.g. "http://localhost:9090/api/v1/write".
+    #[arg(long)]
+    remote_url: Option<String>,
+
+    /// Credentials to send to the remote write endpoint.
+    /// Either a bearer token, or "user:password" for basic authentication.
+    #[arg(long)]
+    remote_authorization: Option<String>,
+
+    /// How many times to retry a write that fails with a connection or server error.
+    #[arg(long, default_value_t = 3)]
+    write_retries: u32,
+}
+
+impl Args {
+    /// Parses the command line, and then reads the configuration file if one was given.
+    fn load() -> Result<Args, String> {
+        let matches = Args::command().get_matches();
+        let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        args.driver.set_command_line(&matches);
+        if let Some(path) = &args.driver.config {
+            args.apply_config(Config::load(path)?)?;
+        }
+        if driver::Args::targets(&args).is_empty() && args.driver.targets_file.is_none() {
+            return Err("no target given on the command line or in the configuration".into());
+        }
+        if !args.driver.dry_run && args.remote_url.is_none() {
+            return Err("no remote URL given on the command line or in the configuration".into());
+        }
+        Ok(args)
+    }
+
+    /// Applies the settings from a configuration file,
+    /// except for those that were given on the command line.
+    fn apply_config(&mut self, config: Config) -> Result<(), String> {
+        self.driver.apply_config(config.driver)?;
+        macro_rules! apply {
+            ($field:ident) => {
+                if let Some(value) = config.$field {
+                    if !self.driver.command_line.contains(stringify!($field)) {
+                        self.$field = value.into();
+                    }
+                }
+            };
+            ($field:ident => $id:ident, $convert:expr) => {
+                if let Some(value) = config.$field {
+                    if !self.driver.command_line.contains(stringify!($id)) {
+                        self.$id = $convert(value)?;
+                    }
+                }
+            };
+        }
+        apply!(remote_url);
+        apply!(remote_authorization);
+        apply!(write_retries);
+        Ok(())
+    }
+}
+
+impl driver::Args for Args {
+    fn driver_args(&self) -> &driver::DriverArgs {
+        &self.driver
+    }
+
+    fn reload(&self) -> Result<driver::Reload, String> {
+        let Some(path) = &self.driver.config else {
+            return Err("no configuration file was given".to_string());
+        };
+        let mut args = self.clone();
+        args.apply_config(Config::load(path)?)?;
+        Ok(driver::Reload {
+            targets: args.targets().iter().map(|t| t.to_string()).collect(),
+            interval: args.interval(),
+        })
+    }
+}
+
+fn main() -> ExitCode {
+    // Initialize logging
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    // Parse command-line arguments and the configuration file
+    let args = match Args::load() {
+        Ok(args) => args,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.driver.dry_run {
+        return driver::run(
+            &args,
+            vec![Box::new(driver::DryRunExporter::default())],
+            None,
+        );
+    }
+    let url = match args.remote_url.as_deref().unwrap().parse() {
+        Ok(url) => url,
+        Err(err) => {
+            error!("invalid remote URL: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let client = Client::new(&args).with_max_retries(args.write_retries);
+    let writer = match RemoteWriteExporter::new(client, url, args.remote_authorization.as_deref()) {
+        Ok(writer) => writer,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    driver::run(&args, vec![Box::new(writer)], None)
+}
@@ -0,0 +1,130 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Benchmarks writing a scrape of series that already exist in the database,
+//! which is the common case once a target has been scraped for a while.
+
+#[macro_use]
+extern crate log;
+
+use std::fmt::Write;
+use std::path::Path;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use driver::Exporter;
+
+// prom2sqlite is only a binary, so the exporter is built into the benchmark
+// directly. Some of it is only used by main.rs.
+#[allow(dead_code)]
+#[path = "../src/table.rs"]
+mod table;
+use table::{Layout, TableExporter, TimestampFormat};
+
+/// Generates an exposition with a few counter and gauge families, each with
+/// many series that have several labels.
+fn exposition() -> String {
+    let mut out = String::new();
+    for family in 0..20 {
+        writeln!(
+            out,
+            "# HELP app_requests_{}_total Requests handled.",
+            family
+        )
+        .unwrap();
+        writeln!(out, "# TYPE app_requests_{}_total counter", family).unwrap();
+        for route in 0..50 {
+            for code in ["200", "404", "500"] {
+                writeln!(
+                    out,
+                    "app_requests_{}_total{{method=\"GET\",route=\"/api/v1/items/{}\",code=\"{}\",pod=\"app-7d9f8b-x2k4q\"}} {}",
+                    family, route, code, route
+                )
+                .unwrap();
+            }
+        }
+        writeln!(out, "# HELP app_queue_{}_depth Items queued.", family).unwrap();
+        writeln!(out, "# TYPE app_queue_{}_depth gauge", family).unwrap();
+        for queue in 0..50 {
+            writeln!(
+                out,
+                "app_queue_{}_depth{{queue=\"q{}\",pod=\"app-7d9f8b-x2k4q\"}} {}",
+                family, queue, queue
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// Removes a database, along with the files SQLite keeps next to it in WAL mode.
+fn remove_database(path: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let mut file = path.as_os_str().to_owned();
+        file.push(suffix);
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+fn bench_export(c: &mut Criterion) {
+    let input = exposition();
+    let families = driver::parse::parse(Some("localhost:8080"), Some("app"), &input).unwrap();
+    let samples: usize = families.iter().map(|f| f.samples.len()).sum();
+
+    let mut group = c.benchmark_group("export");
+    group.throughput(Throughput::Elements(samples as u64));
+    group.sample_size(20);
+    for dedup in [false, true] {
+        let name = if dedup { "dedup" } else { "insert" };
+        let path = std::env::temp_dir().join(format!("prom2sqlite-bench-{}.db", name));
+        remove_database(&path);
+        let mut exporter = TableExporter::open(
+            path.to_str().unwrap(),
+            None,
+            &[],
+            Layout::PerMetric,
+            TimestampFormat::Rfc3339,
+        )
+        .unwrap();
+        if dedup {
+            exporter.enable_dedup();
+        }
+        // The first scrape creates the series, so that the benchmark measures
+        // looking them up.
+        let mut timestamp = 1_700_000_000_000;
+        for family in families.iter() {
+            assert!(exporter.export(timestamp, family));
+        }
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    timestamp += 1000;
+                    timestamp
+                },
+                |timestamp| {
+                    for family in families.iter() {
+                        assert!(exporter.export(timestamp, family));
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        drop(exporter);
+        remove_database(&path);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_export);
+criterion_main!(benches);
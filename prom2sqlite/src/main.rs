@@ -18,98 +18,238 @@
 extern crate log;
 
 use std::process::ExitCode;
+use std::sync::Arc;
 use std::time::Duration;
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
 use env_logger::Env;
 
+mod config;
+use config::Config;
+
+mod query;
+use query::QueryApi;
+
+mod shard;
+use shard::ShardedExporter;
+
 mod table;
-use table::TableExporter;
+use table::{Layout, TableExporter, TimestampFormat};
 
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// The IP address to listen on for connections.
-    /// Only needed when running as a server.
-    #[arg(long, default_value = "127.0.0.1")]
-    host: String,
-
-    /// The port number to use.
-    /// Only needed when running as a server.
-    #[arg(short, long, default_value_t = 8080)]
-    port: u16,
-
-    // The instance label to use for all samples.
-    // If not provided, the address of the source URL will be used.
-    #[arg(long)]
-    instance: Option<String>,
+    #[command(flatten)]
+    driver: driver::DriverArgs,
 
-    // The job label to use for all samples.
-    // If not provided, the address of the source URL will be used.
+    /// Path to the Stanchion SQLite extension.
     #[arg(long)]
-    job: Option<String>,
+    stanchion: Option<String>,
 
-    /// How often metrics will be scraped, in seconds.
-    #[arg(short, long, default_value_t = 5)]
-    interval: u64,
+    /// A pragma to set when opening the database, e.g. "synchronous=NORMAL".
+    /// May be repeated. The database always starts out in WAL mode.
+    #[arg(long = "pragma", value_name = "NAME=VALUE", value_parser = parse_pragma)]
+    pragmas: Vec<(String, String)>,
 
-    /// How many scrapes to hold in memory before dropping samples.
-    #[arg(short, long, default_value_t = 5)]
-    buffer: usize,
+    /// How long to keep samples, e.g. "30d" or "12h".
+    /// Older samples are periodically deleted. If not provided, samples are kept forever.
+    #[arg(long, value_name = "DURATION", value_parser = driver::parse_duration)]
+    retention: Option<Duration>,
 
-    /// Path to the Stanchion SQLite extension.
+    /// Only write a sample when its value differs from the last one written for
+    /// the same series. Histograms and summaries are always written.
     #[arg(long)]
-    stanchion: Option<String>,
+    dedup: bool,
+
+    /// Record when a counter resets, which is when its value is lower than the
+    /// last one seen for the same series, usually because the target restarted.
+    #[arg(long)]
+    detect_resets: bool,
+
+    /// How samples are laid out in tables: one table per metric, or one table
+    /// per metric type that is shared by all metrics.
+    #[arg(long, value_enum, default_value_t = Layout::PerMetric)]
+    layout: Layout,
 
-    /// The URL of a Prometheus client endpoint to scrape.
-    /// If "-", then read from stdin.
-    target: String,
+    /// How timestamps are stored: as RFC 3339 text, or as an integer number of
+    /// milliseconds since the epoch, which is smaller and faster to compare.
+    #[arg(long, value_enum, default_value_t = TimestampFormat::Rfc3339)]
+    timestamp_format: TimestampFormat,
+
+    /// How many threads write to the database, each with its own connection.
+    /// Metrics are divided between them by name. SQLite still commits one
+    /// transaction at a time, but the rest of the work is spread out.
+    #[arg(long, default_value_t = 1)]
+    writers: usize,
 
     /// The path to the SQLite database file to store metrics.
-    output: String,
+    output: Option<String>,
 }
 
-impl driver::Args for Args {
-    fn addr(&self) -> (&str, u16) {
-        (self.host.as_str(), self.port)
-    }
-
-    fn instance(&self) -> Option<&str> {
-        self.instance.as_deref()
+impl Args {
+    /// Parses the command line, and then reads the configuration file if one was given.
+    fn load() -> Result<Args, String> {
+        let matches = Args::command().get_matches();
+        let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        args.driver.set_command_line(&matches);
+        if let Some(path) = &args.driver.config {
+            args.apply_config(Config::load(path)?)?;
+        }
+        // Targets may all come from the targets file, leaving only the output.
+        // Either may be set in the configuration, so this is checked after it.
+        if args.driver.targets_file.is_some() && args.output.is_none() {
+            if let Some(output) = args.driver.target.take() {
+                args.output = Some(output);
+                args.driver.command_line.remove("target");
+                args.driver.command_line.insert("output".to_string());
+            }
+        }
+        if driver::Args::targets(&args).is_empty() && args.driver.targets_file.is_none() {
+            return Err("no target given on the command line or in the configuration".into());
+        }
+        if !args.driver.dry_run && args.output.is_none() {
+            return Err("no output given on the command line or in the configuration".into());
+        }
+        if args.writers == 0 {
+            return Err("there must be at least one writer".into());
+        }
+        Ok(args)
     }
 
-    fn job(&self) -> Option<&str> {
-        self.job.as_deref()
+    /// Applies the settings from a configuration file,
+    /// except for those that were given on the command line.
+    fn apply_config(&mut self, config: Config) -> Result<(), String> {
+        self.driver.apply_config(config.driver)?;
+        macro_rules! apply {
+            ($field:ident) => {
+                if let Some(value) = config.$field {
+                    if !self.driver.command_line.contains(stringify!($field)) {
+                        self.$field = value.into();
+                    }
+                }
+            };
+            ($field:ident => $id:ident, $convert:expr) => {
+                if let Some(value) = config.$field {
+                    if !self.driver.command_line.contains(stringify!($id)) {
+                        self.$id = $convert(value)?;
+                    }
+                }
+            };
+        }
+        apply!(stanchion);
+        apply!(pragmas => pragmas, |pragmas: Vec<String>| {
+            pragmas.iter().map(|p| parse_pragma(p)).collect::<Result<_, _>>()
+        });
+        apply!(retention => retention, |d: driver::ConfigDuration| {
+            d.to_duration().map(Some)
+        });
+        apply!(dedup);
+        apply!(detect_resets);
+        apply!(layout => layout, |l: String| {
+            Layout::from_str(&l, true).map_err(|_| format!("invalid layout {:?}", l))
+        });
+        apply!(timestamp_format => timestamp_format, |f: String| {
+            TimestampFormat::from_str(&f, true)
+                .map_err(|_| format!("invalid timestamp_format {:?}", f))
+        });
+        apply!(writers);
+        apply!(output);
+        Ok(())
     }
+}
 
-    fn interval(&self) -> Duration {
-        Duration::from_secs(self.interval)
+fn parse_pragma(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got {:?}", s))?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("invalid pragma name {:?}", name));
     }
+    Ok((name.to_string(), value.trim().to_string()))
+}
 
-    fn buffer(&self) -> usize {
-        self.buffer
+impl driver::Args for Args {
+    fn driver_args(&self) -> &driver::DriverArgs {
+        &self.driver
     }
 
-    fn target(&self) -> &str {
-        self.target.as_str()
+    fn reload(&self) -> Result<driver::Reload, String> {
+        let Some(path) = &self.driver.config else {
+            return Err("no configuration file was given".to_string());
+        };
+        let mut args = self.clone();
+        args.apply_config(Config::load(path)?)?;
+        Ok(driver::Reload {
+            targets: args.targets().iter().map(|t| t.to_string()).collect(),
+            interval: args.interval(),
+        })
     }
 }
 
 fn main() -> ExitCode {
-    // Parse command-line arguments
-    let args = Args::parse();
-
     // Initialize logging
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let writer = Box::new(
-        match TableExporter::open(&args.output, args.stanchion.as_deref()) {
+    // Parse command-line arguments and the configuration file
+    let args = match Args::load() {
+        Ok(args) => args,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.driver.dry_run {
+        return driver::run(
+            &args,
+            vec![Box::new(driver::DryRunExporter::default())],
+            None,
+        );
+    }
+    let output = args.output.as_deref().unwrap();
+
+    // The connections are opened one after another, so that only the first
+    // creates or upgrades the schema.
+    let mut writers = Vec::with_capacity(args.writers);
+    for _ in 0..args.writers {
+        let mut writer = match TableExporter::open(
+            output,
+            args.stanchion.as_deref(),
+            &args.pragmas,
+            args.layout,
+            args.timestamp_format,
+        ) {
             Ok(writer) => writer,
             Err(err) => {
                 error!("error opening database: {}", err);
                 return ExitCode::FAILURE;
             }
-        },
+        };
+        if let Some(retention) = args.retention {
+            writer.set_retention(retention);
+        }
+        // Each sweep for old samples is run by whichever writer exports first
+        // once it is due.
+        if let Some(first) = writers.first() {
+            writer.share_pruning(first);
+        }
+        if args.dedup {
+            writer.enable_dedup();
+        }
+        if args.detect_resets {
+            writer.enable_reset_detection();
+        }
+        writers.push(writer);
+    }
+    let api = QueryApi::new(
+        output,
+        args.stanchion.as_deref(),
+        writers[0].timestamp_format(),
     );
-    driver::run(&args, writer)
+    let exporter: Box<dyn driver::Exporter + Send> = if writers.len() == 1 {
+        Box::new(writers.pop().unwrap())
+    } else {
+        Box::new(ShardedExporter::new(writers))
+    };
+    driver::run(&args, vec![exporter], Some(Arc::new(api)))
 }
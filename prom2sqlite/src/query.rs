@@ -0,0 +1,231 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The `/api/query` endpoint, which reads the samples of a metric back from
+//! the database, so that it can be inspected without another tool.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime};
+
+use driver::http::{Api, ApiResponse, StatusCode};
+use rusqlite::types::Value;
+use rusqlite::{Connection, LoadExtensionGuard, OpenFlags};
+use serde_json::json;
+
+use crate::table::TimestampFormat;
+
+/// How far back a query reaches if it isn't given a start time.
+const DEFAULT_RANGE: Duration = Duration::from_secs(60 * 60);
+
+/// Serves queries from a database, opening a read-only connection for each,
+/// so that they don't hold up the exporter's writes.
+pub struct QueryApi {
+    database: String,
+    stanchion: Option<String>,
+    timestamp_format: TimestampFormat,
+}
+
+/// A series of a metric, and the points of it that were queried.
+#[derive(Default)]
+struct Series {
+    labels: BTreeMap<String, String>,
+    points: Vec<(i64, f64)>,
+}
+
+impl QueryApi {
+    /// `timestamp_format` must be the one the database is written with, as
+    /// returned by `TableExporter::timestamp_format`.
+    pub fn new(
+        database: &str,
+        stanchion: Option<&str>,
+        timestamp_format: TimestampFormat,
+    ) -> QueryApi {
+        QueryApi {
+            database: database.to_string(),
+            stanchion: stanchion.map(str::to_string),
+            timestamp_format,
+        }
+    }
+
+    fn connect(&self) -> rusqlite::Result<Connection> {
+        let connection = Connection::open_with_flags(
+            &self.database,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        if let Some(stanchion) = &self.stanchion {
+            unsafe {
+                let _guard = LoadExtensionGuard::new(&connection)?;
+                connection.load_extension(stanchion, None)?;
+            }
+        }
+        Ok(connection)
+    }
+
+    /// Handles `/api/query?metric=NAME&from=TIME&to=TIME`. The times are either
+    /// RFC 3339 or milliseconds since the epoch, and default to the last hour.
+    /// Only counters, gauges and untyped metrics can be queried. The response
+    /// holds each series with a point in the range:
+    ///
+    /// ```json
+    /// {
+    ///   "metric": "http_requests_total",
+    ///   "type": "counter",
+    ///   "unit": null,
+    ///   "series": [
+    ///     {
+    ///       "labels": {"code": "200", "instance": "localhost:8080"},
+    ///       "points": [[1714564800000, 1027.0], [1714564860000, 1031.0]]
+    ///     }
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// Each point is a timestamp in milliseconds since the epoch, and a value,
+    /// which is null if it is infinite.
+    fn query(&self, params: &HashMap<String, String>) -> ApiResponse {
+        let Some(metric) = params.get("metric") else {
+            return ApiResponse::error(StatusCode::BAD_REQUEST, "missing parameter \"metric\"");
+        };
+        let time = |name: &str| -> Result<Option<u64>, String> {
+            params
+                .get(name)
+                .map(|value| {
+                    driver::parse_timestamp(value)
+                        .map_err(|err| format!("invalid parameter {:?}: {}", name, err))
+                })
+                .transpose()
+        };
+        let (from, to) = match (time("from"), time("to")) {
+            (Ok(from), Ok(to)) => {
+                let to = to.unwrap_or_else(|| {
+                    SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64
+                });
+                let from =
+                    from.unwrap_or_else(|| to.saturating_sub(DEFAULT_RANGE.as_millis() as u64));
+                (from, to)
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                return ApiResponse::error(StatusCode::BAD_REQUEST, err)
+            }
+        };
+        match self.read(metric, from, to) {
+            Ok(response) => response,
+            Err(err) => {
+                error!("unable to query {}: {}", metric, err);
+                ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+            }
+        }
+    }
+
+    fn read(&self, metric: &str, from: u64, to: u64) -> rusqlite::Result<ApiResponse> {
+        let connection = self.connect()?;
+        let mut stmt =
+            connection.prepare("SELECT id, type, unit, table_name FROM metric WHERE name = ?1")?;
+        let mut rows = stmt.query((metric,))?;
+        let Some(row) = rows.next()? else {
+            return Ok(ApiResponse::error(
+                StatusCode::NOT_FOUND,
+                format!("no metric named {:?}", metric),
+            ));
+        };
+        let metric_id: i64 = row.get(0)?;
+        let r#type: String = row.get(1)?;
+        let unit: Option<String> = row.get(2)?;
+        let table_name: String = row.get(3)?;
+        if !matches!(r#type.as_str(), "counter" | "gauge" | "untyped") {
+            return Ok(ApiResponse::error(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "{} is a {}, only counters, gauges and untyped metrics can be queried",
+                    metric, r#type
+                ),
+            ));
+        }
+
+        // In the narrow layout, the table is shared with other metrics, so the
+        // samples are picked out by the metric of their series.
+        let mut series: BTreeMap<i64, Series> = BTreeMap::new();
+        let mut stmt = connection.prepare(&format!(
+            "SELECT t.series_id, t.timestamp, t.value FROM {:?} t
+             INNER JOIN series s ON s.id = t.series_id
+             WHERE s.metric_id = ?1 AND t.timestamp >= ?2 AND t.timestamp <= ?3
+             ORDER BY t.series_id, t.timestamp",
+            table_name
+        ))?;
+        let mut rows = stmt.query((
+            metric_id,
+            self.timestamp_format.value(from),
+            self.timestamp_format.value(to),
+        ))?;
+        while let Some(row) = rows.next()? {
+            let Some(timestamp) = timestamp_millis(row.get(1)?) else {
+                continue;
+            };
+            series
+                .entry(row.get(0)?)
+                .or_default()
+                .points
+                .push((timestamp, row.get(2)?));
+        }
+
+        let mut stmt = connection.prepare(
+            "SELECT ls.series_id, lv.label, lv.value FROM label_set ls
+             INNER JOIN label_value lv ON lv.id = ls.label_value_id
+             INNER JOIN series s ON s.id = ls.series_id
+             WHERE s.metric_id = ?1",
+        )?;
+        let mut rows = stmt.query((metric_id,))?;
+        while let Some(row) = rows.next()? {
+            if let Some(series) = series.get_mut(&row.get(0)?) {
+                series.labels.insert(row.get(1)?, row.get(2)?);
+            }
+        }
+
+        let series: Vec<serde_json::Value> = series
+            .into_values()
+            .map(|series| json!({ "labels": series.labels, "points": series.points }))
+            .collect();
+        Ok(ApiResponse::ok(&json!({
+            "metric": metric,
+            "type": r#type,
+            "unit": unit,
+            "series": series,
+        })))
+    }
+}
+
+impl Api for QueryApi {
+    fn get(&self, endpoint: &str, params: &HashMap<String, String>) -> Option<ApiResponse> {
+        match endpoint {
+            "query" => Some(self.query(params)),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a stored timestamp back to milliseconds since the epoch, whichever
+/// format it was written in.
+fn timestamp_millis(value: Value) -> Option<i64> {
+    match value {
+        Value::Integer(millis) => Some(millis),
+        Value::Text(text) => chrono::DateTime::parse_from_rfc3339(&text)
+            .ok()
+            .map(|timestamp| timestamp.timestamp_millis()),
+        _ => None,
+    }
+}
@@ -2,7 +2,9 @@ CREATE TABLE IF NOT EXISTS metric (
   id INTEGER PRIMARY KEY AUTOINCREMENT,
   name TEXT UNIQUE NOT NULL,
   type TEXT NOT NULL,
-  help TEXT NOT NULL
+  help TEXT NOT NULL,
+  unit TEXT,
+  table_name TEXT NOT NULL
 );
 
 CREATE TABLE IF NOT EXISTS label_value (
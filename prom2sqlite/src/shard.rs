@@ -0,0 +1,220 @@
+// Copyright (C) 2024, Tony Rippy
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Writing to the database from several threads, each with its own connection.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use driver::parse::{MetricFamily, OwnedMetricFamily};
+use driver::Exporter;
+
+use crate::table::TableExporter;
+
+/// How many metric families may wait for each writer before exporting blocks.
+const MAX_PENDING: usize = 1024;
+
+/// Hands metric families to a pool of writer threads, each with its own
+/// `TableExporter`. A family always goes to the same writer, picked by the hash
+/// of its name, so a metric's table, series and cached ids belong to a single
+/// connection.
+///
+/// SQLite only lets one connection write at a time, so each writer holds the
+/// write lock for the transaction of one family while the others wait for it.
+/// What runs alongside is the rest of their work, and the parsing of the next
+/// scrape, which no longer waits for the database.
+pub struct ShardedExporter {
+    shards: Vec<Shard>,
+}
+
+struct Shard {
+    tx: Option<SyncSender<(u64, OwnedMetricFamily)>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl ShardedExporter {
+    /// Starts a thread for each of `writers`, which should all have been
+    /// opened on the same database.
+    pub fn new(writers: Vec<TableExporter>) -> ShardedExporter {
+        let shards = writers
+            .into_iter()
+            .map(|writer| {
+                let (tx, rx) = mpsc::sync_channel(MAX_PENDING);
+                Shard {
+                    tx: Some(tx),
+                    writer: Some(thread::spawn(move || write_loop(writer, rx))),
+                }
+            })
+            .collect();
+        ShardedExporter { shards }
+    }
+}
+
+impl Exporter for ShardedExporter {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    /// Queues the family for its writer, waiting if the writer is behind.
+    /// Returns false only if the writer has stopped; failures to write the
+    /// family are reported by the writer.
+    fn export(&mut self, timestamp_millis: u64, family: &MetricFamily) -> bool {
+        let mut hasher = DefaultHasher::new();
+        family.var.hash(&mut hasher);
+        let shard = &self.shards[hasher.finish() as usize % self.shards.len()];
+        let Some(tx) = &shard.tx else {
+            return false;
+        };
+        match tx.send((timestamp_millis, family.to_owned_family())) {
+            Ok(()) => true,
+            Err(_) => {
+                error!("sqlite writer has stopped");
+                false
+            }
+        }
+    }
+
+    /// Waits for the writers to finish whatever is still queued.
+    fn close(&mut self) {
+        // Let every writer drain its queue before waiting on any of them.
+        for shard in self.shards.iter_mut() {
+            shard.tx = None;
+        }
+        for shard in self.shards.iter_mut() {
+            if let Some(writer) = shard.writer.take() {
+                if writer.join().is_err() {
+                    error!("sqlite writer panicked");
+                }
+            }
+        }
+    }
+}
+
+/// Writes each family received from `rx`, until the exporter is closed.
+fn write_loop(mut exporter: TableExporter, rx: Receiver<(u64, OwnedMetricFamily)>) {
+    for (timestamp_millis, family) in rx {
+        if !write(&mut exporter, timestamp_millis, &family.as_borrowed()) {
+            driver::export_failed(exporter.name());
+        }
+    }
+    exporter.close();
+}
+
+/// Exports a family, returning false if it couldn't be written. If the
+/// exporter panics, its transaction is rolled back right away, as it would
+/// otherwise keep the other writers from writing until the next export.
+fn write(exporter: &mut TableExporter, timestamp_millis: u64, family: &MetricFamily) -> bool {
+    match panic::catch_unwind(AssertUnwindSafe(|| {
+        exporter.export(timestamp_millis, family)
+    })) {
+        Ok(exported) => exported,
+        Err(_) => {
+            error!("sqlite writer panicked on metric family {:?}", family.var);
+            exporter.abort();
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::{Layout, TimestampFormat};
+    use rusqlite::Connection;
+
+    /// A database file for a test, which is deleted when dropped.
+    struct TestDatabase(String);
+
+    impl TestDatabase {
+        fn new(name: &str) -> TestDatabase {
+            let path =
+                std::env::temp_dir().join(format!("shard-{}-{}.db", std::process::id(), name));
+            TestDatabase(path.to_str().unwrap().to_string())
+        }
+    }
+
+    impl Drop for TestDatabase {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{}", self.0, suffix));
+            }
+        }
+    }
+
+    fn open(path: &str, pragmas: &[(String, String)]) -> TableExporter {
+        TableExporter::open(
+            path,
+            None,
+            pragmas,
+            Layout::PerMetric,
+            TimestampFormat::Millis,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn writers_share_label_values() {
+        let database = TestDatabase::new("share");
+        let path = database.0.as_str();
+        let writers = (0..4).map(|_| open(path, &[])).collect();
+        let mut exporter = ShardedExporter::new(writers);
+        let mut input = String::new();
+        for metric in 0..20 {
+            input.push_str(&format!(
+                "# HELP m{0} Help.\n# TYPE m{0} gauge\nm{0}{{job=\"a\"}} 1\nm{0}{{job=\"b\"}} 2\n",
+                metric
+            ));
+        }
+        let families = driver::parse::parse(None, None, &input).unwrap();
+        for timestamp_millis in [1000, 2000] {
+            for family in families.iter() {
+                assert!(exporter.export(timestamp_millis, family));
+            }
+        }
+        exporter.close();
+
+        let connection = Connection::open(path).unwrap();
+        let count = |sql: &str| -> i64 { connection.query_row(sql, (), |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM metric"), 20);
+        assert_eq!(count("SELECT COUNT(*) FROM label_value"), 2);
+        assert_eq!(count("SELECT COUNT(*) FROM series"), 40);
+        assert_eq!(count("SELECT COUNT(*) FROM m7"), 4);
+    }
+
+    #[test]
+    fn panic_releases_write_lock() {
+        let database = TestDatabase::new("panic");
+        let mut writer = open(&database.0, &[]);
+        // The exporter panics on a family without a name, once its
+        // transaction has begun.
+        let family = MetricFamily {
+            var: None,
+            help: None,
+            r#type: driver::parse::SampleType::Gauge,
+            unit: None,
+            samples: Vec::new(),
+        };
+        assert!(!write(&mut writer, 1000, &family));
+
+        let pragmas = [("busy_timeout".to_string(), "100".to_string())];
+        let mut other = open(&database.0, &pragmas);
+        let families =
+            driver::parse::parse(None, None, "# HELP m Help.\n# TYPE m gauge\nm 1\n").unwrap();
+        assert!(other.export(1000, &families[0]));
+    }
+}
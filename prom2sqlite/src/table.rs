@@ -14,24 +14,134 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use driver::parse::{LabelSet, MetricFamily, SampleType};
-use rusqlite::{Connection, LoadExtensionGuard};
+use driver::parse::{parse_value, LabelSet, MetricFamily, SampleType};
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, LoadExtensionGuard, TransactionBehavior};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const SCHEMA_SQL: &str = include_str!("./schema.sql");
 
+/// How many prepared statements to keep around for reuse.
+/// There are a few per metric table, so this should comfortably exceed that.
+const STATEMENT_CACHE_CAPACITY: usize = 1024;
+
+/// The maximum number of parameters bound by a single insert statement.
+/// This is SQLite's historical limit, which is lower than that of newer versions.
+const MAX_PARAMS_PER_INSERT: usize = 999;
+
+/// How often to sweep the database for samples older than the retention period.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait for another connection to finish writing before giving up
+/// on a transaction. It can be changed with the "busy_timeout" pragma.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The tables holding the samples of each metric type in the narrow layout.
+const NARROW_SCALAR_TABLE: &str = "samples";
+const NARROW_HISTOGRAM_TABLE: &str = "histograms";
+const NARROW_SUMMARY_TABLE: &str = "summaries";
+
+/// The table recording when each counter series was reset.
+const COUNTER_RESET_TABLE: &str = "counter_reset";
+
+/// The table recording how long the value of each deduplicated row held.
+const DEDUP_RANGE_TABLE: &str = "dedup_range";
+
+/// How samples are laid out in tables.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Layout {
+    // One table per metric, named after the metric.
+    #[default]
+    PerMetric,
+    // One table per metric type, shared by all metrics of that type.
+    Narrow,
+}
+
+/// How timestamps are stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum TimestampFormat {
+    // RFC 3339 text, such as "2024-05-01T12:00:00+00:00".
+    #[default]
+    Rfc3339,
+    // Milliseconds since the epoch, as an integer.
+    Millis,
+}
+
+impl TimestampFormat {
+    /// Converts a timestamp to the representation stored in the database.
+    pub fn value(self, timestamp_millis: u64) -> Value {
+        match self {
+            TimestampFormat::Rfc3339 => Value::Text(
+                chrono::DateTime::from_timestamp_millis(timestamp_millis as i64)
+                    .unwrap()
+                    .to_rfc3339(),
+            ),
+            TimestampFormat::Millis => Value::Integer(timestamp_millis as i64),
+        }
+    }
+}
+
 pub struct TableExporter {
     connection: Connection,
     use_stanchion: bool,
-    metric_cache: HashMap<String, i64>,
-    label_value_cache: HashMap<(String, String), i64>,
+    layout: Layout,
+    timestamp_format: TimestampFormat,
+    /// The id and table name of each metric, by metric name.
+    metric_cache: HashMap<String, (i64, String)>,
+    /// An id for each label name seen, indexing `label_value_cache`.
+    label_ids: HashMap<String, usize>,
+    /// The id of each label value, by value, for each interned label name.
+    /// Both are looked up by `&str`, so a cache hit doesn't allocate.
+    label_value_cache: Vec<HashMap<String, i64>>,
     series_cache: HashMap<(i64, Vec<i64>), i64>,
+    /// Reused to build the keys of `series_cache`, so a cache hit doesn't allocate.
+    series_key: (i64, Vec<i64>),
+    retention: Option<Duration>,
+    /// When the next sweep for old samples is due. It is shared by the
+    /// exporters writing to the same database, so that whichever of them
+    /// exports next runs it.
+    next_prune_millis: Arc<AtomicU64>,
+    /// When the last values of series are next checked for rows that are
+    /// about to be pruned.
+    next_forget_millis: u64,
+    /// The bits of the last value written for each scalar series, and the
+    /// timestamp of the row it was written in, if deduplicating.
+    last_values: Option<HashMap<i64, (u64, u64)>>,
+    /// Updates to `last_values` from the family being exported. They are only
+    /// applied once its transaction commits, so that a rollback can't leave
+    /// the cache holding values that were never written.
+    pending_values: Vec<(i64, (u64, u64))>,
+    /// The last value seen for each counter series, if detecting resets.
+    last_counters: Option<HashMap<i64, f64>>,
+    /// Updates to `last_counters` from the family being exported, which are
+    /// applied like those to `last_values`.
+    pending_counters: Vec<(i64, f64)>,
 }
 
 impl TableExporter {
-    pub fn open(database: &str, stanchion: Option<&str>) -> rusqlite::Result<TableExporter> {
+    /// Opens (or creates) the database. `pragmas` are applied after switching to
+    /// WAL mode, so they can be used to override it as well. Stanchion tables
+    /// always store timestamps as milliseconds, whatever `timestamp_format` is.
+    pub fn open(
+        database: &str,
+        stanchion: Option<&str>,
+        pragmas: &[(String, String)],
+        layout: Layout,
+        timestamp_format: TimestampFormat,
+    ) -> rusqlite::Result<TableExporter> {
         info!("using sqlite version {}", rusqlite::version());
         let connection = Connection::open(database)?;
+        connection.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        // WAL mode lets other processes read the database while we're writing to it.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        for (name, value) in pragmas {
+            info!("setting pragma {} = {}", name, value);
+            connection.pragma_update(None, name, value)?;
+        }
         if let Some(stanchion) = stanchion {
             info!("using stanchion from {}", stanchion);
             unsafe {
@@ -40,78 +150,321 @@ impl TableExporter {
             }
         }
         connection.execute_batch(SCHEMA_SQL)?;
-        Ok(TableExporter {
+        // Databases created by older versions don't record metric units, and
+        // named each metric's table after the metric.
+        add_column_if_missing(&connection, "metric", "unit", "TEXT")?;
+        if add_column_if_missing(
+            &connection,
+            "metric",
+            "table_name",
+            "TEXT NOT NULL DEFAULT ''",
+        )? {
+            connection.execute_batch("UPDATE metric SET table_name = name")?;
+        }
+        let exporter = TableExporter {
             connection,
             use_stanchion: stanchion.is_some(),
+            layout,
+            timestamp_format: match stanchion {
+                Some(_) => TimestampFormat::Millis,
+                None => timestamp_format,
+            },
             metric_cache: HashMap::new(),
-            label_value_cache: HashMap::new(),
+            label_ids: HashMap::new(),
+            label_value_cache: Vec::new(),
             series_cache: HashMap::new(),
-        })
+            series_key: (0, Vec::new()),
+            retention: None,
+            next_prune_millis: Arc::new(AtomicU64::new(0)),
+            next_forget_millis: 0,
+            last_values: None,
+            pending_values: Vec::new(),
+            last_counters: None,
+            pending_counters: Vec::new(),
+        };
+        exporter.connection.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {:?} (
+                    series_id INTEGER NOT NULL REFERENCES series(id) ON DELETE CASCADE,
+                    timestamp {} NOT NULL,
+                    PRIMARY KEY (series_id, timestamp)
+            );",
+                COUNTER_RESET_TABLE,
+                exporter.timestamp_type()
+            ),
+            (),
+        )?;
+        exporter.connection.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {:?} (
+                    series_id INTEGER NOT NULL REFERENCES series(id) ON DELETE CASCADE,
+                    timestamp {1} NOT NULL,
+                    last_timestamp {1} NOT NULL,
+                    PRIMARY KEY (series_id, timestamp)
+            );",
+                DEDUP_RANGE_TABLE,
+                exporter.timestamp_type()
+            ),
+            (),
+        )?;
+        if layout == Layout::Narrow {
+            exporter.create_scalar(NARROW_SCALAR_TABLE)?;
+            exporter.create_histogram(NARROW_HISTOGRAM_TABLE)?;
+            exporter.create_summary(NARROW_SUMMARY_TABLE)?;
+        }
+        Ok(exporter)
     }
 
-    fn create_scalar(&self, table_name: &str) -> rusqlite::Result<()> {
+    /// How timestamps are stored, which is always as milliseconds with Stanchion.
+    pub fn timestamp_format(&self) -> TimestampFormat {
+        self.timestamp_format
+    }
+
+    /// Samples older than `retention` will be periodically deleted from the database.
+    pub fn set_retention(&mut self, retention: Duration) {
+        self.retention = Some(retention);
+    }
+
+    /// Shares the schedule of sweeps for old samples with another exporter
+    /// writing to the same database, so that each sweep is only run by one of
+    /// them, whichever exports first once it is due.
+    pub fn share_pruning(&mut self, other: &TableExporter) {
+        self.next_prune_millis = other.next_prune_millis.clone();
+    }
+
+    /// Rolls back the transaction of an export that was interrupted by a
+    /// panic, which would otherwise keep the database locked for writing, and
+    /// forgets the cached ids that may refer to its rows.
+    pub fn abort(&mut self) {
+        if !self.connection.is_autocommit() {
+            warn!("rolling back an unfinished transaction");
+            if let Err(err) = self.connection.execute_batch("ROLLBACK") {
+                error!("unable to roll back transaction: {}", err);
+            }
+        }
+        self.clear_caches();
+    }
+
+    /// Skips writing a scalar sample when its value is identical to the last one
+    /// written for the same series. A series then holds a row only when its value
+    /// changes, and readers should carry each value forward until the next row.
+    /// The timestamp of the last sample skipped for a row is recorded in the
+    /// `dedup_range` table, keyed by the row's series and timestamp.
+    pub fn enable_dedup(&mut self) {
+        self.last_values = Some(HashMap::new());
+    }
+
+    /// Records when a counter resets, which is usually because the target
+    /// restarted. Whenever a counter's value is lower than the last one seen for
+    /// the same series, a row is added to the `counter_reset` table.
+    pub fn enable_reset_detection(&mut self) {
+        self.last_counters = Some(HashMap::new());
+    }
+
+    /// Deletes all samples with a timestamp before `older_than_millis` from the
+    /// metric tables, in a single transaction. Returns the number of rows removed.
+    pub fn prune(&mut self, older_than_millis: u64) -> rusqlite::Result<usize> {
+        let cutoff = self.timestamp_value(older_than_millis);
+        let tx = self
+            .connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut tables = Vec::new();
+        {
+            let mut stmt = tx.prepare_cached("SELECT DISTINCT table_name, type FROM metric")?;
+            let mut rows = stmt.query(())?;
+            while let Some(row) = rows.next()? {
+                let name: String = row.get(0)?;
+                let r#type: String = row.get(1)?;
+                match r#type.as_str() {
+                    "histogram" => tables.push(format!("{}_bucket", name)),
+                    "summary" => tables.push(format!("{}_quantile", name)),
+                    _ => {}
+                }
+                tables.push(name);
+            }
+        }
+        tables.push(COUNTER_RESET_TABLE.to_string());
+        tables.push(DEDUP_RANGE_TABLE.to_string());
+        let mut removed = 0;
+        for table in tables {
+            removed += tx.execute(
+                &format!("DELETE FROM {:?} WHERE timestamp < ?1", table),
+                (&cutoff,),
+            )?;
+        }
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    /// Prunes old samples if a retention period is set and a sweep is due.
+    /// This is driven by scrape timestamps, so it runs as part of the writer loop.
+    fn maybe_prune(&mut self, timestamp_millis: u64) {
+        let Some(retention) = self.retention else {
+            return;
+        };
+        let interval_millis = PRUNE_INTERVAL.as_millis() as u64;
+        let cutoff = timestamp_millis.saturating_sub(retention.as_millis() as u64);
+        if timestamp_millis >= self.next_forget_millis {
+            self.next_forget_millis = timestamp_millis + interval_millis;
+            // The last row of an unchanged series may be pruned before this
+            // check comes round again, whichever exporter runs the sweep, so
+            // write the series again.
+            if let Some(last_values) = self.last_values.as_mut() {
+                let next_cutoff = cutoff + interval_millis;
+                last_values.retain(|_, &mut (_, row_millis)| row_millis >= next_cutoff);
+            }
+        }
+        let next_prune_millis = self.next_prune_millis.load(Ordering::Relaxed);
+        if timestamp_millis < next_prune_millis
+            || self
+                .next_prune_millis
+                .compare_exchange(
+                    next_prune_millis,
+                    timestamp_millis + interval_millis,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            return;
+        }
+        let start_marker = Instant::now();
+        match self.prune(cutoff) {
+            Ok(removed) => info!(
+                "pruned {} rows older than {} in {:?}",
+                removed,
+                cutoff,
+                start_marker.elapsed()
+            ),
+            Err(err) => error!("unable to prune old samples: {}", err),
+        }
+    }
+
+    /// Creates a timeseries table holding `columns` in addition to the series id
+    /// and timestamp. Rows are keyed by series and timestamp, plus any `extra_key` columns.
+    fn create_table(
+        &self,
+        table_name: &str,
+        columns: &str,
+        extra_key: &str,
+    ) -> rusqlite::Result<()> {
         let sql = if self.use_stanchion {
             format!(
-                "CREATE VIRTUAL TABLE {:?} USING stanchion (
+                "CREATE VIRTUAL TABLE IF NOT EXISTS {:?} USING stanchion (
                     series_id INTEGER NOT NULL REFERENCES series(id) ON DELETE CASCADE,
                     timestamp INTEGER NOT NULL,
-                    value REAL NOT NULL,
-                    SORT KEY (series_id, timestamp)
+                    {},
+                    SORT KEY (series_id, timestamp{})
             );",
-                table_name
+                table_name, columns, extra_key
             )
         } else {
             format!(
-                "CREATE TABLE {:?} (
+                "CREATE TABLE IF NOT EXISTS {:?} (
                     series_id INTEGER NOT NULL REFERENCES series(id) ON DELETE CASCADE,
-                    timestamp DATETIME NOT NULL,
-                    value REAL NOT NULL,
-                    PRIMARY KEY (series_id, timestamp)
+                    timestamp {} NOT NULL,
+                    {},
+                    PRIMARY KEY (series_id, timestamp{})
             );",
-                table_name
+                table_name,
+                self.timestamp_type(),
+                columns,
+                extra_key
             )
         };
         self.connection.execute(&sql, ())?;
         Ok(())
     }
 
-    fn insert_scalar(
+    fn create_scalar(&self, table_name: &str) -> rusqlite::Result<()> {
+        self.create_table(table_name, "value REAL NOT NULL", "")
+    }
+
+    /// Histograms are stored in two tables: one with the sum and count of each
+    /// series, and a "_bucket" table with the cumulative count of each bucket.
+    fn create_histogram(&self, table_name: &str) -> rusqlite::Result<()> {
+        self.create_table(table_name, "sum REAL, count REAL", "")?;
+        self.create_table(
+            &format!("{}_bucket", table_name),
+            "le REAL NOT NULL, count REAL NOT NULL",
+            ", le",
+        )
+    }
+
+    /// Summaries are stored like histograms, with a "_quantile" table holding the
+    /// value of each quantile. Quantile values may be NaN, which SQLite stores as NULL.
+    fn create_summary(&self, table_name: &str) -> rusqlite::Result<()> {
+        self.create_table(table_name, "sum REAL, count REAL", "")?;
+        self.create_table(
+            &format!("{}_quantile", table_name),
+            "quantile REAL NOT NULL, value REAL",
+            ", quantile",
+        )
+    }
+
+    /// Inserts rows into a timeseries table, using as few statements as possible.
+    /// `values` holds the rows back to back, each with one value per column.
+    fn insert_rows(
         &self,
         table_name: &str,
-        timestamp_millis: u64,
-        series_id: i64,
-        value: f64,
+        columns: &[&str],
+        values: Vec<Value>,
     ) -> rusqlite::Result<()> {
-        let mut stmt = self.connection.prepare(&format!(
-            "INSERT INTO {:?} (series_id, timestamp, value) VALUES (?1, ?2, ?3)",
-            table_name
-        ))?;
-        if self.use_stanchion {
-            stmt.insert((series_id, timestamp_millis, value))?;
-        } else {
-            stmt.insert((
-                series_id,
-                chrono::DateTime::from_timestamp_millis(timestamp_millis as i64)
-                    .unwrap()
-                    .to_rfc3339(),
-                value,
+        let placeholders = format!("({})", vec!["?"; columns.len()].join(", "));
+        let rows_per_insert = MAX_PARAMS_PER_INSERT / columns.len();
+        let mut values = values.into_iter().peekable();
+        while values.peek().is_some() {
+            let chunk: Vec<Value> = values
+                .by_ref()
+                .take(rows_per_insert * columns.len())
+                .collect();
+            let mut stmt = self.connection.prepare_cached(&format!(
+                "INSERT INTO {:?} ({}) VALUES {}",
+                table_name,
+                columns.join(", "),
+                vec![placeholders.as_str(); chunk.len() / columns.len()].join(", ")
             ))?;
+            stmt.execute(params_from_iter(chunk))?;
         }
         Ok(())
     }
 
-    fn get_metric_id(&self, family: &MetricFamily) -> rusqlite::Result<i64> {
-        // TODO: Wrap in a transaction?
+    /// The type of the timestamp column of timeseries tables.
+    fn timestamp_type(&self) -> &'static str {
+        match self.timestamp_format {
+            TimestampFormat::Rfc3339 => "DATETIME",
+            TimestampFormat::Millis => "INTEGER",
+        }
+    }
+
+    /// Converts a timestamp to the representation stored in the database.
+    fn timestamp_value(&self, timestamp_millis: u64) -> Value {
+        self.timestamp_format.value(timestamp_millis)
+    }
+
+    /// Looks up the id and table name of the metric, adding it if it's new.
+    fn get_metric_id(&self, family: &MetricFamily) -> rusqlite::Result<(i64, String)> {
         let mut stmt = self
             .connection
-            .prepare("SELECT id FROM metric WHERE name = ?1")?;
+            .prepare_cached("SELECT id, table_name FROM metric WHERE name = ?1")?;
         let mut rows = stmt.query((family.var.unwrap(),))?;
         if let Some(row) = rows.next()? {
-            return row.get(0);
+            return Ok((row.get(0)?, row.get(1)?));
         }
-        let mut stmt = self
-            .connection
-            .prepare("INSERT INTO metric (name, type, help) VALUES (?1, ?2, ?3) RETURNING id")?;
+        let table_name = match self.layout {
+            Layout::PerMetric => self.unused_table_name(family)?,
+            Layout::Narrow => match family.r#type {
+                SampleType::Counter | SampleType::Gauge | SampleType::Untyped => {
+                    NARROW_SCALAR_TABLE.to_string()
+                }
+                SampleType::Histogram => NARROW_HISTOGRAM_TABLE.to_string(),
+                SampleType::Summary => NARROW_SUMMARY_TABLE.to_string(),
+            },
+        };
+        let mut stmt = self.connection.prepare_cached(
+            "INSERT INTO metric (name, type, help, unit, table_name)
+             VALUES (?1, ?2, ?3, ?4, ?5) RETURNING id",
+        )?;
         let mut rows = stmt.query((
             family.var.unwrap(),
             match family.r#type {
@@ -121,48 +474,78 @@ impl TableExporter {
                 SampleType::Summary => "summary",
                 SampleType::Histogram => "histogram",
             },
-            family.help,
+            family.help.as_deref(),
+            family.unit,
+            &table_name,
         ))?;
         let id = match rows.next()? {
             Some(row) => row.get(0)?,
             None => unreachable!(),
         };
         // Create a timeseries table for the metric.
-        match family.r#type {
-            SampleType::Counter | SampleType::Gauge | SampleType::Untyped => {
-                self.create_scalar(family.var.unwrap())?
-            }
-            SampleType::Summary => {
-                // TODO:  implement summary table creation
+        if self.layout == Layout::PerMetric {
+            match family.r#type {
+                SampleType::Counter | SampleType::Gauge | SampleType::Untyped => {
+                    self.create_scalar(&table_name)?
+                }
+                SampleType::Summary => self.create_summary(&table_name)?,
+                SampleType::Histogram => self.create_histogram(&table_name)?,
             }
-            SampleType::Histogram => {
-                // TODO: implement histogram table creation
+        }
+        Ok((id, table_name))
+    }
+
+    /// Picks the name of the table for a new metric in the per-metric layout.
+    /// This is based on the metric name, but mustn't clash with any existing
+    /// table or view, including the companion tables of histograms and summaries.
+    fn unused_table_name(&self, family: &MetricFamily) -> rusqlite::Result<String> {
+        let base = sanitize_identifier(family.var.unwrap());
+        let suffix = match family.r#type {
+            SampleType::Histogram => "_bucket",
+            SampleType::Summary => "_quantile",
+            SampleType::Counter | SampleType::Gauge | SampleType::Untyped => "",
+        };
+        // Table names are case insensitive.
+        let mut stmt = self.connection.prepare_cached(
+            "SELECT COUNT(*) > 0 FROM sqlite_master
+             WHERE name COLLATE NOCASE IN (?1, ?2)",
+        )?;
+        let mut n = 0;
+        loop {
+            let candidate = match n {
+                0 => base.clone(),
+                n => format!("{}_{}", base, n),
+            };
+            let companion = format!("{}{}", candidate, suffix);
+            let taken: bool = stmt.query_row((&candidate, &companion), |row| row.get(0))?;
+            if !taken {
+                return Ok(candidate);
             }
+            n += 1;
         }
-        Ok(id)
     }
 
-    fn get_metric_id_cached(&mut self, family: &MetricFamily) -> rusqlite::Result<i64> {
-        if let Some(id) = self.metric_cache.get(family.var.unwrap()) {
-            return Ok(*id);
+    fn get_metric_id_cached(&mut self, family: &MetricFamily) -> rusqlite::Result<(i64, String)> {
+        if let Some(metric) = self.metric_cache.get(family.var.unwrap()) {
+            return Ok(metric.clone());
         }
-        let id = self.get_metric_id(family)?;
+        let metric = self.get_metric_id(family)?;
         self.metric_cache
-            .insert(family.var.unwrap().to_string(), id);
-        Ok(id)
+            .insert(family.var.unwrap().to_string(), metric.clone());
+        Ok(metric)
     }
 
     fn get_label_value(&mut self, label: &str, value: &str) -> rusqlite::Result<i64> {
         let mut stmt = self
             .connection
-            .prepare("SELECT id FROM label_value WHERE label = ?1 AND value = ?2")?;
+            .prepare_cached("SELECT id FROM label_value WHERE label = ?1 AND value = ?2")?;
         let mut rows = stmt.query((label, value))?;
         if let Some(row) = rows.next()? {
             return row.get(0);
         }
-        let mut stmt = self
-            .connection
-            .prepare("INSERT INTO label_value (label, value) VALUES (?1, ?2) RETURNING id")?;
+        let mut stmt = self.connection.prepare_cached(
+            "INSERT INTO label_value (label, value) VALUES (?1, ?2) RETURNING id",
+        )?;
         let mut rows = stmt.query((label, value))?;
         match rows.next()? {
             Some(row) => row.get(0),
@@ -171,31 +554,40 @@ impl TableExporter {
     }
 
     fn get_label_value_cached(&mut self, label: &str, value: &str) -> rusqlite::Result<i64> {
-        let key = (label.to_string(), value.to_string());
-        if let Some(id) = self.label_value_cache.get(&key) {
-            return Ok(*id);
+        let label_id = match self.label_ids.get(label) {
+            Some(&label_id) => label_id,
+            None => {
+                let label_id = self.label_value_cache.len();
+                self.label_ids.insert(label.to_string(), label_id);
+                self.label_value_cache.push(HashMap::new());
+                label_id
+            }
+        };
+        if let Some(&id) = self.label_value_cache[label_id].get(value) {
+            return Ok(id);
         }
         let id = self.get_label_value(label, value)?;
-        self.label_value_cache.insert(key, id);
+        self.label_value_cache[label_id].insert(value.to_string(), id);
         Ok(id)
     }
 
     fn get_series_id(&mut self, metric_id: i64, label_value_ids: &[i64]) -> rusqlite::Result<i64> {
-        // Build a query to find any series that matches those labels
-        // BUG: this doesn't verify that it matches *only* these labels!
-        let mut sql = vec![format!(
-            "SELECT id FROM series WHERE metric_id = {}",
-            metric_id
-        )];
-        for label_value_id in label_value_ids.iter() {
-            sql.push(format!(
-                "SELECT series_id FROM label_set WHERE label_value_id = {}",
-                label_value_id
-            ));
-        }
-        let sql = sql.join(" INTERSECT ");
-        let mut stmt = self.connection.prepare(&sql)?;
-        let mut rows = stmt.query(())?;
+        // Build a query to find any series that has all of those labels
+        let mut sql = String::from("SELECT id FROM series WHERE metric_id = ?");
+        for _ in label_value_ids.iter() {
+            sql.push_str(" INTERSECT SELECT series_id FROM label_set WHERE label_value_id = ?");
+        }
+        // ...and then keep only the one that has no other labels.
+        let sql = format!(
+            "SELECT c.id FROM ({}) AS c
+             WHERE (SELECT COUNT(*) FROM label_set ls WHERE ls.series_id = c.id) = ?",
+            sql
+        );
+        let params = std::iter::once(metric_id)
+            .chain(label_value_ids.iter().copied())
+            .chain(std::iter::once(label_value_ids.len() as i64));
+        let mut stmt = self.connection.prepare_cached(&sql)?;
+        let mut rows = stmt.query(params_from_iter(params))?;
         if let Some(row) = rows.next()? {
             return row.get(0);
         }
@@ -203,7 +595,7 @@ impl TableExporter {
         // Insert a new series.
         let mut stmt = self
             .connection
-            .prepare("INSERT INTO series (metric_id) VALUES (?1) RETURNING id")?;
+            .prepare_cached("INSERT INTO series (metric_id) VALUES (?1) RETURNING id")?;
         let mut rows = stmt.query((metric_id,))?;
         let series_id = match rows.next()? {
             Some(row) => row.get(0)?,
@@ -211,7 +603,7 @@ impl TableExporter {
         };
         let mut stmt = self
             .connection
-            .prepare("INSERT INTO label_set (series_id, label_value_id) VALUES (?1, ?2)")?;
+            .prepare_cached("INSERT INTO label_set (series_id, label_value_id) VALUES (?1, ?2)")?;
         for label_value_id in label_value_ids.iter() {
             stmt.insert((series_id, *label_value_id))?;
         }
@@ -223,71 +615,517 @@ impl TableExporter {
         metric_id: i64,
         label_set: &LabelSet,
     ) -> rusqlite::Result<i64> {
-        let mut label_value_ids = Vec::with_capacity(label_set.len());
-        for &(label, value) in label_set {
+        let mut key = std::mem::take(&mut self.series_key);
+        key.0 = metric_id;
+        key.1.clear();
+        for (label, value) in label_set {
             let label_value_id = self.get_label_value_cached(label, value)?;
-            label_value_ids.push(label_value_id);
+            key.1.push(label_value_id);
         }
-        let key = (metric_id, label_value_ids);
-        if let Some(id) = self.series_cache.get(&key) {
-            return Ok(*id);
-        }
-        let series_id = self.get_series_id(metric_id, &key.1)?;
-        self.series_cache.insert(key, series_id);
+        let series_id = match self.series_cache.get(&key) {
+            Some(&id) => id,
+            None => {
+                let id = self.get_series_id(metric_id, &key.1)?;
+                self.series_cache.insert(key.clone(), id);
+                id
+            }
+        };
+        self.series_key = key;
         Ok(series_id)
     }
-}
 
-impl driver::Exporter for TableExporter {
-    fn export(&mut self, timestamp_millis: u64, family: &MetricFamily) -> bool {
-        // TODO: wrap this in a transaction?
-        let metric_id = match self.get_metric_id_cached(family) {
-            Ok(id) => id,
+    /// Forgets all cached ids and uncommitted updates, e.g. because the rows
+    /// they refer to were rolled back.
+    fn clear_caches(&mut self) {
+        self.metric_cache.clear();
+        self.label_ids.clear();
+        self.label_value_cache.clear();
+        self.series_cache.clear();
+        self.pending_values.clear();
+        self.pending_counters.clear();
+    }
+
+    /// Applies the updates to the last values of series made by the family
+    /// whose transaction was just committed.
+    fn apply_pending(&mut self) {
+        if let Some(last_values) = self.last_values.as_mut() {
+            last_values.extend(self.pending_values.drain(..));
+        }
+        if let Some(last_counters) = self.last_counters.as_mut() {
+            last_counters.extend(self.pending_counters.drain(..));
+        }
+    }
+
+    /// Looks up the series for `labels`, logging any error.
+    fn lookup_series(
+        &mut self,
+        metric_id: i64,
+        family: &MetricFamily,
+        labels: &LabelSet,
+    ) -> Option<i64> {
+        match self.get_series_id_cached(metric_id, labels) {
+            Ok(id) => Some(id),
             Err(err) => {
-                error!("unable to lookup metric family: {}", err);
-                return false;
+                error!(
+                    "unable to lookup series for metric {} and labels {:?}: {}",
+                    family.var.unwrap(),
+                    labels,
+                    err
+                );
+                None
             }
-        };
+        }
+    }
+
+    fn export_scalars(
+        &mut self,
+        metric_id: i64,
+        table_name: &str,
+        timestamp_millis: u64,
+        family: &MetricFamily,
+    ) -> bool {
+        let mut values = Vec::with_capacity(family.samples.len() * 3);
+        let mut resets = Vec::new();
+        let mut ranges = Vec::new();
         for sample in &family.samples {
-            let series_id = match self.get_series_id_cached(metric_id, &sample.labels) {
-                Ok(id) => id,
-                Err(err) => {
-                    error!(
-                        "unable to lookup series for metric {} and labels {:?}: {}",
-                        family.var.unwrap(),
-                        &sample.labels,
-                        err
-                    );
+            let Some(series_id) = self.lookup_series(metric_id, family, &sample.labels) else {
+                return false;
+            };
+            let value = match parse_value(sample.value) {
+                Some(value) => value,
+                None => {
+                    driver::unparseable_sample(sample);
                     return false;
                 }
             };
-            let result = match family.r#type {
-                SampleType::Counter | SampleType::Gauge | SampleType::Untyped => {
-                    let value = match sample.value.parse::<f64>() {
-                        Ok(value) => value,
-                        Err(err) => {
-                            error!("unable to parse scalar value {:?}: {}", sample.value, err);
-                            return false;
-                        }
-                    };
-                    self.insert_scalar(family.var.unwrap(), timestamp_millis, series_id, value)
-                }
-                SampleType::Summary => {
-                    // TODO
-                    Ok(())
+            let timestamp_millis = sample
+                .timestamp
+                .map(|t| t as u64)
+                .unwrap_or(timestamp_millis);
+            if let Some(last_counters) = self
+                .last_counters
+                .as_ref()
+                .filter(|_| family.r#type == SampleType::Counter)
+            {
+                if last_counters
+                    .get(&series_id)
+                    .is_some_and(|&last| value < last)
+                {
+                    resets.push(Value::Integer(series_id));
+                    resets.push(self.timestamp_value(timestamp_millis));
                 }
-                SampleType::Histogram => {
-                    // TODO
-                    Ok(())
+                self.pending_counters.push((series_id, value));
+            }
+            if let Some(last_values) = self.last_values.as_ref() {
+                match last_values.get(&series_id) {
+                    Some(&(bits, row_millis)) if bits == value.to_bits() => {
+                        ranges.push(Value::Integer(series_id));
+                        ranges.push(self.timestamp_value(row_millis));
+                        ranges.push(self.timestamp_value(timestamp_millis));
+                        continue;
+                    }
+                    _ => self
+                        .pending_values
+                        .push((series_id, (value.to_bits(), timestamp_millis))),
                 }
+            }
+            values.push(Value::Integer(series_id));
+            values.push(self.timestamp_value(timestamp_millis));
+            values.push(Value::Real(value));
+        }
+        let columns = ["series_id", "timestamp", "value"];
+        if let Err(err) = self.insert_rows(table_name, &columns, values) {
+            error!("unable to insert samples: {}", err);
+            return false;
+        }
+        if let Err(err) = self.insert_rows(COUNTER_RESET_TABLE, &["series_id", "timestamp"], resets)
+        {
+            error!("unable to record counter resets: {}", err);
+            return false;
+        }
+        if let Err(err) = self.extend_ranges(ranges) {
+            error!("unable to record deduplicated samples: {}", err);
+            return false;
+        }
+        true
+    }
+
+    /// Records the timestamps of skipped samples in `dedup_range`, as the time
+    /// until which the value of their series' last row held. `values` holds the
+    /// series id, row timestamp and skipped sample's timestamp of each.
+    fn extend_ranges(&self, values: Vec<Value>) -> rusqlite::Result<()> {
+        let mut stmt = self.connection.prepare_cached(&format!(
+            "INSERT INTO {:?} (series_id, timestamp, last_timestamp) VALUES (?1, ?2, ?3)
+             ON CONFLICT (series_id, timestamp) DO UPDATE SET last_timestamp = excluded.last_timestamp",
+            DEDUP_RANGE_TABLE
+        ))?;
+        for range in values.chunks(3) {
+            stmt.execute(params_from_iter(range))?;
+        }
+        Ok(())
+    }
+
+    fn export_histogram(
+        &mut self,
+        metric_id: i64,
+        table_name: &str,
+        timestamp_millis: u64,
+        family: &MetricFamily,
+    ) -> bool {
+        let Some(histograms) = family.as_histogram() else {
+            error!("unable to decode histogram {}", family.var.unwrap());
+            return false;
+        };
+        let mut values = Vec::with_capacity(histograms.len() * 4);
+        let mut bucket_values = Vec::new();
+        for histogram in histograms.iter() {
+            let Some(series_id) = self.lookup_series(metric_id, family, &histogram.labels) else {
+                return false;
             };
-            if let Err(err) = result {
-                error!("unable to insert sample: {}", err);
+            let timestamp = self.timestamp_value(
+                histogram
+                    .timestamp
+                    .map(|t| t as u64)
+                    .unwrap_or(timestamp_millis),
+            );
+            values.push(Value::Integer(series_id));
+            values.push(timestamp.clone());
+            values.push(histogram.sum.map_or(Value::Null, Value::Real));
+            values.push(histogram.count.map_or(Value::Null, Value::Real));
+            for &(le, count) in histogram.buckets.iter() {
+                bucket_values.push(Value::Integer(series_id));
+                bucket_values.push(timestamp.clone());
+                bucket_values.push(Value::Real(le));
+                bucket_values.push(Value::Real(count));
+            }
+        }
+        self.insert_distribution(
+            table_name,
+            values,
+            "_bucket",
+            &["series_id", "timestamp", "le", "count"],
+            bucket_values,
+        )
+    }
+
+    fn export_summary(
+        &mut self,
+        metric_id: i64,
+        table_name: &str,
+        timestamp_millis: u64,
+        family: &MetricFamily,
+    ) -> bool {
+        let Some(summaries) = family.as_summary() else {
+            error!("unable to decode summary {}", family.var.unwrap());
+            return false;
+        };
+        let mut values = Vec::with_capacity(summaries.len() * 4);
+        let mut quantile_values = Vec::new();
+        for summary in summaries.iter() {
+            let Some(series_id) = self.lookup_series(metric_id, family, &summary.labels) else {
                 return false;
+            };
+            let timestamp = self.timestamp_value(
+                summary
+                    .timestamp
+                    .map(|t| t as u64)
+                    .unwrap_or(timestamp_millis),
+            );
+            values.push(Value::Integer(series_id));
+            values.push(timestamp.clone());
+            values.push(summary.sum.map_or(Value::Null, Value::Real));
+            values.push(summary.count.map_or(Value::Null, Value::Real));
+            for &(quantile, value) in summary.quantiles.iter() {
+                quantile_values.push(Value::Integer(series_id));
+                quantile_values.push(timestamp.clone());
+                quantile_values.push(Value::Real(quantile));
+                quantile_values.push(if value.is_nan() {
+                    Value::Null
+                } else {
+                    Value::Real(value)
+                });
             }
         }
+        self.insert_distribution(
+            table_name,
+            values,
+            "_quantile",
+            &["series_id", "timestamp", "quantile", "value"],
+            quantile_values,
+        )
+    }
+
+    /// Inserts the rows of a histogram or summary. `values` holds the sum and
+    /// count of each series, while `detail_values` holds the rows of the
+    /// companion table named by `suffix`.
+    fn insert_distribution(
+        &self,
+        table_name: &str,
+        values: Vec<Value>,
+        suffix: &str,
+        detail_columns: &[&str],
+        detail_values: Vec<Value>,
+    ) -> bool {
+        let result = self
+            .insert_rows(
+                table_name,
+                &["series_id", "timestamp", "sum", "count"],
+                values,
+            )
+            .and_then(|_| {
+                self.insert_rows(
+                    &format!("{}{}", table_name, suffix),
+                    detail_columns,
+                    detail_values,
+                )
+            });
+        if let Err(err) = result {
+            error!("unable to insert samples for {}: {}", table_name, err);
+            return false;
+        }
         true
     }
 
+    fn export_family(&mut self, timestamp_millis: u64, family: &MetricFamily) -> bool {
+        let (metric_id, table_name) = match self.get_metric_id_cached(family) {
+            Ok(metric) => metric,
+            Err(err) => {
+                error!("unable to lookup metric family: {}", err);
+                return false;
+            }
+        };
+        match family.r#type {
+            SampleType::Counter | SampleType::Gauge | SampleType::Untyped => {
+                self.export_scalars(metric_id, &table_name, timestamp_millis, family)
+            }
+            SampleType::Histogram => {
+                self.export_histogram(metric_id, &table_name, timestamp_millis, family)
+            }
+            SampleType::Summary => {
+                self.export_summary(metric_id, &table_name, timestamp_millis, family)
+            }
+        }
+    }
+}
+
+impl driver::Exporter for TableExporter {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn export(&mut self, timestamp_millis: u64, family: &MetricFamily) -> bool {
+        if !self.connection.is_autocommit() {
+            // The last export was interrupted by a panic before it could finish.
+            self.abort();
+        }
+        self.maybe_prune(timestamp_millis);
+        // All samples of a family are written in one transaction, both for speed
+        // and so that a failure doesn't leave a partially written family behind.
+        // It takes the write lock up front, so that metrics and label values
+        // are looked up and added atomically when other connections write too.
+        if let Err(err) = self.connection.execute_batch("BEGIN IMMEDIATE") {
+            error!("unable to begin transaction: {}", err);
+            return false;
+        }
+        if self.export_family(timestamp_millis, family) {
+            match self.connection.execute_batch("COMMIT") {
+                Ok(()) => {
+                    self.apply_pending();
+                    return true;
+                }
+                Err(err) => error!("unable to commit transaction: {}", err),
+            }
+        }
+        if let Err(err) = self.connection.execute_batch("ROLLBACK") {
+            error!("unable to roll back transaction: {}", err);
+        }
+        self.clear_caches();
+        false
+    }
+
     fn close(&mut self) {}
 }
+
+/// Maps a metric name to an identifier that can safely be used as a table name.
+/// Anything other than ASCII letters, digits and underscores is replaced with an
+/// underscore, and a prefix is added to names that don't start with a letter or
+/// underscore, or that start with the "sqlite_" prefix reserved by SQLite.
+fn sanitize_identifier(name: &str) -> String {
+    let identifier: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if identifier.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && !identifier.to_ascii_lowercase().starts_with("sqlite_")
+    {
+        identifier
+    } else {
+        format!("m_{}", identifier)
+    }
+}
+
+/// Adds a column to `table` unless it already has one called `column`.
+/// Returns whether the column was added.
+fn add_column_if_missing(
+    connection: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<bool> {
+    let exists: bool = connection.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        (table, column),
+        |row| row.get(0),
+    )?;
+    if !exists {
+        connection.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))?;
+    }
+    Ok(!exists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> TableExporter {
+        TableExporter::open(
+            ":memory:",
+            None,
+            &[],
+            Layout::PerMetric,
+            TimestampFormat::Millis,
+        )
+        .unwrap()
+    }
+
+    fn export(exporter: &mut TableExporter, timestamp_millis: u64, family: &MetricFamily) -> bool {
+        driver::Exporter::export(exporter, timestamp_millis, family)
+    }
+
+    fn count(exporter: &TableExporter, sql: &str) -> i64 {
+        exporter
+            .connection
+            .query_row(sql, (), |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn series_lookup_matches_exact_label_set() {
+        // The label-superset series comes first, so a lookup of the subset
+        // would find it if only the given labels were checked.
+        let input = "# HELP m Help.\n# TYPE m gauge\nm{a=\"1\",b=\"2\"} 1\nm{a=\"1\"} 2\n";
+        let families = driver::parse::parse(None, None, input).unwrap();
+        let mut exporter = open();
+        assert!(export(&mut exporter, 1000, &families[0]));
+        // Look the series up in the database again, rather than in the cache.
+        exporter.clear_caches();
+        assert!(export(&mut exporter, 2000, &families[0]));
+        assert_eq!(count(&exporter, "SELECT COUNT(*) FROM series"), 2);
+        assert_eq!(
+            count(&exporter, "SELECT COUNT(DISTINCT series_id) FROM m"),
+            2
+        );
+        assert_eq!(count(&exporter, "SELECT COUNT(*) FROM m"), 4);
+    }
+
+    /// A gauge family `m` with a single sample for each of `values`, labeled by
+    /// its index.
+    fn gauge<'a>(values: &[&'a str]) -> MetricFamily<'a> {
+        const SERIES: [&str; 3] = ["0", "1", "2"];
+        MetricFamily {
+            var: Some("m"),
+            help: Some("Help.".into()),
+            r#type: SampleType::Gauge,
+            unit: None,
+            samples: values
+                .iter()
+                .zip(SERIES)
+                .map(|(value, series)| driver::parse::Sample {
+                    var: "m",
+                    labels: vec![("series", series.into())],
+                    value,
+                    timestamp: None,
+                    exemplar: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn dedup_records_range() {
+        let mut exporter = open();
+        exporter.enable_dedup();
+        assert!(export(&mut exporter, 1000, &gauge(&["1"])));
+        assert!(export(&mut exporter, 2000, &gauge(&["1"])));
+        assert!(export(&mut exporter, 3000, &gauge(&["1"])));
+        assert!(export(&mut exporter, 4000, &gauge(&["2"])));
+        assert_eq!(count(&exporter, "SELECT COUNT(*) FROM m"), 2);
+        assert_eq!(
+            count(
+                &exporter,
+                "SELECT COUNT(*) FROM dedup_range WHERE timestamp = 1000 AND last_timestamp = 3000"
+            ),
+            1
+        );
+        assert_eq!(count(&exporter, "SELECT COUNT(*) FROM dedup_range"), 1);
+    }
+
+    #[test]
+    fn dedup_forgets_rolled_back_values() {
+        let mut exporter = open();
+        exporter.enable_dedup();
+        assert!(export(&mut exporter, 1000, &gauge(&["1"])));
+        // The first sample changes, but the family fails on the second.
+        assert!(!export(&mut exporter, 2000, &gauge(&["2", "bogus"])));
+        assert!(export(&mut exporter, 3000, &gauge(&["2"])));
+        assert_eq!(
+            count(&exporter, "SELECT COUNT(*) FROM m WHERE value = 2"),
+            1
+        );
+    }
+
+    #[test]
+    fn reset_detection_forgets_rolled_back_values() {
+        let mut exporter = open();
+        exporter.enable_reset_detection();
+        let mut family = gauge(&["5"]);
+        family.r#type = SampleType::Counter;
+        assert!(export(&mut exporter, 1000, &family));
+        // A rolled back drop to 1 mustn't be taken as the last value, or the
+        // next sample would look like it went up from there.
+        let mut failed = gauge(&["1", "bogus"]);
+        failed.r#type = SampleType::Counter;
+        assert!(!export(&mut exporter, 2000, &failed));
+        let mut family = gauge(&["3"]);
+        family.r#type = SampleType::Counter;
+        assert!(export(&mut exporter, 3000, &family));
+        assert_eq!(
+            count(
+                &exporter,
+                "SELECT COUNT(*) FROM counter_reset WHERE timestamp = 3000"
+            ),
+            1
+        );
+        assert_eq!(count(&exporter, "SELECT COUNT(*) FROM counter_reset"), 1);
+    }
+
+    #[test]
+    fn pruning_is_shared_between_exporters() {
+        let first = open();
+        let mut second = open();
+        second.set_retention(Duration::from_secs(3600));
+        second.share_pruning(&first);
+        // The first exporter never exports, but the second still prunes.
+        assert!(export(&mut second, 1000, &gauge(&["1"])));
+        let later = 1000 + 2 * 3600 * 1000;
+        assert!(export(&mut second, later, &gauge(&["2"])));
+        assert_eq!(count(&second, "SELECT COUNT(*) FROM m"), 1);
+        assert_eq!(
+            first.next_prune_millis.load(Ordering::Relaxed),
+            later + PRUNE_INTERVAL.as_millis() as u64
+        );
+    }
+}